@@ -1,17 +1,30 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::Display,
     fs,
+    ops::Range,
     path::{Path, PathBuf},
 };
 
 use id_arena::{Arena, Id};
-use toolchain_core::instruction::Instruction;
+use itertools::Itertools;
+use toolchain_core::{
+    debug_info::DebugInfo,
+    image::{Image, Segment},
+    memory_layout::{MemoryLayout, PlacementError},
+    object::{self, ObjectFile, RelocationKind, RelocationTarget},
+};
 
 use crate::{
-    lexer,
-    parser::{self, Ast, Item},
-    types::{AssemblerError, Span, Spanned},
+    dataflow,
+    diagnostic::{DiagnosticRecord, Locations, diagnostic},
+    encoder,
+    eval::{Evaluator, RelocationBase, Value},
+    layout, lexer, lints, listing,
+    parser::{self, Ast, Contract, Expr, Item, MacroParam, ParamKind},
+    symbols,
+    types::{AssemblerError, AssertionFailure, Severity, Span, Spanned},
+    warnings::{Suppressions, WarningOptions},
 };
 
 pub type FileId = Id<ParsedFile>;
@@ -19,33 +32,53 @@ pub type FileId = Id<ParsedFile>;
 #[derive(Clone, Debug, Default)]
 pub struct Assembler {
     files: Arena<ParsedFile>,
+    /// Paths read by `add_file`, in order, without those that failed to open
+    read_files: Vec<PathBuf>,
+}
+
+/// Settings that apply to the whole assembly
+#[derive(Clone, Debug, Default)]
+pub struct AssembleOptions {
+    /// Leave label addresses for the linker to fill in, producing an object file
+    pub relocatable: bool,
+    /// Regions that the segments must fit into, ignored for relocatable output
+    pub memory_layout: MemoryLayout,
+    /// Accept calling convention register names (`sp`, `a0`, ...) besides `rN`
+    pub abi_names: bool,
+    /// Warning classes to report, others are dropped
+    pub warnings: WarningOptions,
+    /// Produce the assembly listing in [`Output::listing`]
+    pub listing: bool,
 }
 
 #[derive(Clone, Debug)]
 pub struct ParsedFile {
     path: PathBuf,
 
+    /// Source text of the file, empty if the file could not be read.
+    source: String,
+
     /// Ast might be empty if parsing failed, but we still need the ParsedFile and FileId to report errors.
     ast: Option<Ast>,
+
+    /// Places where `allow` comments silence warnings
+    suppressions: Suppressions,
+
+    /// Definitions from the command line, placed in the root scope shared by all files
+    /// instead of a scope of their own.
+    is_command_line: bool,
 }
 
-fn parse_file(
-    path: &Path,
+fn parse_source(
+    source: &str,
     file_id: FileId,
-    source_span: Option<Span>,
     errors: &mut Vec<AssemblerError>,
-) -> Option<Ast> {
-    let content = fs::read_to_string(&path)
-        .map_err(|error| {
-            errors.push(AssemblerError::FileOpenFailed {
-                span: source_span,
-                file_path: path.to_owned(),
-                error,
-            })
-        })
-        .ok()?;
-    let tokens = lexer::tokenize(content.as_str(), Some(file_id), errors)?;
-    parser::parse(tokens.as_slice(), Some(file_id), content.len(), errors)
+) -> (Option<Ast>, Suppressions) {
+    let tokens = lexer::tokenize_with_comments(source, Some(file_id), errors);
+    let suppressions = Suppressions::new(&tokens, errors);
+    let tokens = lexer::without_comments(tokens);
+    let ast = parser::parse(tokens.as_slice(), Some(file_id), source.len(), errors);
+    (ast, suppressions)
 }
 
 impl Assembler {
@@ -57,31 +90,406 @@ impl Assembler {
         source_span: Option<Span>,
         errors: &mut Vec<AssemblerError>,
     ) -> FileId {
-        let file_id = self.files.alloc_with_id(|file_id| {
-            let ast = parse_file(&path, file_id, source_span, errors);
-            ParsedFile { path, ast }
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => {
+                self.read_files.push(path.clone());
+                Some(source)
+            }
+            Err(error) => {
+                errors.push(AssemblerError::FileOpenFailed {
+                    span: source_span,
+                    file_path: path.to_owned(),
+                    error,
+                });
+                None
+            }
+        };
+        self.alloc_file(path, source, errors)
+    }
+
+    /// Add a file with the given source text instead of reading it, for example an
+    /// unsaved editor buffer or inline code in a test. The path is only used for
+    /// reporting, it does not need to exist.
+    pub fn add_source(
+        &mut self,
+        path: impl Into<PathBuf>,
+        source: String,
+        errors: &mut Vec<AssemblerError>,
+    ) -> FileId {
+        self.alloc_file(path.into(), Some(source), errors)
+    }
+
+    fn alloc_file(
+        &mut self,
+        path: PathBuf,
+        source: Option<String>,
+        errors: &mut Vec<AssemblerError>,
+    ) -> FileId {
+        self.files.alloc_with_id(|file_id| {
+            let (ast, suppressions) = source
+                .as_deref()
+                .map(|source| parse_source(source, file_id, errors))
+                .unwrap_or_default();
+            ParsedFile {
+                path,
+                source: source.unwrap_or_default(),
+                ast,
+                suppressions,
+                is_command_line: false,
+            }
+        })
+    }
+
+    /// Add constants given as `NAME=EXPR` (or just `NAME`, defined as 1) on the command line.
+    /// They are visible from all files, so they should be added before them.
+    pub fn add_definitions(
+        &mut self,
+        definitions: &[String],
+        errors: &mut Vec<AssemblerError>,
+    ) -> FileId {
+        let source = definitions
+            .iter()
+            .map(|definition| {
+                if definition.contains('=') {
+                    definition.clone()
+                } else {
+                    format!("{}=1", definition)
+                }
+            })
+            .join("\n");
+
+        self.files.alloc_with_id(|file_id| {
+            let tokens = lexer::tokenize(&source, Some(file_id), errors);
+            let ast = parser::parse_definitions(&tokens, Some(file_id), source.len(), errors);
+            ParsedFile {
+                path: PathBuf::from("<command line>"),
+                source,
+                ast,
+                suppressions: Suppressions::default(),
+                is_command_line: true,
+            }
+        })
+    }
+
+    /// Expand macros, lay out and encode all added files.
+    pub fn assemble(
+        &self,
+        options: &AssembleOptions,
+        errors: &mut Vec<AssemblerError>,
+    ) -> Assembled {
+        let macros = self.collect_macros(errors);
+        let layout = layout::layout(self, self.file_scopes(), &macros, options, errors);
+        let evaluator = Evaluator::new(&layout, options);
+        let mismatched_calls = check_macro_arguments(&layout, &evaluator, errors);
+
+        let fragments: Vec<Fragment> = layout
+            .statements
+            .iter()
+            .map(|statement| {
+                // Errors in expansions with mismatched arguments would only repeat them
+                if statement
+                    .expansion
+                    .iter()
+                    .any(|call| mismatched_calls.contains(call))
+                {
+                    encoder::encode(statement, &evaluator, &mut Vec::new())
+                } else {
+                    encoder::encode(statement, &evaluator, errors)
+                }
+            })
+            .collect();
+        check_overlaps(&fragments, errors);
+        check_assertions(&layout, &evaluator, options, errors);
+        dataflow::check_contracts(&layout, &fragments, &evaluator, errors);
+        lints::check_delay_slots(&layout, &fragments, errors);
+        if !options.relocatable {
+            check_memory_layout(&fragments, &options.memory_layout, errors);
+        }
+
+        let mut labels = AssemblerTable::default();
+        let mut constants = AssemblerTable::default();
+        for (name, symbol) in layout.symbols.iter() {
+            match &symbol.value {
+                SymbolValue::Label {
+                    segment, address, ..
+                } => {
+                    labels.insert(
+                        name.clone(),
+                        LabelValue {
+                            span: symbol.span.clone(),
+                            segment: *segment,
+                            address: *address,
+                        },
+                    );
+                }
+                SymbolValue::Constant { value, scope } => {
+                    match evaluator.eval_number(value, scope) {
+                        Ok(value) => {
+                            constants.insert(
+                                name.clone(),
+                                ConstantValue {
+                                    span: symbol.span.clone(),
+                                    value,
+                                },
+                            );
+                        }
+                        Err(e) => errors.push(e),
+                    }
+                }
+                SymbolValue::Struct { size: value } | SymbolValue::Field { offset: value, .. } => {
+                    constants.insert(
+                        name.clone(),
+                        ConstantValue {
+                            span: symbol.span.clone(),
+                            value: (*value).into(),
+                        },
+                    );
+                }
+                SymbolValue::Register { value, scope } => {
+                    // Aliases are not a part of the output, but errors in unused ones
+                    // should not go unnoticed
+                    if let Err(e) = evaluator.eval_register(value, scope) {
+                        errors.push(e);
+                    }
+                }
+                SymbolValue::Import { .. } | SymbolValue::Counter { .. } => {}
+            }
+        }
+
+        let mut exports: Vec<(String, LabelValue)> = Vec::new();
+        let mut export_spans: HashMap<&str, &Span> = HashMap::new();
+        for export in layout.exports.iter() {
+            match layout.symbols.lookup([export.name], &export.scope) {
+                Some((_, symbol)) => {
+                    let SymbolValue::Label {
+                        segment, address, ..
+                    } = symbol.value
+                    else {
+                        errors.push(AssemblerError::ExpectedLabel {
+                            span: export.span.clone(),
+                            name: export.name.to_owned(),
+                        });
+                        continue;
+                    };
+                    if let Some(previous_span) = export_spans.insert(export.name, &export.span) {
+                        errors.push(AssemblerError::DuplicateDefinition {
+                            span: export.span.clone(),
+                            name: QualifiedName::default().with_names([export.name]),
+                            previous_span: previous_span.clone(),
+                        });
+                        continue;
+                    }
+                    exports.push((
+                        export.name.to_owned(),
+                        LabelValue {
+                            span: symbol.span.clone(),
+                            segment,
+                            address,
+                        },
+                    ));
+                }
+                None => errors.push(AssemblerError::UndefinedName {
+                    span: export.span.clone(),
+                    name: export.name.to_owned(),
+                }),
+            }
+        }
+
+        // Only after everything was evaluated, so that all uses of labels are known
+        lints::check_unused_labels(&layout, errors);
+        lints::check_shadowed_names(&layout, errors);
+        let names = self.name_index(&layout, &macros);
+        errors.retain(|error| match error.warning_class() {
+            Some(class) => options.warnings.is_enabled(class) && !self.is_suppressed(error),
+            None => true,
         });
 
-        file_id
+        Assembled {
+            fragments,
+            labels,
+            constants,
+            exports,
+            alignments: layout.alignments,
+            included_files: layout.included_files,
+            names,
+        }
+    }
+
+    /// Assemble into an image (or an object file if relocatable), with the symbols and all
+    /// errors and warnings described. `errors` are those from adding the files, nothing
+    /// is assembled if they contain errors.
+    pub fn build(&self, options: &AssembleOptions, mut errors: Vec<AssemblerError>) -> Output {
+        let has_errors = |errors: &[AssemblerError]| {
+            errors
+                .iter()
+                .any(|error| options.warnings.severity(error) == Severity::Error)
+        };
+
+        let mut output = Output::default();
+        if !has_errors(&errors) {
+            let assembled = self.assemble(options, &mut errors);
+            if !has_errors(&errors) {
+                if options.relocatable {
+                    output.object = Some(assembled.object_file());
+                } else {
+                    match assembled.image(&options.memory_layout) {
+                        Ok(image) => output.image = Some(image),
+                        Err(error) => errors.push(error),
+                    }
+                }
+            }
+            if !has_errors(&errors) {
+                output.symbols = Some(symbols::debug_info(self, &assembled));
+                output.listing = options.listing.then(|| self.listing(&assembled));
+                output.dependencies = self
+                    .read_files
+                    .iter()
+                    .chain(assembled.included_files.iter().map(|file| &file.path))
+                    .cloned()
+                    .collect();
+            }
+        }
+
+        let mut locations = Locations::new(self);
+        output.diagnostics = errors
+            .iter()
+            .map(|error| {
+                diagnostic(error, options.warnings.severity(error)).to_record(&mut locations)
+            })
+            .collect();
+        output.errors = errors;
+        output
     }
 
-    pub fn assemble(&self) -> Vec<Instruction> {
-        let macros = self.collect_macros();
-        dbg!(macros);
-        Vec::new()
+    fn listing(&self, assembled: &Assembled) -> String {
+        let mut listing = Vec::new();
+        listing::write_listing(&mut listing, self, assembled)
+            .expect("writing to memory only fails for files of another assembler");
+        String::from_utf8(listing).expect("listings are made of source text")
+    }
+
+    /// Collect where symbols and macros are defined and which names in the source refer
+    /// to them
+    fn name_index(&self, layout: &layout::Layout, macros: &AssemblerTable<MacroDef>) -> NameIndex {
+        let definitions = layout
+            .symbols
+            .iter()
+            .map(|(name, symbol)| {
+                let span = self.name_span(name, &symbol.span);
+                (NameTarget::Symbol(name.clone()), span)
+            })
+            .chain(macros.iter().map(|(name, macro_def)| {
+                let span = self.name_span(name, &macro_def.span);
+                (NameTarget::Macro(name.clone()), span)
+            }))
+            .collect();
+
+        let exports = layout.exports.iter().filter_map(|export| {
+            let (name, _) = layout.symbols.lookup([export.name], &export.scope)?;
+            Some((export.span.clone(), NameTarget::Symbol(name)))
+        });
+        let references: HashSet<_> = layout
+            .references
+            .borrow()
+            .iter()
+            .map(|(span, name)| (span.clone(), NameTarget::Symbol(name.clone())))
+            .chain(
+                layout
+                    .local_labels
+                    .iter()
+                    .map(|((_, span), name)| (span.clone(), NameTarget::Symbol(name.clone()))),
+            )
+            .chain(
+                layout
+                    .macro_references
+                    .iter()
+                    .map(|(span, name)| (span.clone(), NameTarget::Macro(name.clone()))),
+            )
+            .chain(exports)
+            .collect();
+        let mut references: Vec<_> = references.into_iter().collect();
+        references.sort_by_key(|(span, _)| (span.file_id.map(|id| id.index()), span.start));
+
+        NameIndex {
+            definitions,
+            references,
+        }
+    }
+
+    /// Span of the defined name inside the span of its whole definition: the first
+    /// occurrence of the last part of the name as a whole word. Falls back to the whole
+    /// definition.
+    fn name_span(&self, name: &QualifiedName, span: &Span) -> Span {
+        let is_word_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+        let found = name.split_last_name().and_then(|(_, last)| {
+            let source = self.get_source(span.file_id?)?.get(span.start..span.end)?;
+            source
+                .match_indices(last)
+                .map(|(i, _)| i)
+                .find(|&i| {
+                    !source[..i].ends_with(is_word_char)
+                        && !source[i + last.len()..].starts_with(is_word_char)
+                })
+                .map(|i| span.start + i..span.start + i + last.len())
+        });
+        match found {
+            Some(range) => Span {
+                file_id: span.file_id,
+                start: range.start,
+                end: range.end,
+            },
+            None => span.clone(),
+        }
+    }
+
+    /// Check whether an `allow` comment silences a warning at its location
+    fn is_suppressed(&self, warning: &AssemblerError) -> bool {
+        let (Some(class), Some(span)) = (warning.warning_class(), warning.warning_span()) else {
+            return false;
+        };
+        span.file_id
+            .and_then(|file_id| self.files.get(file_id))
+            .is_some_and(|file| file.suppressions.covers(class, span.start))
     }
 
     pub fn get_path(&self, file_id: FileId) -> Option<&Path> {
         self.files.get(file_id).map(|file| file.path.as_ref())
     }
 
-    fn collect_macros(&'_ self) -> AssemblerTable<MacroDef<'_>> {
+    pub fn get_source(&self, file_id: FileId) -> Option<&str> {
+        self.files.get(file_id).map(|file| file.source.as_str())
+    }
+
+    /// Source files read from disk, for dependency tracking. Files added with
+    /// `add_source` are not included.
+    pub fn read_files(&self) -> &[PathBuf] {
+        &self.read_files
+    }
+
+    pub fn file_ids(&self) -> impl Iterator<Item = FileId> + '_ {
+        self.files.iter().map(|(file_id, _)| file_id)
+    }
+
+    /// Iterate over root scopes of all successfully parsed files
+    fn file_scopes(&self) -> impl Iterator<Item = (QualifiedName, &Ast)> {
+        self.files
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (_file_id, f))| {
+                let scope = if f.is_command_line {
+                    QualifiedName::default()
+                } else {
+                    QualifiedName::new_anonymous(i)
+                };
+                Some((scope, f.ast.as_ref()?))
+            })
+    }
+
+    fn collect_macros(&'_ self, errors: &mut Vec<AssemblerError>) -> AssemblerTable<MacroDef<'_>> {
         let mut table = AssemblerTable::default();
 
-        for (i, (_file_id, f)) in self.files.iter().enumerate() {
-            let Some(ast) = f.ast.as_ref() else { continue };
-            let mut current_scope = QualifiedName::new_anonymous(i);
-            collect_macros_recursive(ast, &mut current_scope, &mut table);
+        for (mut current_scope, ast) in self.file_scopes() {
+            collect_macros_recursive(ast, &mut current_scope, &mut table, errors);
         }
 
         table
@@ -92,6 +500,13 @@ impl Assembler {
 pub enum QualifiedNameEntry {
     /// Anonymous entry, parameter is just for disambiguation
     Anonymous(usize),
+    /// Scope of a single macro expansion, parameter is unique for the whole assembly
+    MacroExpansion(usize),
+    /// Scope of a single iteration of `.rept` or `.for`, parameter is unique for the whole assembly
+    Iteration(usize),
+    /// Definition of a local numeric label, with its number and how many definitions
+    /// of the same number precede it
+    LocalLabel(i64, usize),
     Named(String),
 }
 
@@ -99,6 +514,11 @@ impl<'src> Display for QualifiedNameEntry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             QualifiedNameEntry::Anonymous(id) => write!(f, "<anonymous {}>", id),
+            QualifiedNameEntry::MacroExpansion(id) => write!(f, "<expansion {}>", id),
+            QualifiedNameEntry::Iteration(id) => write!(f, "<iteration {}>", id),
+            QualifiedNameEntry::LocalLabel(number, index) => {
+                write!(f, "<local {} #{}>", number, index)
+            }
             QualifiedNameEntry::Named(name) => write!(f, "{}", name),
         }
     }
@@ -111,17 +531,68 @@ impl QualifiedName {
     fn new_anonymous(id: usize) -> Self {
         QualifiedName(vec![QualifiedNameEntry::Anonymous(id)])
     }
-    fn push_name(&mut self, name: String) {
+
+    pub fn push_name(&mut self, name: String) {
         self.0.push(QualifiedNameEntry::Named(name));
     }
 
-    fn push_anonymous(&mut self, id: usize) {
+    pub fn push_anonymous(&mut self, id: usize) {
         self.0.push(QualifiedNameEntry::Anonymous(id));
     }
 
-    fn pop(&mut self) {
+    pub fn push_expansion(&mut self, id: usize) {
+        self.0.push(QualifiedNameEntry::MacroExpansion(id));
+    }
+
+    pub fn push_iteration(&mut self, id: usize) {
+        self.0.push(QualifiedNameEntry::Iteration(id));
+    }
+
+    pub fn push_local_label(&mut self, number: i64, index: usize) {
+        self.0.push(QualifiedNameEntry::LocalLabel(number, index));
+    }
+
+    pub fn pop(&mut self) {
         self.0.pop();
     }
+
+    /// Returns a copy of this name with `names` appended.
+    pub fn with_names<'a>(&self, names: impl IntoIterator<Item = &'a str>) -> QualifiedName {
+        let mut ret = self.clone();
+        for name in names {
+            ret.push_name(name.to_owned());
+        }
+        ret
+    }
+
+    /// Scope containing this name and the last entry, if it is a plain name
+    pub fn split_last_name(&self) -> Option<(QualifiedName, &str)> {
+        match self.0.split_last()? {
+            (QualifiedNameEntry::Named(name), scope) => {
+                Some((QualifiedName(scope.to_vec()), name.as_str()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Check whether the name comes from a macro expansion or a `.rept`/`.for` iteration
+    /// rather than directly from the source
+    pub fn is_generated(&self) -> bool {
+        self.0.iter().any(|entry| {
+            matches!(
+                entry,
+                QualifiedNameEntry::MacroExpansion(_) | QualifiedNameEntry::Iteration(_)
+            )
+        })
+    }
+
+    /// Iterate over this name and all of its parent scopes, starting from the longest.
+    /// This is the order in which names are looked up.
+    pub fn scopes(&self) -> impl Iterator<Item = QualifiedName> + '_ {
+        (0..=self.0.len())
+            .rev()
+            .map(|len| QualifiedName(self.0[..len].to_vec()))
+    }
 }
 
 impl<'src> Display for QualifiedName {
@@ -155,17 +626,464 @@ impl<'src, T> Default for AssemblerTable<T> {
     }
 }
 
+impl<T> AssemblerTable<T> {
+    pub fn get(&self, name: &QualifiedName) -> Option<&T> {
+        self.0.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &QualifiedName) -> Option<&mut T> {
+        self.0.get_mut(name)
+    }
+
+    /// Insert a value, returning the previous value with the same name, if any.
+    pub fn insert(&mut self, name: QualifiedName, value: T) -> Option<T> {
+        self.0.insert(name, value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&QualifiedName, &T)> {
+        self.0.iter()
+    }
+
+    /// Iterate over the entries ordered by the displayed name
+    pub fn iter_sorted(&self) -> impl Iterator<Item = (&QualifiedName, &T)> {
+        let mut entries: Vec<_> = self.0.iter().collect();
+        entries.sort_by_cached_key(|(name, _)| name.to_string());
+        entries.into_iter()
+    }
+
+    /// Look up a name from within `scope`, searching the scope and all its parents.
+    pub fn lookup<'a>(
+        &self,
+        names: impl IntoIterator<Item = &'a str> + Clone,
+        scope: &QualifiedName,
+    ) -> Option<(QualifiedName, &T)> {
+        scope.scopes().find_map(|scope| {
+            let candidate = scope.with_names(names.clone());
+            let value = self.0.get(&candidate)?;
+            Some((candidate, value))
+        })
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct MacroDef<'ast> {
-    span: Span,
-    params: &'ast Vec<String>,
-    body: &'ast Vec<Spanned<Item>>,
+    pub span: Span,
+    /// Scope in which the macro was defined, names in the body are resolved from here
+    pub scope: QualifiedName,
+    pub params: &'ast Vec<MacroParam>,
+    /// `.contract` placed before the definition, checked in every expansion
+    pub contract: Option<(&'ast Contract, &'ast Span)>,
+    pub body: &'ast Vec<Spanned<Item>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Symbol<'ast> {
+    pub span: Span,
+    pub value: SymbolValue<'ast>,
+}
+
+#[derive(Clone, Debug)]
+pub enum SymbolValue<'ast> {
+    Label {
+        segment: Segment,
+        address: u16,
+        /// Size in words of the scope named by the label, known after the end of the scope
+        size: Option<u16>,
+    },
+    Constant {
+        value: &'ast Spanned<Expr>,
+        /// Scope in which the constant was defined, its value is evaluated here
+        scope: QualifiedName,
+    },
+    /// Register alias defined by `.reg`
+    Register {
+        value: &'ast Spanned<Expr>,
+        /// Scope in which the alias was defined, its value is evaluated here
+        scope: QualifiedName,
+    },
+    /// Symbol provided by another object file
+    Import { name: &'ast str },
+    /// Counter of a `.for` loop in a single iteration
+    Counter { value: i64 },
+    /// `.struct`, evaluates to the total size in words
+    Struct { size: u16 },
+    /// Field of a `.struct`, evaluates to the offset from the start of the struct
+    Field { offset: u16, size: u16 },
+}
+
+/// Parameter bindings of a single macro expansion
+#[derive(Clone, Debug)]
+pub struct Expansion<'ast> {
+    /// Arguments are evaluated in the scope of the macro call
+    pub caller_scope: QualifiedName,
+    pub args: HashMap<&'ast str, &'ast Spanned<Expr>>,
+    /// Default values of parameters left out of the call, evaluated in the expansion scope
+    /// so that they can refer to earlier parameters
+    pub defaults: HashMap<&'ast str, &'ast Spanned<Expr>>,
+    /// Name of the variadic parameter and the arguments it collected
+    pub variadic: Option<(&'ast str, &'ast [Spanned<Expr>])>,
+}
+
+/// Encoded piece of the output at a fixed address.
+#[derive(Clone, Debug)]
+pub struct Fragment {
+    pub span: Span,
+    /// Spans of macro calls that produced this fragment, outermost first
+    pub expansion: Vec<Span>,
+    pub segment: Segment,
+    pub address: u16,
+    /// Encoded words, empty for labels
+    pub words: Vec<u16>,
+    pub cycles: u32,
+    pub relocations: Vec<FragmentRelocation>,
+}
+
+/// Field of a fragment that must be patched by the linker
+#[derive(Clone, Debug)]
+pub struct FragmentRelocation {
+    /// Offset in words from the start of the fragment
+    pub offset: u16,
+    pub kind: RelocationKind,
+    pub base: RelocationBase,
+    pub addend: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct LabelValue {
+    pub span: Span,
+    pub segment: Segment,
+    pub address: u16,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConstantValue {
+    pub span: Span,
+    pub value: i64,
+}
+
+/// Part of a binary file placed into the output by `.incbin` or `.incbinw`
+#[derive(Clone, Debug)]
+pub struct IncludedFile {
+    pub span: Span,
+    /// Path the file was read from, with relative paths of the directive resolved
+    /// against the directory of the including source
+    pub path: PathBuf,
+    pub segment: Segment,
+    pub address: u16,
+    /// Size in words
+    pub size: u16,
+}
+
+/// Everything a tool using the assembler as a library needs, see [`Assembler::build`]
+#[derive(Debug, Default)]
+pub struct Output {
+    /// Missing if there were errors or the output is relocatable
+    pub image: Option<Image>,
+    /// Object file for the linker, only for relocatable output without errors
+    pub object: Option<ObjectFile>,
+    /// Labels, constants and line table, missing if there were errors
+    pub symbols: Option<DebugInfo>,
+    /// Listing of the sources with addresses and encoded words, if it was requested
+    /// in [`AssembleOptions::listing`] and there were no errors
+    pub listing: Option<String>,
+    /// Files the output was assembled from, including those from `.incbin`,
+    /// empty if there were errors
+    pub dependencies: Vec<PathBuf>,
+    /// Errors and warnings in the order they were found
+    pub diagnostics: Vec<DiagnosticRecord>,
+    /// The errors and warnings described by `diagnostics`, for reporting them
+    /// with [`diagnostic`]
+    pub errors: Vec<AssemblerError>,
+}
+
+impl Output {
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+}
+
+/// Result of the assembly
+#[derive(Clone, Debug, Default)]
+pub struct Assembled {
+    pub fragments: Vec<Fragment>,
+    pub labels: AssemblerTable<LabelValue>,
+    pub constants: AssemblerTable<ConstantValue>,
+    /// Labels visible to other object files, by their exported name
+    pub exports: Vec<(String, LabelValue)>,
+    /// Alignment of each segment required by `.align`
+    pub alignments: HashMap<Segment, u32>,
+    /// Binary files included by `.incbin`, in the order of their statements
+    pub included_files: Vec<IncludedFile>,
+    /// Definitions of names and references to them
+    pub names: NameIndex,
+}
+
+/// Symbol or macro that a name in the source refers to
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NameTarget {
+    Symbol(QualifiedName),
+    Macro(QualifiedName),
+}
+
+/// Where symbols and macros are defined and which names in the source refer to them.
+/// Names inside macro bodies refer to a different target in each expansion.
+#[derive(Clone, Debug, Default)]
+pub struct NameIndex {
+    /// Span of the name in the definition of each target
+    pub definitions: HashMap<NameTarget, Span>,
+    /// Spans of names referring to a target, ordered by file and position
+    pub references: Vec<(Span, NameTarget)>,
+}
+
+impl Assembled {
+    /// Build the boot ROM image from all fragments that the memory layout stores in it.
+    pub fn image(&self, memory_layout: &MemoryLayout) -> Result<Image, AssemblerError> {
+        let mut image = Image::default();
+        // Load addresses of the fragments placed so far, segments may be loaded
+        // over each other even if the fragments don't overlap within a segment
+        let mut placed: Vec<(Range<u32>, &Span)> = Vec::new();
+        for fragment in self.fragments.iter() {
+            if let Some(load_address) =
+                memory_layout.load_address(fragment.segment, fragment.address)
+            {
+                if let Err(error) = image.add(load_address, &fragment.words) {
+                    let previous_span = placed
+                        .iter()
+                        .find(|(range, _)| range.contains(&error.offset))
+                        .map_or(&fragment.span, |(_, span)| span);
+                    return Err(AssemblerError::Overlap {
+                        span: fragment.span.clone(),
+                        previous_span: previous_span.clone(),
+                    });
+                }
+                let end = load_address + fragment.words.len() as u32;
+                placed.push((load_address..end, &fragment.span));
+            }
+        }
+        Ok(image)
+    }
+
+    /// Build a relocatable object file with one section per segment.
+    pub fn object_file(&self) -> ObjectFile {
+        let mut object = ObjectFile::default();
+        let mut section_indices: HashMap<Segment, usize> = HashMap::new();
+        let mut import_indices: HashMap<&str, usize> = HashMap::new();
+
+        let mut fragments: Vec<&Fragment> = self.fragments.iter().collect();
+        fragments.sort_by_key(|fragment| (fragment.segment, fragment.address));
+
+        for fragment in fragments.iter() {
+            section_indices.entry(fragment.segment).or_insert_with(|| {
+                object.sections.push(object::Section {
+                    segment: fragment.segment,
+                    alignment: self.alignments.get(&fragment.segment).copied().unwrap_or(1),
+                    data: Vec::new(),
+                    relocations: Vec::new(),
+                });
+                object.sections.len() - 1
+            });
+        }
+
+        for fragment in fragments {
+            let section_index = section_indices[&fragment.segment];
+            let section = &mut object.sections[section_index];
+
+            let start = usize::from(fragment.address);
+            let end = start + fragment.words.len();
+            if section.data.len() < end {
+                section.data.resize(end, 0);
+            }
+            section.data[start..end].copy_from_slice(&fragment.words);
+
+            for relocation in fragment.relocations.iter() {
+                let target = match &relocation.base {
+                    RelocationBase::Segment(segment) => {
+                        RelocationTarget::Section(section_indices[segment])
+                    }
+                    RelocationBase::Import(name) => {
+                        RelocationTarget::Import(*import_indices.entry(name).or_insert_with(|| {
+                            object.imports.push(name.clone());
+                            object.imports.len() - 1
+                        }))
+                    }
+                };
+                section.relocations.push(object::Relocation {
+                    offset: fragment.address + relocation.offset,
+                    kind: relocation.kind,
+                    target,
+                    addend: relocation.addend,
+                });
+            }
+        }
+
+        object.exports = self
+            .exports
+            .iter()
+            .map(|(name, label)| object::Export {
+                name: name.clone(),
+                section: section_indices[&label.segment],
+                offset: label.address,
+            })
+            .collect();
+
+        object
+    }
+}
+
+/// Check macro arguments against the kinds of their parameters.
+/// Returns spans of macro calls with a mismatched argument.
+fn check_macro_arguments(
+    layout: &layout::Layout,
+    evaluator: &Evaluator,
+    errors: &mut Vec<AssemblerError>,
+) -> HashSet<Span> {
+    let mut mismatched_calls = HashSet::new();
+    for check in layout.argument_checks.iter() {
+        let matches = match check.param.kind {
+            ParamKind::Reg => evaluator
+                .eval(check.arg, &check.scope)
+                .map(|value| matches!(value, Value::Register(_))),
+            ParamKind::Imm => evaluator
+                .eval(check.arg, &check.scope)
+                .map(|value| matches!(value, Value::Number(_) | Value::Relocatable(_))),
+            ParamKind::Label => evaluator.is_label(check.arg, &check.scope),
+            ParamKind::Expr => Ok(true),
+        };
+        match matches {
+            Ok(true) => continue,
+            Ok(false) => errors.push(AssemblerError::MacroArgumentKind {
+                span: check.arg.1.clone(),
+                param: check.param.name.0.clone(),
+                kind: check.param.kind,
+                param_span: check.param.name.1.clone(),
+            }),
+            Err(e) => errors.push(e),
+        }
+        mismatched_calls.insert(check.call_span.clone());
+    }
+    mismatched_calls
+}
+
+/// Evaluate all `.assert` conditions. Conditions that depend on label addresses
+/// can't be checked in relocatable output and are skipped.
+fn check_assertions(
+    layout: &layout::Layout,
+    evaluator: &Evaluator,
+    options: &AssembleOptions,
+    errors: &mut Vec<AssemblerError>,
+) {
+    for assertion in layout.assertions.iter() {
+        match evaluator.eval_number(assertion.condition, &assertion.scope) {
+            Ok(0) => {
+                // Show the values that the condition compared
+                let operands = match &assertion.condition.0 {
+                    Expr::BinaryOp { lhs, rhs, .. } => vec![lhs.as_ref(), rhs.as_ref()],
+                    Expr::UnaryOp { expr, .. } => vec![expr.as_ref()],
+                    _ => Vec::new(),
+                };
+                errors.push(AssemblerError::AssertionFailed(Box::new(
+                    AssertionFailure {
+                        span: assertion.span.clone(),
+                        condition_span: assertion.condition.1.clone(),
+                        message: assertion.message.map(ToOwned::to_owned),
+                        operands: operands
+                            .into_iter()
+                            .filter_map(|operand| {
+                                let value =
+                                    evaluator.eval_number(operand, &assertion.scope).ok()?;
+                                Some((operand.1.clone(), value))
+                            })
+                            .collect(),
+                    },
+                )));
+            }
+            Ok(_) => {}
+            Err(AssemblerError::NotRelocatable { .. }) if options.relocatable => {}
+            Err(e) => errors.push(e),
+        }
+    }
+}
+
+/// Check that no two fragments of a segment occupy the same address.
+/// Only possible after `.org` moves the address back.
+fn check_overlaps(fragments: &[Fragment], errors: &mut Vec<AssemblerError>) {
+    let mut sorted: Vec<(usize, &Fragment)> = fragments
+        .iter()
+        .enumerate()
+        .filter(|(_, fragment)| !fragment.words.is_empty())
+        .collect();
+    sorted.sort_by_key(|(_, fragment)| (fragment.segment, fragment.address));
+
+    // Fragment reaching furthest so far in the current segment
+    let mut furthest: Option<(usize, &Fragment)> = None;
+    for (index, fragment) in sorted {
+        let end = |fragment: &Fragment| u32::from(fragment.address) + fragment.words.len() as u32;
+        if let Some((other_index, other)) = furthest
+            && other.segment == fragment.segment
+        {
+            if end(other) > u32::from(fragment.address) {
+                let (first, second) = if other_index < index {
+                    (other, fragment)
+                } else {
+                    (fragment, other)
+                };
+                errors.push(AssemblerError::Overlap {
+                    span: second.span.clone(),
+                    previous_span: first.span.clone(),
+                });
+            }
+            if end(other) >= end(fragment) {
+                continue;
+            }
+        }
+        furthest = Some((index, fragment));
+    }
+}
+
+/// Check that every fragment is inside a region of its segment and doesn't cross page boundaries
+/// where the region doesn't allow it.
+fn check_memory_layout(
+    fragments: &[Fragment],
+    memory_layout: &MemoryLayout,
+    errors: &mut Vec<AssemblerError>,
+) {
+    let mut overflow_reported = HashSet::new();
+    for fragment in fragments
+        .iter()
+        .filter(|fragment| !fragment.words.is_empty())
+    {
+        let address = u32::from(fragment.address);
+        let length = fragment.words.len() as u32;
+        let placement = memory_layout
+            .region_at(fragment.segment, fragment.address)
+            .map(|region| (region, region.check_placement(address, length)));
+        match placement {
+            Some((_, Ok(()))) => {}
+            Some((region, Err(PlacementError::PageBoundary))) => {
+                errors.push(AssemblerError::PageBoundary {
+                    span: fragment.span.clone(),
+                    region: region.name.clone(),
+                });
+            }
+            Some((_, Err(PlacementError::OutOfRegion))) | None => {
+                if overflow_reported.insert(fragment.segment) {
+                    errors.push(AssemblerError::RegionOverflow {
+                        span: fragment.span.clone(),
+                        segment: fragment.segment,
+                    });
+                }
+            }
+        }
+    }
 }
 
 fn collect_macros_recursive<'ast>(
     ast: &'ast Ast,
     current_scope: &mut QualifiedName,
     table: &mut AssemblerTable<MacroDef<'ast>>,
+    errors: &mut Vec<AssemblerError>,
 ) {
     for (index, (item, span)) in ast.iter().enumerate() {
         match item {
@@ -175,22 +1093,271 @@ fn collect_macros_recursive<'ast>(
                 } else {
                     current_scope.push_anonymous(index);
                 }
-                collect_macros_recursive(content, current_scope, table);
+                collect_macros_recursive(content, current_scope, table, errors);
                 current_scope.pop();
             }
+            // Macros in conditional and repeated blocks are defined regardless of the condition
+            Item::If {
+                branches,
+                else_body,
+            } => {
+                for body in branches.iter().map(|(_, body)| body).chain(else_body) {
+                    collect_macros_recursive(body, current_scope, table, errors);
+                }
+            }
+            Item::Repeat { body, .. } | Item::For { body, .. } => {
+                collect_macros_recursive(body, current_scope, table, errors)
+            }
             Item::MacroDefinition { name, params, body } => {
-                let mut macro_name = current_scope.clone();
-                macro_name.push_name(name.clone());
-                table.0.insert(
-                    macro_name,
+                let macro_name = current_scope.with_names([name.as_str()]);
+                check_nested_macros(body, &macro_name, span, errors);
+                let previous = table.insert(
+                    macro_name.clone(),
                     MacroDef {
                         span: span.clone(),
+                        scope: current_scope.clone(),
                         params,
+                        contract: preceding_contract(ast, index),
                         body,
                     },
                 );
+                if let Some(previous) = previous {
+                    errors.push(AssemblerError::DuplicateDefinition {
+                        span: span.clone(),
+                        name: macro_name,
+                        previous_span: previous.span,
+                    });
+                }
             }
             _ => (),
         }
     }
 }
+
+/// `.contract` placed directly before the item at `index`
+pub fn preceding_contract(ast: &Ast, index: usize) -> Option<(&Contract, &Span)> {
+    match ast.get(index.checked_sub(1)?)? {
+        (Item::Contract { contract }, span) => Some((contract, span)),
+        _ => None,
+    }
+}
+
+fn check_nested_macros(
+    body: &Ast,
+    macro_name: &QualifiedName,
+    macro_span: &Span,
+    errors: &mut Vec<AssemblerError>,
+) {
+    for (item, span) in body.iter() {
+        match item {
+            Item::Scope { content, .. }
+            | Item::Repeat { body: content, .. }
+            | Item::For { body: content, .. } => {
+                check_nested_macros(content, macro_name, macro_span, errors)
+            }
+            Item::If {
+                branches,
+                else_body,
+            } => {
+                for body in branches.iter().map(|(_, body)| body).chain(else_body) {
+                    check_nested_macros(body, macro_name, macro_span, errors);
+                }
+            }
+            Item::MacroDefinition { .. } => errors.push(AssemblerError::NestedMacro {
+                span: span.clone(),
+                nested_in_name: macro_name.clone(),
+                nested_in_span: macro_span.clone(),
+            }),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::program;
+
+    /// Words of the image and all errors of a source assembled with command line definitions
+    fn assemble_defined(definitions: &[&str], source: &str) -> (Vec<u16>, Vec<AssemblerError>) {
+        let mut assembler = Assembler::default();
+        let mut errors = Vec::new();
+        let definitions: Vec<String> = definitions.iter().map(|d| d.to_string()).collect();
+        assembler.add_definitions(&definitions, &mut errors);
+        assembler.add_source("test.asm", source.to_owned(), &mut errors);
+        let options = AssembleOptions::default();
+        let assembled = assembler.assemble(&options, &mut errors);
+
+        let image = assembled
+            .image(&options.memory_layout)
+            .unwrap_or_else(|error| {
+                errors.push(error);
+                Image::default()
+            });
+        let words = image
+            .iter_blocks()
+            .flat_map(|(_, words)| words.iter().copied())
+            .collect();
+        (words, errors)
+    }
+
+    /// Diagnostics of a source assembled on its own
+    fn records(source: &str) -> Vec<DiagnosticRecord> {
+        let mut assembler = Assembler::default();
+        let mut errors = Vec::new();
+        assembler.add_source("test.asm", source.to_owned(), &mut errors);
+        assembler
+            .build(&AssembleOptions::default(), errors)
+            .diagnostics
+    }
+
+    const LOAD: &str = "macro load dst: reg, value: imm = 5 {\n    ldi dst, value\n}\n";
+
+    #[test]
+    fn macro_default_argument() {
+        assert_eq!(
+            program(&format!("{}load! r1\nload! r2, 7\n", LOAD)),
+            program("ldi r1, 5\nldi r2, 7\n")
+        );
+    }
+
+    #[test]
+    fn variadic_macro() {
+        let source = "macro words values...: imm {\n\
+                      .for i in 0..len(values) {\n.dw values[i] + len(values)\n}\n}\n\
+                      words! 1, 2, 3\nwords!\n";
+        assert_eq!(program(source), [4, 5, 6]);
+    }
+
+    #[test]
+    fn macro_argument_kind() {
+        let records = records(&format!("{}load! 5\n", LOAD));
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].code, "macro-argument-kind");
+        assert_eq!(records[0].message, "argument for `dst` must be a register");
+        let primary = records[0].primary_span.as_ref().unwrap();
+        assert_eq!((primary.line, primary.column), (4, 7));
+        let declaration = &records[0].secondary_spans[0];
+        assert_eq!((declaration.line, declaration.column), (1, 12));
+        assert_eq!(
+            declaration.label.as_deref(),
+            Some("parameter declared here")
+        );
+    }
+
+    #[test]
+    fn macro_argument_count() {
+        let codes: Vec<_> = records(&format!("{}load!\nload! r1, 2, 3\n", LOAD))
+            .iter()
+            .map(|record| record.code)
+            .collect();
+        assert_eq!(codes, ["macro-argument-count", "macro-argument-count"]);
+    }
+
+    #[test]
+    fn unknown_instruction_at_mnemonic() {
+        let records = records("nop\n  ldu r1, 5\n");
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].code, "unknown-instruction");
+        assert_eq!(records[0].help, ["did you mean `ldui`?"]);
+        let primary = records[0].primary_span.as_ref().unwrap();
+        assert_eq!(
+            (
+                primary.line,
+                primary.column,
+                primary.end_line,
+                primary.end_column
+            ),
+            (2, 3, 2, 6)
+        );
+    }
+
+    #[test]
+    fn assertion_holds() {
+        let source = "buffer: {\n.space 4\n}\n.assert sizeof(buffer) == 4, \"size\"\n\
+                      .assert end - buffer <= 4\nend:\n";
+        assert!(records(source).is_empty());
+    }
+
+    #[test]
+    fn assertion_fails() {
+        let source = "buffer: {\n.space 6\n}\n.assert sizeof(buffer) <= 4, \"buffer too big\"\n";
+        let records = records(source);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].code, "assertion-failed");
+        assert_eq!(records[0].message, "assertion failed: buffer too big");
+        let primary = records[0].primary_span.as_ref().unwrap();
+        assert_eq!(primary.line, 4);
+        let labels: Vec<_> = records[0]
+            .secondary_spans
+            .iter()
+            .map(|span| span.label.as_deref().unwrap())
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                "this evaluates to zero",
+                "this is 6 (0x6)",
+                "this is 4 (0x4)"
+            ]
+        );
+    }
+
+    #[test]
+    fn definition_defaults_to_one() {
+        let (words, errors) = assemble_defined(&["DEBUG"], ".dw DEBUG\n");
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(words, [1]);
+    }
+
+    #[test]
+    fn definition_with_expression() {
+        let (words, errors) =
+            assemble_defined(&["SIZE=2 * 3", "WIDTH=SIZE + 1"], ".dw SIZE, WIDTH\n");
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(words, [6, 7]);
+    }
+
+    #[test]
+    fn defined() {
+        let source = ".dw defined(DEBUG), defined(RELEASE)\n\
+                      .if defined(DEBUG) {\n.dw 5\n}\n";
+        let (words, errors) = assemble_defined(&["DEBUG"], source);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(words, [1, 0, 5]);
+
+        let (words, errors) = assemble_defined(&[], source);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(words, [0, 0]);
+    }
+
+    #[test]
+    fn invalid_definition() {
+        let (_, errors) = assemble_defined(&["SIZE=="], ".dw 0\n");
+        assert!(
+            matches!(errors.as_slice(), [AssemblerError::SyntaxError(_)]),
+            "{:?}",
+            errors
+        );
+    }
+
+    #[test]
+    fn read_files_skip_failed_reads() {
+        let directory = std::env::temp_dir().join(format!("read-files-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let present = directory.join("present.asm");
+        fs::write(&present, "nop\n").unwrap();
+
+        let mut assembler = Assembler::default();
+        let mut errors = Vec::new();
+        assembler.add_file(present.clone(), None, &mut errors);
+        assembler.add_file(directory.join("missing.asm"), None, &mut errors);
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert!(matches!(
+            errors.as_slice(),
+            [AssemblerError::FileOpenFailed { .. }]
+        ));
+        assert_eq!(assembler.read_files(), [present]);
+    }
+}
@@ -1,8 +1,73 @@
 use std::fmt::Debug;
 
-use chumsky::error::Rich;
+use chumsky::error::{Rich, RichPattern, RichReason};
+use itertools::Itertools;
 
-use crate::types::{FileId, ParseError, Span};
+use crate::{
+    lexer::Token,
+    types::{FileId, ParseError, Span},
+};
+
+/// How a token is shown in error messages
+pub trait Describe {
+    fn describe(&self) -> String;
+
+    /// Binary operators are listed together as "operator" among expected tokens
+    fn is_operator(&self) -> bool {
+        false
+    }
+}
+
+impl Describe for char {
+    fn describe(&self) -> String {
+        match self {
+            '\n' => "end of line".to_owned(),
+            c => format!("`{}`", c.escape_debug()),
+        }
+    }
+}
+
+impl Describe for Token<'_> {
+    fn describe(&self) -> String {
+        match self {
+            Token::Eol => "end of line".to_owned(),
+            token => format!("`{}`", token),
+        }
+    }
+
+    fn is_operator(&self) -> bool {
+        matches!(
+            self,
+            Token::Asterisk
+                | Token::Slash
+                | Token::Percent
+                | Token::Plus
+                | Token::Minus
+                | Token::DoubleLt
+                | Token::DoubleGt
+                | Token::Ampersand
+                | Token::Caret
+                | Token::Pipe
+                | Token::DoubleEqual
+                | Token::Neq
+                | Token::Lt
+                | Token::Gt
+                | Token::Le
+                | Token::Ge
+                | Token::DoubleAmpersand
+                | Token::DoublePipe
+        )
+    }
+}
+
+fn describe_pattern<T: Debug + Describe>(pattern: &RichPattern<T>) -> String {
+    match pattern {
+        RichPattern::Token(token) if token.is_operator() => "operator".to_owned(),
+        RichPattern::Token(token) => token.describe(),
+        RichPattern::Label(label) => label.to_string(),
+        pattern => format!("{:?}", pattern),
+    }
+}
 
 impl chumsky::span::Span for Span {
     type Context = Option<FileId>;
@@ -29,20 +94,25 @@ impl chumsky::span::Span for Span {
     }
 }
 
-impl<'a, T: Debug> From<Rich<'a, T, Span>> for ParseError {
+impl<'a, T: Debug + Describe> From<Rich<'a, T, Span>> for ParseError {
     fn from(value: Rich<'a, T, Span>) -> Self {
         ParseError {
             span: value.span().clone(),
-            found: value.found().map(|found| format!("{:?}", found)),
+            message: match value.reason() {
+                RichReason::Custom(message) => Some(message.clone()),
+                _ => None,
+            },
+            found: value.found().map(Describe::describe),
             expected: value
                 .expected()
-                .into_iter()
-                .map(|pattern| format!("{:?}", pattern))
+                .filter(|pattern| !matches!(pattern, RichPattern::SomethingElse))
+                .map(describe_pattern)
+                .sorted()
+                .dedup()
                 .collect(),
             context: value
                 .contexts()
-                .into_iter()
-                .map(|(pattern, span)| (format!("{:?}", pattern), span.clone()))
+                .map(|(pattern, span)| (describe_pattern(pattern), span.clone()))
                 .collect(),
         }
     }
@@ -0,0 +1,184 @@
+//! Checking macro expansions and routines against their `.contract`.
+//!
+//! Branch targets are only known at runtime, so the analysis goes through the
+//! statements in address order, as if the code was straight-line. A location is
+//! defined once it is listed as an input or written by an earlier instruction.
+use std::collections::HashSet;
+
+use toolchain_core::instruction::{Instruction, Location};
+
+use crate::{
+    assembler::{Fragment, QualifiedName},
+    eval::Evaluator,
+    layout::{ContractCheck, Layout, StatementContent},
+    parser::Expr,
+    types::{AssemblerError, Spanned},
+};
+
+/// Name that stands for the carry flag in a contract
+const CARRY_NAME: &str = "C";
+
+/// Warn about reads of undefined locations and writes to locations that the
+/// contract doesn't list as outputs or clobbers.
+pub fn check_contracts(
+    layout: &Layout,
+    fragments: &[Fragment],
+    evaluator: &Evaluator,
+    errors: &mut Vec<AssemblerError>,
+) {
+    for check in layout.contract_checks.iter() {
+        let lists = [
+            &check.contract.inputs,
+            &check.contract.outputs,
+            &check.contract.clobbers,
+        ]
+        .map(|list| locations(list, &check.scope, evaluator, errors));
+        let [Some(inputs), Some(outputs), Some(clobbers)] = lists else {
+            continue;
+        };
+
+        check_statements(check, layout, fragments, inputs, outputs, clobbers, errors);
+    }
+}
+
+fn check_statements(
+    check: &ContractCheck,
+    layout: &Layout,
+    fragments: &[Fragment],
+    mut defined: HashSet<Location>,
+    outputs: HashSet<Location>,
+    clobbers: HashSet<Location>,
+    errors: &mut Vec<AssemblerError>,
+) {
+    // Each location is reported only once per check
+    let mut reported_reads = HashSet::new();
+    let mut reported_writes = HashSet::new();
+
+    for index in check.statements.clone() {
+        let statement = &layout.statements[index];
+        if !matches!(statement.content, StatementContent::Instruction { .. }) {
+            continue;
+        }
+
+        for instruction in fragments[index]
+            .words
+            .iter()
+            .filter_map(|word| Instruction::decode(*word))
+        {
+            for location in instruction.reads() {
+                if !defined.contains(&location) && reported_reads.insert(location) {
+                    errors.push(AssemblerError::UndefinedRead {
+                        span: statement.span.clone(),
+                        location: location.to_string(),
+                        contract_span: check.span.clone(),
+                    });
+                }
+            }
+            for location in instruction.writes() {
+                defined.insert(location);
+                if !outputs.contains(&location)
+                    && !clobbers.contains(&location)
+                    && reported_writes.insert(location)
+                {
+                    errors.push(AssemblerError::UndeclaredWrite {
+                        span: statement.span.clone(),
+                        location: location.to_string(),
+                        contract_span: check.span.clone(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Evaluate a list of registers from a contract, `None` if any of them fails
+fn locations(
+    list: &[Spanned<Expr>],
+    scope: &QualifiedName,
+    evaluator: &Evaluator,
+    errors: &mut Vec<AssemblerError>,
+) -> Option<HashSet<Location>> {
+    let mut ok = true;
+    let locations = list
+        .iter()
+        .filter_map(|expr| match &expr.0 {
+            Expr::QualifiedName(names) if matches!(names.as_slice(), [(name, _)] if name == CARRY_NAME) => {
+                Some(Location::Carry)
+            }
+            _ => match evaluator.eval_register(expr, scope) {
+                Ok(reg) => Some(Location::Register(reg)),
+                Err(e) => {
+                    errors.push(e);
+                    ok = false;
+                    None
+                }
+            },
+        })
+        .collect();
+    ok.then_some(locations)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        AssembleOptions, AssemblerError,
+        testing::{assemble_with, codes},
+    };
+
+    /// Locations reported by the contract check, as `read r1` or `write r1`
+    fn violations(source: &str) -> Vec<String> {
+        let (_, errors) = assemble_with(source, &AssembleOptions::default());
+        errors
+            .iter()
+            .map(|error| match error {
+                AssemblerError::UndefinedRead { location, .. } => format!("read {}", location),
+                AssemblerError::UndeclaredWrite { location, .. } => format!("write {}", location),
+                error => panic!("unexpected error {:?}", error),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn clean_routine() {
+        let source = ".contract in(r1, r2) out(r3) clobbers(r4, C)\n\
+                      sum:\n    add r4, r1, r2\n    addc r4, r1\n    add r3, r4, r2\n";
+        assert!(codes(source).is_empty());
+    }
+
+    #[test]
+    fn use_before_definition() {
+        let source = ".contract in(r1) out(r3) clobbers(C)\n\
+                      sum:\n    add r3, r1, r2\n    addc r3, r2\n";
+        assert_eq!(violations(source), ["read r2"]);
+    }
+
+    #[test]
+    fn carry_read_before_definition() {
+        let source = ".contract in(r1) out(r1) clobbers(C)\nrotate:\n    shrc r1, r1\n";
+        assert_eq!(violations(source), ["read C"]);
+    }
+
+    #[test]
+    fn clobbered_input() {
+        let source = ".contract in(r1, r2) out(r3) clobbers(C)\n\
+                      sum:\n    add r1, r1, r2\n    add r3, r1, r1\n";
+        assert_eq!(violations(source), ["write r1"]);
+    }
+
+    #[test]
+    fn routine_ends_at_next_label() {
+        let source = ".contract in(r1) out(r1) clobbers(C)\n\
+                      inc:\n    add r1, r1, r1\n\
+                      1:\n    add r1, r1, r1\n\
+                      other:\n    add r5, r6, r7\n";
+        assert!(codes(source).is_empty());
+    }
+
+    #[test]
+    fn checked_in_each_expansion() {
+        let source = ".contract in(a) out(a) clobbers(C)\n\
+                      macro double a {\n    add a, a, a\n    add r5, a, a\n}\n\
+                      double! r1\ndouble! r2\n";
+        assert_eq!(violations(source), ["write r5", "write r5"]);
+    }
+}
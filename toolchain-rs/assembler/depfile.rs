@@ -0,0 +1,67 @@
+//! Makefile dependency rules, so that build systems know which files an output was
+//! assembled from.
+
+use std::{io, path::Path};
+
+use itertools::Itertools;
+
+/// Write a rule making `target` depend on all `dependencies`, in the format understood
+/// by make and ninja.
+pub fn write_depfile(
+    out: &mut impl io::Write,
+    target: &Path,
+    dependencies: &[&Path],
+) -> io::Result<()> {
+    write!(out, "{}:", escape(target))?;
+    for dependency in dependencies.iter().unique() {
+        write!(out, " \\\n  {}", escape(dependency))?;
+    }
+    writeln!(out)
+}
+
+/// Escape characters that make treats specially in file names
+fn escape(path: &Path) -> String {
+    let mut escaped = String::new();
+    for c in path.to_string_lossy().chars() {
+        match c {
+            ' ' | '#' => escaped.push('\\'),
+            '$' => escaped.push('$'),
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("plain.asm", "plain.asm" ; "plain")]
+    #[test_case("with space.asm", "with\\ space.asm" ; "space")]
+    #[test_case("cost$.asm", "cost$$.asm" ; "dollar")]
+    #[test_case("#1.asm", "\\#1.asm" ; "hash")]
+    fn escaping(path: &str, expected: &str) {
+        assert_eq!(escape(Path::new(path)), expected);
+    }
+
+    #[test]
+    fn rule() {
+        let mut out = Vec::new();
+        write_depfile(
+            &mut out,
+            Path::new("out.hex"),
+            &[
+                Path::new("main.asm"),
+                Path::new("lib.asm"),
+                Path::new("main.asm"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "out.hex: \\\n  main.asm \\\n  lib.asm\n"
+        );
+    }
+}
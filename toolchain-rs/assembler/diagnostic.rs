@@ -0,0 +1,931 @@
+//! Errors and warnings prepared for presentation, either as terminal reports
+//! or as JSON records for tools.
+use std::collections::HashMap;
+
+use ariadne::{Color, Report, ReportKind};
+use itertools::Itertools;
+use serde::Serialize;
+use strum::IntoEnumIterator;
+
+use crate::{
+    assembler::Assembler,
+    types::{
+        ArgumentCountError, AssemblerError, AssertionFailure, ColumnUnit, DelaySlotHazard, FileId,
+        LineIndex, Severity, Span, WarningClass,
+    },
+};
+
+/// Sources of the assembled files, for printing reports
+#[derive(Debug)]
+pub struct AriadneCache<'assembler> {
+    assembler: &'assembler Assembler,
+    cache: HashMap<FileId, ariadne::Source<String>>,
+    empty_source: ariadne::Source<String>,
+}
+
+impl<'assembler> ariadne::Cache<Option<FileId>> for AriadneCache<'assembler> {
+    type Storage = String;
+
+    fn fetch(
+        &mut self,
+        id: &Option<FileId>,
+    ) -> Result<&ariadne::Source<Self::Storage>, impl std::fmt::Debug> {
+        Ok::<_, std::io::Error>(if let Some(id) = id {
+            match self.cache.entry(*id) {
+                std::collections::hash_map::Entry::Occupied(occupied_entry) => {
+                    occupied_entry.into_mut()
+                }
+                std::collections::hash_map::Entry::Vacant(vacant_entry) => {
+                    let content = self
+                        .assembler
+                        .get_source(*id)
+                        .expect("It should be impossible to get an invalid FileId");
+                    vacant_entry.insert(content.to_owned().into())
+                }
+            }
+        } else {
+            &self.empty_source
+        })
+    }
+
+    fn display<'a>(&self, id: &'a Option<FileId>) -> Option<impl std::fmt::Display + 'a> {
+        if let Some(id) = id {
+            let path = self
+                .assembler
+                .get_path(*id)
+                .expect("It should be impossible to get an invalid FileId");
+            Some(format!("{}", path.display()))
+        } else {
+            Some("<no file>".to_string())
+        }
+    }
+}
+
+impl<'assembler> AriadneCache<'assembler> {
+    pub fn new(assembler: &Assembler) -> AriadneCache<'_> {
+        AriadneCache {
+            assembler,
+            cache: Default::default(),
+            empty_source: String::new().into(),
+        }
+    }
+}
+
+impl ariadne::Span for &Span {
+    type SourceId = Option<FileId>;
+
+    fn source(&self) -> &Self::SourceId {
+        &self.file_id
+    }
+
+    fn start(&self) -> usize {
+        self.start
+    }
+
+    fn end(&self) -> usize {
+        self.end
+    }
+}
+
+/// Error or warning with its message, labelled source spans and notes
+#[derive(Clone, Debug)]
+pub struct Diagnostic<'a> {
+    pub severity: Severity,
+    /// Kind of the error, kebab-case name of the `AssemblerError` variant
+    pub code: &'static str,
+    /// Primary location
+    pub span: &'a Span,
+    pub message: String,
+    pub labels: Vec<Label<'a>>,
+    pub notes: Vec<String>,
+    pub help: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Label<'a> {
+    pub span: &'a Span,
+    pub message: Option<String>,
+    pub color: Option<Color>,
+}
+
+impl<'a> Label<'a> {
+    pub fn new(span: &'a Span) -> Self {
+        Label {
+            span,
+            message: None,
+            color: None,
+        }
+    }
+
+    pub fn with_message(mut self, message: impl ToString) -> Self {
+        self.message = Some(message.to_string());
+        self
+    }
+
+    pub fn with_color(mut self, color: Color) -> Self {
+        self.color = Some(color);
+        self
+    }
+}
+
+impl<'a> Diagnostic<'a> {
+    pub fn build(severity: Severity, span: &'a Span) -> Self {
+        Diagnostic {
+            severity,
+            code: "",
+            span,
+            message: String::new(),
+            labels: Vec::new(),
+            notes: Vec::new(),
+            help: Vec::new(),
+        }
+    }
+
+    pub fn with_message(mut self, message: impl ToString) -> Self {
+        self.message = message.to_string();
+        self
+    }
+
+    pub fn with_label(mut self, label: Label<'a>) -> Self {
+        self.labels.push(label);
+        self
+    }
+
+    pub fn with_note(mut self, note: impl ToString) -> Self {
+        self.notes.push(note.to_string());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl ToString) -> Self {
+        self.help.push(help.to_string());
+        self
+    }
+
+    /// Terminal report, source ids are file ids
+    pub fn to_report(&self) -> Report<'a, &'a Span> {
+        let kind = match self.severity {
+            Severity::Error => ReportKind::Error,
+            Severity::Warning => ReportKind::Warning,
+        };
+        let mut report = Report::build(kind, self.span).with_message(&self.message);
+        for label in self.labels.iter() {
+            let mut ariadne_label = ariadne::Label::new(label.span);
+            if let Some(message) = &label.message {
+                ariadne_label = ariadne_label.with_message(message);
+            }
+            if let Some(color) = label.color {
+                ariadne_label = ariadne_label.with_color(color);
+            }
+            report = report.with_label(ariadne_label);
+        }
+        for help in self.help.iter() {
+            report = report.with_help(help);
+        }
+        for note in self.notes.iter() {
+            report = report.with_note(note);
+        }
+        report.finish()
+    }
+
+    /// Owned description with lines and columns, for tools using the assembler as a library
+    pub fn to_record(&self, locations: &mut Locations) -> DiagnosticRecord {
+        // The first label at the primary location carries its message
+        let primary_label = self.labels.iter().position(|label| label.span == self.span);
+        DiagnosticRecord {
+            severity: self.severity,
+            code: self.code,
+            message: self.message.clone(),
+            primary_span: locations.get(
+                self.span,
+                primary_label.and_then(|i| self.labels[i].message.clone()),
+            ),
+            secondary_spans: self
+                .labels
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| Some(*i) != primary_label)
+                .filter_map(|(_, label)| locations.get(label.span, label.message.clone()))
+                .collect(),
+            notes: self.notes.clone(),
+            help: self.help.clone(),
+        }
+    }
+
+    /// Single line JSON record, for editors and other tools
+    pub fn to_json(&self, locations: &mut Locations) -> String {
+        serde_json::to_string(&self.to_record(locations))
+            .expect("diagnostics are always serializable")
+    }
+}
+
+/// Error or warning resolved to file names, lines and columns
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DiagnosticRecord {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    /// Missing for errors not related to a place in the sources
+    pub primary_span: Option<RecordSpan>,
+    pub secondary_spans: Vec<RecordSpan>,
+    pub notes: Vec<String>,
+    pub help: Vec<String>,
+}
+
+/// Source range, lines and columns are one based, `start` and `end` are byte offsets
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RecordSpan {
+    pub file: String,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+    pub label: Option<String>,
+}
+
+/// Converts spans to file names, lines and columns
+pub struct Locations<'a> {
+    assembler: &'a Assembler,
+    indices: HashMap<FileId, LineIndex>,
+}
+
+impl<'a> Locations<'a> {
+    pub fn new(assembler: &'a Assembler) -> Self {
+        Locations {
+            assembler,
+            indices: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, span: &Span, label: Option<String>) -> Option<RecordSpan> {
+        let file_id = span.file_id?;
+        let source = self.assembler.get_source(file_id)?;
+        let index = self
+            .indices
+            .entry(file_id)
+            .or_insert_with(|| LineIndex::new(source));
+
+        let position = |offset: usize| {
+            let (line, column) = index.position(source, offset, ColumnUnit::Char);
+            (line + 1, column + 1)
+        };
+        let (line, column) = position(span.start);
+        let (end_line, end_column) = position(span.end);
+
+        Some(RecordSpan {
+            file: self.assembler.get_path(file_id)?.display().to_string(),
+            start: span.start,
+            end: span.end,
+            line,
+            column,
+            end_line,
+            end_column,
+            label,
+        })
+    }
+}
+
+/// Describe an error or warning, reported with the given severity
+pub fn diagnostic(error: &AssemblerError, severity: Severity) -> Diagnostic<'_> {
+    let diagnostic = match error {
+        AssemblerError::InvalidToken(err) | AssemblerError::SyntaxError(err) => {
+            let message = match &err.message {
+                Some(message) => format!("syntax error: {}", message),
+                None => format!(
+                    "syntax error: unexpected {}",
+                    err.found.as_deref().unwrap_or("end of input")
+                ),
+            };
+            let mut report = Diagnostic::build(Severity::Error, &err.span)
+                .with_message(message)
+                .with_label(
+                    Label::new(&err.span)
+                        .with_message("error occurred here")
+                        .with_color(Color::Red),
+                );
+            match err.expected.as_slice() {
+                [] => {}
+                [expected] => report = report.with_note(format!("expected {}", expected)),
+                expected => {
+                    report = report.with_note(format!("expected one of {}", expected.join(", ")))
+                }
+            }
+
+            for (ctx, span) in err.context.iter() {
+                report = report.with_label(
+                    Label::new(span)
+                        .with_message(ctx.clone())
+                        .with_color(Color::Yellow),
+                );
+            }
+
+            report
+        }
+
+        AssemblerError::NestedMacro {
+            span,
+            nested_in_name,
+            nested_in_span,
+        } => Diagnostic::build(Severity::Error, span)
+            .with_message(format!("nested macro inside `{}`", nested_in_name))
+            .with_label(
+                Label::new(span)
+                    .with_message("macro defined here")
+                    .with_color(Color::Red),
+            )
+            .with_label(
+                Label::new(nested_in_span)
+                    .with_message("enclosing macro")
+                    .with_color(Color::Yellow),
+            ),
+
+        AssemblerError::FileOpenFailed {
+            span,
+            file_path,
+            error,
+        } => {
+            let report =
+                Diagnostic::build(Severity::Error, span.as_ref().unwrap_or(&NO_SPAN)).with_message(
+                    format!("could not open file {}: {}", file_path.display(), error),
+                );
+            match span {
+                Some(span) => report.with_label(
+                    Label::new(span)
+                        .with_message("file named here")
+                        .with_color(Color::Red),
+                ),
+                None => report,
+            }
+        }
+
+        AssemblerError::FileWriteFailed { file_path, error } => {
+            Diagnostic::build(Severity::Error, &NO_SPAN).with_message(format!(
+                "could not write file {}: {}",
+                file_path.display(),
+                error
+            ))
+        }
+
+        AssemblerError::DuplicateDefinition {
+            span,
+            name,
+            previous_span,
+        } => Diagnostic::build(Severity::Error, span)
+            .with_message(format!("`{}` is defined multiple times", name))
+            .with_label(
+                Label::new(span)
+                    .with_message("redefined here")
+                    .with_color(Color::Red),
+            )
+            .with_label(
+                Label::new(previous_span)
+                    .with_message("previous definition")
+                    .with_color(Color::Yellow),
+            ),
+
+        AssemblerError::UndefinedName { span, name } => simple_report(
+            span,
+            format!("cannot find `{}` in this scope", name),
+            "not found",
+        ),
+
+        AssemblerError::UndefinedMacro { span, name } => simple_report(
+            span,
+            format!("cannot find macro `{}` in this scope", name),
+            "macro not found",
+        ),
+
+        AssemblerError::MacroArgumentCount(error) => {
+            let ArgumentCountError {
+                span,
+                name,
+                min,
+                max,
+                found,
+                definition_span,
+            } = error.as_ref();
+            Diagnostic::build(Severity::Error, span)
+                .with_message(format!(
+                    "macro `{}` takes {} arguments, but {} were given",
+                    name,
+                    match max {
+                        Some(max) if max == min => min.to_string(),
+                        Some(max) => format!("{} to {}", min, max),
+                        None => format!("at least {}", min),
+                    },
+                    found
+                ))
+                .with_label(
+                    Label::new(span)
+                        .with_message("called here")
+                        .with_color(Color::Red),
+                )
+                .with_label(
+                    Label::new(definition_span)
+                        .with_message("macro defined here")
+                        .with_color(Color::Yellow),
+                )
+        }
+
+        AssemblerError::MacroArgumentKind {
+            span,
+            param,
+            kind,
+            param_span,
+        } => Diagnostic::build(Severity::Error, span)
+            .with_message(format!(
+                "argument for `{}` must be {}",
+                param,
+                kind.description()
+            ))
+            .with_label(
+                Label::new(span)
+                    .with_message("mismatched argument")
+                    .with_color(Color::Red),
+            )
+            .with_label(
+                Label::new(param_span)
+                    .with_message("parameter declared here")
+                    .with_color(Color::Yellow),
+            ),
+
+        AssemblerError::VariadicParameter { span, name } => simple_report(
+            span,
+            format!("`{}` is a variadic parameter", name),
+            format!("use `{}[i]` for a single argument or `len({})`", name, name),
+        ),
+
+        AssemblerError::AssertionFailed(failure) => {
+            let AssertionFailure {
+                span,
+                condition_span,
+                message,
+                operands,
+            } = failure.as_ref();
+            let mut report = Diagnostic::build(Severity::Error, span)
+                .with_message(match message {
+                    Some(message) => format!("assertion failed: {}", message),
+                    None => "assertion failed".to_string(),
+                })
+                .with_label(
+                    Label::new(condition_span)
+                        .with_message("this evaluates to zero")
+                        .with_color(Color::Red),
+                );
+            for (operand_span, value) in operands {
+                report = report.with_label(
+                    Label::new(operand_span)
+                        .with_message(format!("this is {} ({:#x})", value, value))
+                        .with_color(Color::Yellow),
+                );
+            }
+            report
+        }
+
+        AssemblerError::FieldOverrun {
+            span,
+            field_span,
+            offset,
+            size,
+        } => Diagnostic::build(Severity::Error, span)
+            .with_message("offset is outside of the struct field")
+            .with_label(
+                Label::new(span)
+                    .with_message(format!(
+                        "this is {} words into a field of {} words",
+                        offset, size
+                    ))
+                    .with_color(Color::Red),
+            )
+            .with_label(
+                Label::new(field_span)
+                    .with_message("field used here")
+                    .with_color(Color::Yellow),
+            ),
+
+        AssemblerError::DuplicateJumpTableCase {
+            span,
+            value,
+            previous_span,
+        } => Diagnostic::build(Severity::Error, span)
+            .with_message(format!(
+                "jump table case {} is listed multiple times",
+                value
+            ))
+            .with_label(
+                Label::new(span)
+                    .with_message("repeated here")
+                    .with_color(Color::Red),
+            )
+            .with_label(
+                Label::new(previous_span)
+                    .with_message("previous case")
+                    .with_color(Color::Yellow),
+            ),
+
+        AssemblerError::MissingJumpTableCase { span, value } => simple_report(
+            span,
+            format!("jump table has no case {}", value),
+            "add the case or a `default` target",
+        ),
+
+        AssemblerError::JumpTableRegisters { span } => simple_report(
+            span,
+            "invalid jump table temporary register",
+            "must be different from r0 and from the index register",
+        ),
+
+        AssemblerError::MisplacedPoolLiteral { span } => simple_report(
+            span,
+            "literals can only be loaded by `ldi`",
+            "literal pool operand",
+        ),
+
+        AssemblerError::LiteralOutOfReach { span, offset } => simple_report(
+            span,
+            "literal pool is out of `ldpc` reach",
+            format!(
+                "pool entry is {} words away, add a `.pool` closer to this instruction",
+                offset
+            ),
+        ),
+
+        AssemblerError::UnplacedLiteral { span } => simple_report(
+            span,
+            "literal has no pool entry",
+            "no literal pool was placed for this instruction",
+        ),
+
+        AssemblerError::MisplacedContract { span } => simple_report(
+            span,
+            "`.contract` must be followed by a macro definition or a label",
+            "contract for nothing",
+        ),
+
+        AssemblerError::UndefinedRead {
+            span,
+            location,
+            contract_span,
+        } => Diagnostic::build(severity, span)
+            .with_message(format!("`{}` is read before it is defined", location))
+            .with_label(
+                Label::new(span)
+                    .with_message(format!("reads `{}`", location))
+                    .with_color(Color::Yellow),
+            )
+            .with_label(
+                Label::new(contract_span)
+                    .with_message("not listed as an input here")
+                    .with_color(Color::Blue),
+            ),
+
+        AssemblerError::UndeclaredWrite {
+            span,
+            location,
+            contract_span,
+        } => Diagnostic::build(severity, span)
+            .with_message(format!("`{}` is written but not declared", location))
+            .with_label(
+                Label::new(span)
+                    .with_message(format!("writes `{}`", location))
+                    .with_color(Color::Yellow),
+            )
+            .with_label(
+                Label::new(contract_span)
+                    .with_message("not listed as an output or clobber here")
+                    .with_color(Color::Blue),
+            ),
+
+        AssemblerError::UnusedLabel { span, name } => Diagnostic::build(severity, span)
+            .with_message(format!("label `{}` is never used", name))
+            .with_label(
+                Label::new(span)
+                    .with_message("defined here")
+                    .with_color(Color::Yellow),
+            ),
+
+        AssemblerError::DelaySlot {
+            span,
+            branch_span,
+            hazard,
+        } => {
+            let (message, label) = match hazard {
+                DelaySlotHazard::Branch => (
+                    "branch in the delay slot of another branch",
+                    "executes in the delay slot",
+                ),
+                DelaySlotHazard::PartialInstruction => (
+                    "only a part of this executes in the delay slot of a branch",
+                    "only the first instruction executes in the delay slot",
+                ),
+                DelaySlotHazard::Data => (
+                    "data in the delay slot of a branch",
+                    "executes as an instruction",
+                ),
+            };
+            Diagnostic::build(severity, span)
+                .with_message(message)
+                .with_label(
+                    Label::new(span)
+                        .with_message(label)
+                        .with_color(Color::Yellow),
+                )
+                .with_label(
+                    Label::new(branch_span)
+                        .with_message("delay slot of this branch")
+                        .with_color(Color::Blue),
+                )
+                .with_help("put a `nop` after the branch")
+        }
+
+        AssemblerError::TruncatedImmediate {
+            span,
+            value,
+            truncated,
+        } => Diagnostic::build(severity, span)
+            .with_message(format!(
+                "immediate `{}` is out of the signed range of the field",
+                value
+            ))
+            .with_label(
+                Label::new(span)
+                    .with_message(format!("encoded as {}", truncated))
+                    .with_color(Color::Yellow),
+            )
+            .with_help(format!("write `{}` if this is intended", truncated)),
+
+        AssemblerError::ShadowedName {
+            span,
+            name,
+            previous_span,
+        } => Diagnostic::build(severity, span)
+            .with_message(format!(
+                "`{}` shadows a definition from an outer scope",
+                name
+            ))
+            .with_label(
+                Label::new(span)
+                    .with_message("defined here")
+                    .with_color(Color::Yellow),
+            )
+            .with_label(
+                Label::new(previous_span)
+                    .with_message("hidden definition")
+                    .with_color(Color::Blue),
+            ),
+
+        AssemblerError::UnknownWarningClass { span, name } => simple_report(
+            span,
+            format!("unknown warning class `{}`", name),
+            "unknown class",
+        )
+        .with_note(format!(
+            "known classes are {}",
+            WarningClass::iter().join(", ")
+        )),
+
+        AssemblerError::MacroRecursionLimit { span } => simple_report(
+            span,
+            "macro expansion is nested too deeply",
+            "while expanding this macro",
+        ),
+
+        AssemblerError::RecursiveDefinition { span } => simple_report(
+            span,
+            "definition is recursive or nested too deeply",
+            "while evaluating this",
+        ),
+
+        AssemblerError::UnknownInstruction {
+            span,
+            name,
+            suggestion,
+        } => {
+            let report = simple_report(
+                span,
+                format!("unknown instruction `{}`", name),
+                "unknown instruction",
+            );
+            match suggestion {
+                Some(suggestion) => report.with_help(format!("did you mean `{}`?", suggestion)),
+                None => report,
+            }
+        }
+
+        AssemblerError::OperandCount {
+            span,
+            expected,
+            found,
+        } => simple_report(
+            span,
+            format!(
+                "instruction takes {} operands, but {} were given",
+                expected, found
+            ),
+            "wrong number of operands",
+        ),
+
+        AssemblerError::ExpectedRegister { span } => {
+            simple_report(span, "expected a register", "not a register")
+        }
+
+        AssemblerError::ExpectedControlRegister { span } => simple_report(
+            span,
+            "expected a control register",
+            "not a control register",
+        ),
+
+        AssemblerError::ExpectedValue { span } => simple_report(
+            span,
+            "expected a numeric value",
+            "registers cannot be used as values",
+        ),
+
+        AssemblerError::ValueOutOfRange {
+            span,
+            value,
+            min,
+            max,
+        } => simple_report(
+            span,
+            format!("value {} is out of range", value),
+            format!("expected value between {} and {}", min, max),
+        ),
+
+        AssemblerError::DivisionByZero { span } => {
+            simple_report(span, "division by zero", "this evaluates to zero")
+        }
+
+        AssemblerError::AddressOverflow { span } => simple_report(
+            span,
+            "program does not fit into the address space",
+            "overflow occurs here",
+        ),
+
+        AssemblerError::NotRelocatable { span } => simple_report(
+            span,
+            "expression cannot be relocated",
+            "only `label + value`, `label - label` and `label >> 8` can be used here",
+        ),
+
+        AssemblerError::FunctionNotRelocatable { span, function } => simple_report(
+            span,
+            format!("`{}()` of an address cannot be relocated", function),
+            "this address is only known after linking",
+        )
+        .with_help(
+            "only `hi()` is relocated in object files, load whole addresses with `ldi rd, label`",
+        ),
+
+        AssemblerError::UnresolvedImport { span, name } => simple_report(
+            span,
+            format!("imported symbol `{}` cannot be used in an image", name),
+            "assemble to an object file and link it instead",
+        ),
+
+        AssemblerError::ExpectedLabel { span, name } => simple_report(
+            span,
+            format!("`{}` is not a label", name),
+            "only labels can be exported",
+        ),
+
+        AssemblerError::InvalidLayoutFile { file_path, message } => {
+            Diagnostic::build(Severity::Error, &NO_SPAN).with_message(format!(
+                "invalid memory layout file {}: {}",
+                file_path.display(),
+                message
+            ))
+        }
+
+        AssemblerError::RegionOverflow { span, segment } => simple_report(
+            span,
+            format!("{} segment does not fit into its memory regions", segment),
+            "this is outside of all regions",
+        ),
+
+        AssemblerError::PageBoundary { span, region } => simple_report(
+            span,
+            format!("crossing a page boundary in region `{}`", region),
+            "this spans two pages",
+        ),
+
+        AssemblerError::Overlap {
+            span,
+            previous_span,
+        } => Diagnostic::build(Severity::Error, span)
+            .with_message("output overlaps with previously placed code or data")
+            .with_label(
+                Label::new(span)
+                    .with_message("placed over existing output")
+                    .with_color(Color::Red),
+            )
+            .with_label(
+                Label::new(previous_span)
+                    .with_message("previously placed here")
+                    .with_color(Color::Yellow),
+            ),
+
+        AssemblerError::UnknownSize { span, name } => simple_report(
+            span,
+            format!("size of `{}` is not known", name),
+            "only structs and labelled scopes have a size, scopes only after their end",
+        ),
+
+        AssemblerError::OddBinarySize {
+            span,
+            file_path,
+            size,
+        } => simple_report(
+            span,
+            format!(
+                "{} has an odd number of bytes ({}) after the offset, it can't be read as words",
+                file_path.display(),
+                size
+            ),
+            "give the length in words, or pack bytes with `.incbin`",
+        ),
+    };
+
+    let mut diagnostic = match error.warning_class() {
+        Some(class) if severity == Severity::Error => {
+            diagnostic.with_note(format!("`{}` warning, made an error by `-Werror`", class))
+        }
+        Some(class) => diagnostic.with_note(format!(
+            "`{}` warning, silence with `-Wno-{}` or `; allow({})`",
+            class, class, class
+        )),
+        None => diagnostic,
+    };
+    diagnostic.code = error.into();
+    diagnostic
+}
+
+const NO_SPAN: Span = Span {
+    file_id: None,
+    start: 0,
+    end: 0,
+};
+
+/// Report with a message and a single label
+fn simple_report<'a>(
+    span: &'a Span,
+    message: impl ToString,
+    label: impl ToString,
+) -> Diagnostic<'a> {
+    Diagnostic::build(Severity::Error, span)
+        .with_message(message)
+        .with_label(Label::new(span).with_message(label).with_color(Color::Red))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::assembler::AssembleOptions;
+
+    #[test]
+    fn json_record() {
+        let mut assembler = Assembler::default();
+        let mut errors = Vec::new();
+        let source = "x:\nouter: {\n    x:\n    .dw x\n}\n.dw x\n";
+        assembler.add_source("test.asm", source.to_owned(), &mut errors);
+        assembler.assemble(&AssembleOptions::default(), &mut errors);
+        assert_eq!(errors.len(), 1);
+
+        let json = diagnostic(&errors[0], Severity::Error).to_json(&mut Locations::new(&assembler));
+        assert!(!json.contains('\n'));
+        let record: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            record,
+            json!({
+                "severity": "error",
+                "code": "shadowed-name",
+                "message": "`x` shadows a definition from an outer scope",
+                "primary_span": {
+                    "file": "test.asm",
+                    "start": 16,
+                    "end": 18,
+                    "line": 3,
+                    "column": 5,
+                    "end_line": 3,
+                    "end_column": 7,
+                    "label": "defined here",
+                },
+                "secondary_spans": [{
+                    "file": "test.asm",
+                    "start": 0,
+                    "end": 2,
+                    "line": 1,
+                    "column": 1,
+                    "end_line": 1,
+                    "end_column": 3,
+                    "label": "hidden definition",
+                }],
+                "notes": ["`shadowed-name` warning, made an error by `-Werror`"],
+                "help": [],
+            })
+        );
+    }
+}
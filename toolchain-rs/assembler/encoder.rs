@@ -0,0 +1,938 @@
+use std::{cell::RefCell, slice, str::FromStr};
+
+use strum::IntoEnumIterator;
+use toolchain_core::{
+    image::Segment,
+    instruction::{ControlRegister, Instruction, Mnemonic, Reg},
+    object::RelocationKind,
+};
+use ux::{i4, u4};
+
+use crate::{
+    assembler::{Fragment, FragmentRelocation, QualifiedName},
+    eval::{Evaluator, Relocatable, RelocationBase, Value},
+    layout::{Statement, StatementContent},
+    parser::{BinOp, Expr},
+    types::{AssemblerError, Span, Spanned},
+};
+
+/// Instructions that the assembler accepts on top of the real ones
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum PseudoInstruction {
+    /// `nop`, encoded as `add r0, r0, r0`
+    Nop,
+    /// `ldi rd, value`, loads a 16 bit value to a register.
+    /// Values that fit into i8 are loaded by `and` + `addi`, everything else
+    /// gets an extra `ldui` for the upper byte.
+    /// `ldi rd, =value` loads the value from a literal pool by `ldpc` + `ldp`.
+    Ldi,
+}
+
+impl FromStr for PseudoInstruction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nop" => Ok(PseudoInstruction::Nop),
+            "ldi" => Ok(PseudoInstruction::Ldi),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Names of all pseudo instructions, for suggestions and completion
+pub const PSEUDO_INSTRUCTIONS: [&str; 2] = ["nop", "ldi"];
+
+const LDI_SHORT_SIZE: u16 = 2;
+const LDI_LONG_SIZE: u16 = 3;
+const LDI_POOL_SIZE: u16 = 2;
+
+/// `ldpc`, `add`, `ldp`, `jal` and a `nop` in the delay slot
+const JUMP_TABLE_DISPATCH_SIZE: u16 = 5;
+/// Short `ldi` of the case count, `sub`, `ldpc` + `ldp` of the default target and `bc`
+const JUMP_TABLE_CHECK_SIZE: u16 = 6;
+/// Position of the `ldpc` of the default target in the bounds check
+const JUMP_TABLE_CHECK_LDPC: u16 = 3;
+
+/// Size of a `.jumptable` with the given number of table entries
+pub fn jump_table_size(entries: usize, bounds_check: bool) -> u16 {
+    let check_size = if bounds_check {
+        JUMP_TABLE_CHECK_SIZE
+    } else {
+        0
+    };
+    check_size + JUMP_TABLE_DISPATCH_SIZE + entries as u16
+}
+
+/// Largest case value of a `.jumptable`.
+/// The `ldpc` of the bounds check must reach the default target after all cases,
+/// without the check only the address space limits the table.
+pub fn max_jump_table_case(bounds_check: bool) -> i64 {
+    if bounds_check {
+        let default_offset = jump_table_size(0, true) - JUMP_TABLE_CHECK_LDPC;
+        i64::from(i8::MAX) - i64::from(default_offset) - 1
+    } else {
+        i64::from(u16::MAX - JUMP_TABLE_DISPATCH_SIZE) - 1
+    }
+}
+
+/// Value of `ldi rd, =value` to be placed into a literal pool
+pub fn pool_literal<'ast>(name: &str, args: &'ast [Spanned<Expr>]) -> Option<&'ast Spanned<Expr>> {
+    match (PseudoInstruction::from_str(name), args) {
+        (Ok(PseudoInstruction::Ldi), [_, literal @ (Expr::PoolLiteral(_), _)]) => Some(literal),
+        _ => None,
+    }
+}
+
+/// Decide how many words an instruction takes.
+/// The evaluator only knows symbols defined before this instruction, values
+/// that can't be evaluated yet get the conservative (long) encoding.
+pub fn instruction_size(
+    name: &str,
+    args: &[Spanned<Expr>],
+    scope: &QualifiedName,
+    evaluator: &Evaluator,
+) -> u16 {
+    if pool_literal(name, args).is_some() {
+        return LDI_POOL_SIZE;
+    }
+    match PseudoInstruction::from_str(name) {
+        Ok(PseudoInstruction::Ldi) => {
+            match args.get(1).map(|arg| evaluator.eval_number(arg, scope)) {
+                Some(Ok(value)) if i8::try_from(value).is_ok() => LDI_SHORT_SIZE,
+                _ => LDI_LONG_SIZE,
+            }
+        }
+        _ => 1,
+    }
+}
+
+/// Encode a laid out statement into a fragment.
+/// On errors the fragment is filled with zeros, to keep the addresses consistent.
+pub fn encode(
+    statement: &Statement,
+    evaluator: &Evaluator,
+    errors: &mut Vec<AssemblerError>,
+) -> Fragment {
+    let mut relocations = Vec::new();
+    let (words, cycles) = match &statement.content {
+        StatementContent::Label => (Vec::new(), 0),
+        StatementContent::Instruction {
+            name: (name, name_span),
+            args,
+            size,
+        } => {
+            let operands = Operands {
+                evaluator,
+                scope: &statement.scope,
+                args,
+                span: &statement.span,
+                warnings: Default::default(),
+            };
+            let encoded = encode_instruction(
+                name,
+                name_span,
+                &operands,
+                statement.segment,
+                statement.address,
+                *size,
+            );
+            errors.extend(operands.warnings.take());
+            match encoded {
+                Ok((instructions, relocation)) => {
+                    relocations.extend(relocation);
+                    (
+                        instructions.iter().map(Instruction::encode).collect(),
+                        instructions.iter().map(Instruction::cycles).sum(),
+                    )
+                }
+                Err(e) => {
+                    errors.push(e);
+                    (vec![0; (*size).into()], 0)
+                }
+            }
+        }
+        StatementContent::Words { values } => {
+            let operands = Operands {
+                evaluator,
+                scope: &statement.scope,
+                args: values,
+                span: &statement.span,
+                warnings: Default::default(),
+            };
+            let words = (0..values.len())
+                .map(
+                    |i| match data_word(&operands, i, i as u16, &mut relocations) {
+                        Ok(word) => word,
+                        Err(e) => {
+                            errors.push(e);
+                            0
+                        }
+                    },
+                )
+                .collect();
+            (words, 0)
+        }
+        StatementContent::JumpTable {
+            index,
+            tmp,
+            targets,
+            bounds_check,
+        } => {
+            let encoded = encode_jump_table(
+                index,
+                tmp,
+                targets,
+                *bounds_check,
+                statement,
+                evaluator,
+                &mut relocations,
+            );
+            match encoded {
+                Ok((words, cycles)) => (words, cycles),
+                Err(e) => {
+                    errors.push(e);
+                    relocations.clear();
+                    let size = jump_table_size(targets.len(), *bounds_check);
+                    (vec![0; size.into()], 0)
+                }
+            }
+        }
+        StatementContent::Binary { data } => (data.clone(), 0),
+        StatementContent::Fill { value, size } => {
+            let fill = match value {
+                Some(value) => {
+                    let operands = Operands {
+                        evaluator,
+                        scope: &statement.scope,
+                        args: std::slice::from_ref(*value),
+                        span: &statement.span,
+                        warnings: Default::default(),
+                    };
+                    operands
+                        .number(0, i16::MIN.into(), u16::MAX.into())
+                        .unwrap_or_else(|e| {
+                            errors.push(e);
+                            0
+                        }) as u16
+                }
+                None => 0,
+            };
+            (vec![fill; (*size).into()], 0)
+        }
+    };
+
+    Fragment {
+        span: statement.span.clone(),
+        expansion: statement.expansion.clone(),
+        segment: statement.segment,
+        address: statement.address,
+        words,
+        cycles,
+        relocations,
+    }
+}
+
+/// Known instruction name closest to a misspelled one, if any is close enough
+fn similar_instruction(name: &str) -> Option<&'static str> {
+    let name = name.to_ascii_lowercase();
+    let max_distance = (name.len() / 2).clamp(1, 2);
+    Mnemonic::iter()
+        .map(<&'static str>::from)
+        .chain(PSEUDO_INSTRUCTIONS)
+        .map(|candidate| (edit_distance(&name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Optimal string alignment distance: insertions, deletions, substitutions
+/// and transpositions of adjacent characters each count as one edit
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// Data word that is either a number or an address to be relocated
+fn data_word(
+    operands: &Operands,
+    i: usize,
+    offset: u16,
+    relocations: &mut Vec<FragmentRelocation>,
+) -> Result<u16, AssemblerError> {
+    match operands.relocatable(i, i16::MIN.into(), u16::MAX.into())? {
+        Ok(value) => Ok(value as u16),
+        Err(relocatable) => {
+            relocations.push(FragmentRelocation {
+                offset,
+                kind: RelocationKind::Word,
+                base: relocatable.base,
+                addend: relocatable.addend,
+            });
+            Ok(0)
+        }
+    }
+}
+
+/// Encode the dispatch code and the table of a `.jumptable`.
+/// The table follows the code, so `ldpc` reaches it and no relocation is needed.
+fn encode_jump_table(
+    index_expr: &Spanned<Expr>,
+    tmp_expr: &Spanned<Expr>,
+    targets: &[&Spanned<Expr>],
+    bounds_check: bool,
+    statement: &Statement,
+    evaluator: &Evaluator,
+    relocations: &mut Vec<FragmentRelocation>,
+) -> Result<(Vec<u16>, u32), AssemblerError> {
+    let r0 = Reg::new(0).unwrap();
+    let index = evaluator.eval_register(index_expr, &statement.scope)?;
+    let tmp = evaluator.eval_register(tmp_expr, &statement.scope)?;
+    if tmp == r0 || tmp == index {
+        return Err(AssemblerError::JumpTableRegisters {
+            span: tmp_expr.1.clone(),
+        });
+    }
+
+    let table_offset = jump_table_size(0, bounds_check);
+    // Offset of the table (plus `entry`) from the `ldpc` at position `position`
+    let ldpc_offset = |position: u16, entry: u16| (table_offset + entry - position) as i8;
+
+    let mut instructions = Vec::new();
+    if bounds_check {
+        let cases = targets.len() as u16 - 1;
+        instructions.extend([
+            Instruction::And {
+                rd: tmp,
+                ra: r0,
+                rb: r0,
+            },
+            Instruction::Addi {
+                rd: tmp,
+                v: cases as i8,
+            },
+            // Carry is set if the index is out of range (unsigned)
+            Instruction::Sub {
+                rd: r0,
+                ra: index,
+                rb: tmp,
+            },
+            Instruction::Ldpc {
+                rd: tmp,
+                offset: ldpc_offset(JUMP_TABLE_CHECK_LDPC, cases),
+            },
+            Instruction::Ldp { rd: tmp, addr: tmp },
+            // The delay slot overwrites tmp only after the branch target was taken from it
+            Instruction::Bc { addr: tmp },
+        ]);
+    }
+    let dispatch_start = instructions.len() as u16;
+    instructions.extend([
+        Instruction::Ldpc {
+            rd: tmp,
+            offset: ldpc_offset(dispatch_start, 0),
+        },
+        Instruction::Add {
+            rd: tmp,
+            ra: tmp,
+            rb: index,
+        },
+        Instruction::Ldp { rd: tmp, addr: tmp },
+        Instruction::Jal { rd: r0, addr: tmp },
+        Instruction::Add {
+            rd: r0,
+            ra: r0,
+            rb: r0,
+        },
+    ]);
+
+    let mut words: Vec<u16> = instructions.iter().map(Instruction::encode).collect();
+    let cycles = instructions.iter().map(Instruction::cycles).sum();
+    for (i, target) in targets.iter().enumerate() {
+        let operands = Operands {
+            evaluator,
+            scope: &statement.scope,
+            args: slice::from_ref(*target),
+            span: &statement.span,
+            warnings: Default::default(),
+        };
+        words.push(data_word(
+            &operands,
+            0,
+            table_offset + i as u16,
+            relocations,
+        )?);
+    }
+    Ok((words, cycles))
+}
+
+fn encode_instruction(
+    name: &str,
+    name_span: &Span,
+    ops: &Operands,
+    segment: Segment,
+    address: u16,
+    size: u16,
+) -> Result<(Vec<Instruction>, Option<FragmentRelocation>), AssemblerError> {
+    if let Ok(pseudo) = PseudoInstruction::from_str(name) {
+        return encode_pseudo_instruction(pseudo, ops, address, size);
+    }
+
+    let Ok(mnemonic) = Mnemonic::from_str(name) else {
+        return Err(AssemblerError::UnknownInstruction {
+            span: name_span.clone(),
+            name: name.to_owned(),
+            suggestion: similar_instruction(name),
+        });
+    };
+
+    let rrr = |f: fn(Reg, Reg, Reg) -> Instruction| {
+        ops.check_count(3)?;
+        Ok(f(ops.reg(0)?, ops.reg(1)?, ops.reg(2)?))
+    };
+    let rr = |f: fn(Reg, Reg) -> Instruction| {
+        ops.check_count(2)?;
+        Ok(f(ops.reg(0)?, ops.reg(1)?))
+    };
+    let ri4 = |f: fn(Reg, i4) -> Instruction| {
+        ops.check_count(2)?;
+        Ok(f(ops.reg(0)?, ops.i4(1)?))
+    };
+
+    let mut relocation = None;
+    let instruction = match mnemonic {
+        Mnemonic::And => rrr(|rd, ra, rb| Instruction::And { rd, ra, rb })?,
+        Mnemonic::Or => rrr(|rd, ra, rb| Instruction::Or { rd, ra, rb })?,
+        Mnemonic::Xor => rrr(|rd, ra, rb| Instruction::Xor { rd, ra, rb })?,
+        Mnemonic::Add => rrr(|rd, ra, rb| Instruction::Add { rd, ra, rb })?,
+        Mnemonic::Sub => rrr(|rd, ra, rb| Instruction::Sub { rd, ra, rb })?,
+        Mnemonic::Pack => rrr(|rd, ra, rb| Instruction::Pack { rd, ra, rb })?,
+        Mnemonic::Bcmp => rrr(|rd, ra, rb| Instruction::Bcmp { rd, ra, rb })?,
+        Mnemonic::Cadd => rrr(|rd, ra, rb| Instruction::Cadd { rd, ra, rb })?,
+        Mnemonic::Ldui => {
+            ops.check_count(2)?;
+            let v = match ops.relocatable_high_byte(1, 0, u8::MAX.into())? {
+                Ok(v) => v as u8,
+                Err(relocatable) => {
+                    relocation = Some(FragmentRelocation {
+                        offset: 0,
+                        kind: RelocationKind::Ldui,
+                        base: relocatable.base,
+                        addend: relocatable.addend,
+                    });
+                    0
+                }
+            };
+            Instruction::Ldui { rd: ops.reg(0)?, v }
+        }
+        Mnemonic::Ldpc => {
+            ops.check_count(2)?;
+            // Offset is relative to the address of the ldpc instruction itself.
+            // Targets in the same segment don't need relocation, since the segment moves as a whole.
+            let target = match ops.relocatable(1, 0, u16::MAX.into())? {
+                Ok(target) => Some(target),
+                Err(relocatable) if relocatable.base == RelocationBase::Segment(segment) => {
+                    Some(relocatable.addend)
+                }
+                Err(relocatable) => {
+                    relocation = Some(FragmentRelocation {
+                        offset: 0,
+                        kind: RelocationKind::Ldpc,
+                        base: relocatable.base,
+                        addend: relocatable.addend,
+                    });
+                    None
+                }
+            };
+            let offset = match target {
+                Some(target) => ops.check_range(
+                    1,
+                    target - i64::from(address),
+                    i8::MIN.into(),
+                    i8::MAX.into(),
+                )? as i8,
+                None => 0,
+            };
+            Instruction::Ldpc {
+                rd: ops.reg(0)?,
+                offset,
+            }
+        }
+        Mnemonic::Addi => {
+            ops.check_count(2)?;
+            Instruction::Addi {
+                rd: ops.reg(0)?,
+                v: ops.signed_immediate(1, 8)? as i8,
+            }
+        }
+        Mnemonic::Ld => {
+            ops.check_count(2)?;
+            let (addr, offset) = ops.reg_offset(1)?;
+            Instruction::Ld {
+                rd: ops.reg(0)?,
+                addr,
+                offset,
+            }
+        }
+        Mnemonic::St => {
+            ops.check_count(2)?;
+            let (addr, offset) = ops.reg_offset(0)?;
+            Instruction::St {
+                val: ops.reg(1)?,
+                addr,
+                offset,
+            }
+        }
+        Mnemonic::Bc => {
+            ops.check_count(1)?;
+            Instruction::Bc { addr: ops.reg(0)? }
+        }
+        Mnemonic::Bnc => {
+            ops.check_count(1)?;
+            Instruction::Bnc { addr: ops.reg(0)? }
+        }
+        Mnemonic::Bz => rr(|cond, addr| Instruction::Bz { cond, addr })?,
+        Mnemonic::Bnz => rr(|cond, addr| Instruction::Bnz { cond, addr })?,
+        Mnemonic::Jal => rr(|rd, addr| Instruction::Jal { rd, addr })?,
+        Mnemonic::Addc => rr(|rd, rb| Instruction::Addc { rd, rb })?,
+        Mnemonic::Subc => rr(|rd, rb| Instruction::Subc { rd, rb })?,
+        Mnemonic::Shr => rr(|rd, rb| Instruction::Shr { rd, rb })?,
+        Mnemonic::Shrc => rr(|rd, rb| Instruction::Shrc { rd, rb })?,
+        Mnemonic::Shra => rr(|rd, rb| Instruction::Shra { rd, rb })?,
+        Mnemonic::Shr8 => rr(|rd, rb| Instruction::Shr8 { rd, rb })?,
+        Mnemonic::Ldp => rr(|rd, addr| Instruction::Ldp { rd, addr })?,
+        Mnemonic::Cst => rr(|rd, addr| Instruction::Cst { rd, addr })?,
+        Mnemonic::Andi => ri4(|rd, v| Instruction::Andi { rd, v })?,
+        Mnemonic::Ori => ri4(|rd, v| Instruction::Ori { rd, v })?,
+        Mnemonic::Xori => ri4(|rd, v| Instruction::Xori { rd, v })?,
+        Mnemonic::Ldcr => {
+            ops.check_count(2)?;
+            Instruction::Ldcr {
+                rd: ops.reg(0)?,
+                cr: ops.control_register(1)?,
+            }
+        }
+        Mnemonic::Stcr => {
+            ops.check_count(2)?;
+            Instruction::Stcr {
+                cr: ops.control_register(0)?,
+                val: ops.reg(1)?,
+            }
+        }
+        Mnemonic::Syscall => {
+            ops.check_count(1)?;
+            Instruction::Syscall {
+                val: u4::new(ops.number(0, 0, u8::from(u4::MAX).into())? as u8),
+            }
+        }
+        Mnemonic::Reti => {
+            ops.check_count(0)?;
+            Instruction::Reti
+        }
+        Mnemonic::Break => {
+            ops.check_count(0)?;
+            Instruction::Break
+        }
+    };
+    Ok((vec![instruction], relocation))
+}
+
+fn encode_pseudo_instruction(
+    pseudo: PseudoInstruction,
+    ops: &Operands,
+    address: u16,
+    size: u16,
+) -> Result<(Vec<Instruction>, Option<FragmentRelocation>), AssemblerError> {
+    let r0 = Reg::new(0).unwrap();
+    match pseudo {
+        PseudoInstruction::Nop => {
+            ops.check_count(0)?;
+            Ok((
+                vec![Instruction::Add {
+                    rd: r0,
+                    ra: r0,
+                    rb: r0,
+                }],
+                None,
+            ))
+        }
+        PseudoInstruction::Ldi => {
+            ops.check_count(2)?;
+            let rd = ops.reg(0)?;
+            if let (Expr::PoolLiteral(_), span) = &ops.args[1] {
+                // Pools are in the same segment, the offset doesn't need relocation
+                let entry = ops
+                    .evaluator
+                    .literal_address(&ops.args[1], ops.scope)
+                    .ok_or_else(|| AssemblerError::UnplacedLiteral { span: span.clone() })?;
+                let offset = i64::from(entry) - i64::from(address);
+                let offset =
+                    i8::try_from(offset).map_err(|_| AssemblerError::LiteralOutOfReach {
+                        span: span.clone(),
+                        offset,
+                    })?;
+                return Ok((
+                    vec![
+                        Instruction::Ldpc { rd, offset },
+                        Instruction::Ldp { rd, addr: rd },
+                    ],
+                    None,
+                ));
+            }
+            let mut relocation = None;
+            let value = if size == LDI_SHORT_SIZE {
+                ops.number(1, i8::MIN.into(), i8::MAX.into())?
+            } else {
+                match ops.relocatable(1, i16::MIN.into(), u16::MAX.into())? {
+                    Ok(value) => value,
+                    Err(relocatable) => {
+                        relocation = Some(FragmentRelocation {
+                            offset: 0,
+                            kind: RelocationKind::Ldi,
+                            base: relocatable.base,
+                            addend: relocatable.addend,
+                        });
+                        0
+                    }
+                }
+            };
+            let mut ret = vec![
+                Instruction::And { rd, ra: r0, rb: r0 },
+                Instruction::Addi {
+                    rd,
+                    v: value as u8 as i8,
+                },
+            ];
+            if size == LDI_LONG_SIZE {
+                ret.push(Instruction::Ldui {
+                    rd,
+                    v: (value >> 8) as u8,
+                })
+            }
+            Ok((ret, relocation))
+        }
+    }
+}
+
+/// Helper for evaluating instruction operands
+struct Operands<'a, 'b, 'ast> {
+    evaluator: &'a Evaluator<'b, 'ast>,
+    scope: &'a QualifiedName,
+    args: &'a [Spanned<Expr>],
+    span: &'a Span,
+    /// Warnings found while evaluating the operands
+    warnings: RefCell<Vec<AssemblerError>>,
+}
+
+impl<'a, 'b, 'ast> Operands<'a, 'b, 'ast> {
+    fn check_count(&self, expected: usize) -> Result<(), AssemblerError> {
+        if self.args.len() == expected {
+            Ok(())
+        } else {
+            Err(AssemblerError::OperandCount {
+                span: self.span.clone(),
+                expected,
+                found: self.args.len(),
+            })
+        }
+    }
+
+    fn reg(&self, i: usize) -> Result<Reg, AssemblerError> {
+        self.evaluator.eval_register(&self.args[i], self.scope)
+    }
+
+    fn control_register(&self, i: usize) -> Result<ControlRegister, AssemblerError> {
+        self.evaluator
+            .eval_control_register(&self.args[i], self.scope)
+    }
+
+    fn number(&self, i: usize, min: i64, max: i64) -> Result<i64, AssemblerError> {
+        let value = self.evaluator.eval_number(&self.args[i], self.scope)?;
+        self.check_range(i, value, min, max)
+    }
+
+    /// Operand that is either a number in the given range, or an address to be relocated.
+    fn relocatable(
+        &self,
+        i: usize,
+        min: i64,
+        max: i64,
+    ) -> Result<Result<i64, Relocatable>, AssemblerError> {
+        match self.evaluator.eval(&self.args[i], self.scope)? {
+            Value::Relocatable(relocatable) if !relocatable.high_byte => Ok(Err(relocatable)),
+            Value::Relocatable(_) => Err(AssemblerError::NotRelocatable {
+                span: self.args[i].1.clone(),
+            }),
+            _ => self.number(i, min, max).map(Ok),
+        }
+    }
+
+    /// Like `relocatable`, but the relocated value must be the upper byte of an address.
+    fn relocatable_high_byte(
+        &self,
+        i: usize,
+        min: i64,
+        max: i64,
+    ) -> Result<Result<i64, Relocatable>, AssemblerError> {
+        match self.evaluator.eval(&self.args[i], self.scope)? {
+            Value::Relocatable(relocatable) if relocatable.high_byte => Ok(Err(relocatable)),
+            Value::Relocatable(_) => Err(AssemblerError::NotRelocatable {
+                span: self.args[i].1.clone(),
+            }),
+            _ => self.number(i, min, max).map(Ok),
+        }
+    }
+
+    fn check_range(&self, i: usize, value: i64, min: i64, max: i64) -> Result<i64, AssemblerError> {
+        if (min..=max).contains(&value) {
+            Ok(value)
+        } else {
+            Err(AssemblerError::ValueOutOfRange {
+                span: self.args[i].1.clone(),
+                value,
+                min,
+                max,
+            })
+        }
+    }
+
+    fn i4(&self, i: usize) -> Result<i4, AssemblerError> {
+        Ok(i4::new(self.signed_immediate(i, 4)? as i8))
+    }
+
+    /// Signed immediate with the given number of bits. Values that only fit as an
+    /// unsigned bit pattern (`andi r1, 0xf`) are accepted with a warning, since
+    /// the sign extension likely isn't intended.
+    fn signed_immediate(&self, i: usize, bits: u32) -> Result<i64, AssemblerError> {
+        let max = (1 << (bits - 1)) - 1;
+        let value = self.number(i, -max - 1, (1 << bits) - 1)?;
+        if value <= max {
+            return Ok(value);
+        }
+        let truncated = value - (1 << bits);
+        self.warnings
+            .borrow_mut()
+            .push(AssemblerError::TruncatedImmediate {
+                span: self.args[i].1.clone(),
+                value,
+                truncated,
+            });
+        Ok(truncated)
+    }
+
+    /// Memory address operand in form `reg`, `reg + offset` or `reg - offset`,
+    /// where the offset may be a sum of several terms.
+    ///
+    /// If exactly one of the added terms is a struct field, the offset must stay within it.
+    fn reg_offset(&self, i: usize) -> Result<(Reg, i4), AssemblerError> {
+        let span = &self.args[i].1;
+        let mut terms = Vec::new();
+        flatten_sum(&self.args[i], false, &mut terms);
+        let (reg_expr, offset_terms) = terms.split_first().expect("at least one term");
+
+        let reg = self.evaluator.eval_register(reg_expr.0, self.scope)?;
+        let mut offset = 0i64;
+        let mut fields = Vec::new();
+        for (term, negated) in offset_terms {
+            let value = self.evaluator.eval_number(term, self.scope)?;
+            offset += if *negated { -value } else { value };
+            if !negated && let Some(field) = self.evaluator.struct_field(term, self.scope) {
+                fields.push((&term.1, field));
+            }
+        }
+
+        if let [(field_span, (field_offset, size))] = fields.as_slice() {
+            let inner = offset - i64::from(*field_offset);
+            if !(0..i64::from(*size)).contains(&inner) {
+                return Err(AssemblerError::FieldOverrun {
+                    span: span.clone(),
+                    field_span: (*field_span).clone(),
+                    offset: inner,
+                    size: *size,
+                });
+            }
+        }
+
+        if !(i64::from(i4::MIN)..=i64::from(i4::MAX)).contains(&offset) {
+            return Err(AssemblerError::ValueOutOfRange {
+                span: span.clone(),
+                value: offset,
+                min: i4::MIN.into(),
+                max: i4::MAX.into(),
+            });
+        }
+        Ok((reg, i4::new(offset as i8)))
+    }
+}
+
+/// Split a tree of additions and subtractions into its terms, left to right
+fn flatten_sum<'e>(
+    expr: &'e Spanned<Expr>,
+    negated: bool,
+    terms: &mut Vec<(&'e Spanned<Expr>, bool)>,
+) {
+    match &expr.0 {
+        Expr::BinaryOp {
+            op: op @ (BinOp::Add | BinOp::Sub),
+            lhs,
+            rhs,
+        } => {
+            flatten_sum(lhs, negated, terms);
+            flatten_sum(rhs, negated != (*op == BinOp::Sub), terms);
+        }
+        _ => terms.push((expr, negated)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use super::*;
+    use crate::testing::{assemble_with, errors, program};
+
+    fn r(n: u16) -> Reg {
+        Reg::new(n).unwrap()
+    }
+
+    fn dispatch(offset: i8) -> Vec<u16> {
+        [
+            Instruction::Ldpc { rd: r(2), offset },
+            Instruction::Add {
+                rd: r(2),
+                ra: r(2),
+                rb: r(1),
+            },
+            Instruction::Ldp {
+                rd: r(2),
+                addr: r(2),
+            },
+            Instruction::Jal {
+                rd: r(0),
+                addr: r(2),
+            },
+            Instruction::Add {
+                rd: r(0),
+                ra: r(0),
+                rb: r(0),
+            },
+        ]
+        .iter()
+        .map(Instruction::encode)
+        .collect()
+    }
+
+    #[test]
+    fn struct_field_offset() {
+        let source = |offset| {
+            format!(
+                ".struct Point {{ x: 1, y: 2 }}\nld r1, r2 + Point.y + {}\n",
+                offset
+            )
+        };
+        let words = program(&source(1));
+        let expected = Instruction::Ld {
+            rd: r(1),
+            addr: r(2),
+            offset: i4::new(2),
+        };
+        assert_eq!(words, [expected.encode()]);
+
+        let (_, errors) = assemble_with(&source(2), &Default::default());
+        assert!(
+            matches!(errors.as_slice(), [AssemblerError::FieldOverrun { .. }]),
+            "{:?}",
+            errors
+        );
+    }
+
+    #[test]
+    fn dense_jump_table() {
+        let words = program(".jumptable r1, r2 { 0: a, 1: b }\na: nop\nb: nop\n");
+        assert_eq!(words[..5], dispatch(5));
+        assert_eq!(words[5..7], [7, 8]);
+    }
+
+    #[test]
+    fn sparse_jump_table_with_default() {
+        let words =
+            program(".jumptable r1, r2 { 0: a, 2: b, default: c }\na: nop\nb: nop\nc: nop\n");
+        let check: Vec<u16> = [
+            Instruction::And {
+                rd: r(2),
+                ra: r(0),
+                rb: r(0),
+            },
+            Instruction::Addi { rd: r(2), v: 3 },
+            Instruction::Sub {
+                rd: r(0),
+                ra: r(1),
+                rb: r(2),
+            },
+            Instruction::Ldpc {
+                rd: r(2),
+                offset: 11,
+            },
+            Instruction::Ldp {
+                rd: r(2),
+                addr: r(2),
+            },
+            Instruction::Bc { addr: r(2) },
+        ]
+        .iter()
+        .map(Instruction::encode)
+        .collect();
+        assert_eq!(words[..6], check);
+        assert_eq!(words[6..11], dispatch(5));
+        // Missing case 1 and the out of range entry go to the default
+        assert_eq!(words[11..15], [15, 17, 16, 17]);
+    }
+
+    #[test]
+    fn jump_table_case_out_of_range() {
+        let errors = errors(".jumptable r1, r2 { -1: a, default: a }\na: nop\n");
+        assert!(
+            matches!(errors.as_slice(), [AssemblerError::ValueOutOfRange { .. }]),
+            "{:?}",
+            errors
+        );
+    }
+
+    #[test]
+    fn jump_table_limit() {
+        let table = |case| format!(".jumptable r1, r2 {{ {}: a, default: a }}\na: nop\n", case);
+        let max_case = max_jump_table_case(true);
+        assert_eq!(max_case, 118);
+        assert!(errors(&table(max_case)).is_empty());
+        let errors = errors(&table(max_case + 1));
+        assert!(
+            matches!(errors.as_slice(), [AssemblerError::ValueOutOfRange { .. }]),
+            "{:?}",
+            errors
+        );
+
+        // Without a bounds check, the table can be longer than `ldpc` reach
+        let cases = (0..200).map(|case| format!("{}: a", case)).join(", ");
+        let words = program(&format!(".jumptable r1, r2 {{ {} }}\na: nop\n", cases));
+        assert_eq!(words.len(), 206);
+    }
+}
@@ -0,0 +1,649 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+
+use itertools::Itertools;
+use toolchain_core::{
+    image::Segment,
+    instruction::{ControlRegister, Reg},
+};
+
+use crate::{
+    assembler::{AssembleOptions, AssemblerTable, Expansion, QualifiedName, Symbol, SymbolValue},
+    layout::Layout,
+    parser::{BinOp, Expr, Function, UnOp},
+    types::{AssemblerError, Span, Spanned},
+};
+
+/// Maximum nesting of constant definitions and macro arguments, used to detect cycles.
+const MAX_DEPTH: usize = 64;
+
+/// Number of address bits within a MMU page
+const PAGE_BITS: u32 = 10;
+
+/// Result of evaluating an expression
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Number(i64),
+    Register(Reg),
+    ControlRegister(ControlRegister),
+    /// Address that is only known after linking
+    Relocatable(Relocatable),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Relocatable {
+    pub base: RelocationBase,
+    pub addend: i64,
+    /// Only the upper byte of the address is used (`address >> 8`)
+    pub high_byte: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RelocationBase {
+    /// Start of the segment in the current object file
+    Segment(Segment),
+    /// Symbol imported from another object file
+    Import(String),
+}
+
+/// Evaluates expressions using symbols defined so far.
+#[derive(Clone, Copy, Debug)]
+pub struct Evaluator<'a, 'ast> {
+    symbols: &'a AssemblerTable<Symbol<'ast>>,
+    expansions: &'a HashMap<QualifiedName, Expansion<'ast>>,
+    local_labels: &'a HashMap<(QualifiedName, Span), QualifiedName>,
+    literals: &'a HashMap<(QualifiedName, Span), u16>,
+    references: &'a RefCell<HashSet<(Span, QualifiedName)>>,
+    /// Labels evaluate to relocatable values instead of numbers
+    relocatable: bool,
+    abi_names: bool,
+}
+
+impl<'a, 'ast> Evaluator<'a, 'ast> {
+    pub fn new(layout: &'a Layout<'ast>, options: &AssembleOptions) -> Self {
+        Evaluator {
+            symbols: &layout.symbols,
+            expansions: &layout.expansions,
+            local_labels: &layout.local_labels,
+            literals: &layout.literals,
+            references: &layout.references,
+            relocatable: options.relocatable,
+            abi_names: options.abi_names,
+        }
+    }
+
+    pub fn eval(
+        &self,
+        expr: &Spanned<Expr>,
+        scope: &QualifiedName,
+    ) -> Result<Value, AssemblerError> {
+        self.eval_depth(expr, scope, 0)
+    }
+
+    /// Address of the literal pool entry of an `=expr` operand, if it was placed
+    pub fn literal_address(&self, literal: &Spanned<Expr>, scope: &QualifiedName) -> Option<u16> {
+        self.literals
+            .get(&(scope.clone(), literal.1.clone()))
+            .copied()
+    }
+
+    pub fn eval_number(
+        &self,
+        expr: &Spanned<Expr>,
+        scope: &QualifiedName,
+    ) -> Result<i64, AssemblerError> {
+        self.eval_number_depth(expr, scope, 0)
+    }
+
+    pub fn eval_register(
+        &self,
+        expr: &Spanned<Expr>,
+        scope: &QualifiedName,
+    ) -> Result<Reg, AssemblerError> {
+        match self.eval(expr, scope)? {
+            Value::Register(reg) => Ok(reg),
+            _ => Err(AssemblerError::ExpectedRegister {
+                span: expr.1.clone(),
+            }),
+        }
+    }
+
+    pub fn eval_control_register(
+        &self,
+        expr: &Spanned<Expr>,
+        scope: &QualifiedName,
+    ) -> Result<ControlRegister, AssemblerError> {
+        match self.eval(expr, scope)? {
+            Value::ControlRegister(cr) => Ok(cr),
+            _ => Err(AssemblerError::ExpectedControlRegister {
+                span: expr.1.clone(),
+            }),
+        }
+    }
+
+    fn eval_number_depth(
+        &self,
+        expr: &Spanned<Expr>,
+        scope: &QualifiedName,
+        depth: usize,
+    ) -> Result<i64, AssemblerError> {
+        match self.eval_depth(expr, scope, depth)? {
+            Value::Number(n) => Ok(n),
+            Value::Relocatable(_) => Err(AssemblerError::NotRelocatable {
+                span: expr.1.clone(),
+            }),
+            _ => Err(AssemblerError::ExpectedValue {
+                span: expr.1.clone(),
+            }),
+        }
+    }
+
+    fn eval_depth(
+        &self,
+        expr: &Spanned<Expr>,
+        scope: &QualifiedName,
+        depth: usize,
+    ) -> Result<Value, AssemblerError> {
+        let (expr, span) = expr;
+        if depth > MAX_DEPTH {
+            return Err(AssemblerError::RecursiveDefinition { span: span.clone() });
+        }
+
+        match expr {
+            Expr::Number(n) => Ok(Value::Number(*n)),
+            Expr::QualifiedName(names) => self.resolve(names, span, scope, depth),
+            Expr::LocalLabel { number, forward } => {
+                match self
+                    .local_labels
+                    .get(&(scope.clone(), span.clone()))
+                    .and_then(|name| self.symbols.get(name))
+                {
+                    Some(symbol) => self.symbol_value(symbol, span, depth),
+                    None => Err(AssemblerError::UndefinedName {
+                        span: span.clone(),
+                        name: format!("{}{}", number, if *forward { 'f' } else { 'b' }),
+                    }),
+                }
+            }
+            Expr::PoolLiteral(_) => {
+                Err(AssemblerError::MisplacedPoolLiteral { span: span.clone() })
+            }
+            Expr::Defined(names) => Ok(Value::Number(self.is_defined(names, scope) as i64)),
+            Expr::SizeOf(names) => self.size_of(names, span, scope),
+            Expr::Len(name) => Ok(Value::Number(
+                self.variadic_args(name, scope)?.0.len() as i64
+            )),
+            Expr::Index { name, index } => {
+                let (args, caller_scope) = self.variadic_args(name, scope)?;
+                let i = self.eval_number_depth(index, scope, depth + 1)?;
+                let arg = usize::try_from(i)
+                    .ok()
+                    .and_then(|i| args.get(i))
+                    .ok_or_else(|| AssemblerError::ValueOutOfRange {
+                        span: index.1.clone(),
+                        value: i,
+                        min: 0,
+                        max: args.len() as i64 - 1,
+                    })?;
+                self.eval_depth(arg, caller_scope, depth + 1)
+            }
+            Expr::Function { function, arg } => match self.eval_depth(arg, scope, depth)? {
+                Value::Number(v) => Ok(Value::Number(apply_function(*function, v))),
+                v @ Value::Relocatable(_) if *function == Function::Hi => {
+                    relocatable_op(BinOp::Shr, v, Value::Number(8), span)
+                }
+                Value::Relocatable(_) => Err(AssemblerError::FunctionNotRelocatable {
+                    span: span.clone(),
+                    function: function.name(),
+                }),
+                _ => Err(AssemblerError::ExpectedValue {
+                    span: arg.1.clone(),
+                }),
+            },
+            Expr::UnaryOp { op, expr } => {
+                let v = self.eval_number_depth(expr, scope, depth)?;
+                Ok(Value::Number(match op {
+                    UnOp::Neg => v.wrapping_neg(),
+                    UnOp::Not => (v == 0) as i64,
+                    UnOp::BitNot => !v,
+                }))
+            }
+            Expr::BinaryOp { op, lhs, rhs } => {
+                let l = self.eval_depth(lhs, scope, depth)?;
+                let r = self.eval_depth(rhs, scope, depth)?;
+                match (l, r) {
+                    (Value::Number(l), Value::Number(r)) => {
+                        Ok(Value::Number(binary_op(*op, l, r, &rhs.1)?))
+                    }
+                    (
+                        l @ (Value::Number(_) | Value::Relocatable(_)),
+                        r @ (Value::Number(_) | Value::Relocatable(_)),
+                    ) => relocatable_op(*op, l, r, span),
+                    (Value::Number(_) | Value::Relocatable(_), _) => {
+                        Err(AssemblerError::ExpectedValue {
+                            span: rhs.1.clone(),
+                        })
+                    }
+                    _ => Err(AssemblerError::ExpectedValue {
+                        span: lhs.1.clone(),
+                    }),
+                }
+            }
+        }
+    }
+
+    /// Resolve a (possibly qualified) name as seen from `scope`.
+    /// Macro parameters and symbols in inner scopes shadow the outer ones,
+    /// register names are only used if no symbol matches.
+    fn resolve(
+        &self,
+        names: &[Spanned<String>],
+        span: &Span,
+        scope: &QualifiedName,
+        depth: usize,
+    ) -> Result<Value, AssemblerError> {
+        let single_name = match names {
+            [(name, _)] => Some(name.as_str()),
+            _ => None,
+        };
+
+        for scope in scope.scopes() {
+            if let Some(name) = single_name
+                && let Some(expansion) = self.expansions.get(&scope)
+            {
+                if let Some(arg) = expansion.args.get(name) {
+                    return self.eval_depth(arg, &expansion.caller_scope, depth + 1);
+                }
+                if let Some(default) = expansion.defaults.get(name) {
+                    return self.eval_depth(default, &scope, depth + 1);
+                }
+                if expansion
+                    .variadic
+                    .is_some_and(|(variadic, _)| variadic == name)
+                {
+                    return Err(AssemblerError::VariadicParameter {
+                        span: span.clone(),
+                        name: name.to_owned(),
+                    });
+                }
+            }
+
+            let candidate = scope.with_names(names.iter().map(|(name, _)| name.as_str()));
+            if let Some(symbol) = self.symbols.get(&candidate) {
+                self.record_references(names, &candidate);
+                return self.symbol_value(symbol, span, depth);
+            }
+        }
+
+        if let Some(name) = single_name {
+            if let Ok(reg) = Reg::from_str(name) {
+                return Ok(Value::Register(reg));
+            }
+            if self.abi_names
+                && let Some(reg) = Reg::from_abi_name(name)
+            {
+                return Ok(Value::Register(reg));
+            }
+            if let Ok(cr) = ControlRegister::from_str(name) {
+                return Ok(Value::ControlRegister(cr));
+            }
+        }
+
+        Err(AssemblerError::UndefinedName {
+            span: span.clone(),
+            name: names.iter().map(|(name, _)| name).join("."),
+        })
+    }
+
+    /// Value of a symbol, `span` is the place where it's referenced
+    fn symbol_value(
+        &self,
+        symbol: &Symbol<'ast>,
+        span: &Span,
+        depth: usize,
+    ) -> Result<Value, AssemblerError> {
+        match &symbol.value {
+            SymbolValue::Label {
+                segment, address, ..
+            } if self.relocatable => Ok(Value::Relocatable(Relocatable {
+                base: RelocationBase::Segment(*segment),
+                addend: (*address).into(),
+                high_byte: false,
+            })),
+            SymbolValue::Label { address, .. } => Ok(Value::Number((*address).into())),
+            SymbolValue::Constant { value, scope } => {
+                match self.eval_depth(value, scope, depth + 1)? {
+                    v @ (Value::Number(_) | Value::Relocatable(_)) => Ok(v),
+                    _ => Err(AssemblerError::ExpectedValue {
+                        span: value.1.clone(),
+                    }),
+                }
+            }
+            SymbolValue::Register { value, scope } => {
+                match self.eval_depth(value, scope, depth + 1)? {
+                    v @ Value::Register(_) => Ok(v),
+                    _ => Err(AssemblerError::ExpectedRegister {
+                        span: value.1.clone(),
+                    }),
+                }
+            }
+            SymbolValue::Import { name } if self.relocatable => {
+                Ok(Value::Relocatable(Relocatable {
+                    base: RelocationBase::Import(name.to_string()),
+                    addend: 0,
+                    high_byte: false,
+                }))
+            }
+            SymbolValue::Import { name } => Err(AssemblerError::UnresolvedImport {
+                span: span.clone(),
+                name: name.to_string(),
+            }),
+            SymbolValue::Counter { value } => Ok(Value::Number(*value)),
+            SymbolValue::Struct { size } => Ok(Value::Number((*size).into())),
+            SymbolValue::Field { offset, .. } => Ok(Value::Number((*offset).into())),
+        }
+    }
+
+    /// Arguments of a variadic macro parameter and the scope they are evaluated in
+    fn variadic_args(
+        &self,
+        (name, span): &Spanned<String>,
+        scope: &QualifiedName,
+    ) -> Result<(&'a [Spanned<Expr>], &'a QualifiedName), AssemblerError> {
+        scope
+            .scopes()
+            .find_map(|scope| {
+                let expansion = self.expansions.get(&scope)?;
+                match expansion.variadic {
+                    Some((variadic, args)) if variadic == name => {
+                        Some((args, &expansion.caller_scope))
+                    }
+                    _ => None,
+                }
+            })
+            .ok_or_else(|| AssemblerError::UndefinedName {
+                span: span.clone(),
+                name: name.clone(),
+            })
+    }
+
+    /// Check whether an expression names a label (local, imported, or passed through
+    /// macro arguments). The expression must evaluate successfully.
+    pub fn is_label(
+        &self,
+        expr: &Spanned<Expr>,
+        scope: &QualifiedName,
+    ) -> Result<bool, AssemblerError> {
+        self.eval(expr, scope)?;
+        Ok(matches!(
+            self.named_symbol(expr, scope, 0),
+            Some(Symbol {
+                value: SymbolValue::Label { .. } | SymbolValue::Import { .. },
+                ..
+            })
+        ))
+    }
+
+    /// Offset and size of the struct field that an expression names, if it does
+    pub fn struct_field(&self, expr: &Spanned<Expr>, scope: &QualifiedName) -> Option<(u16, u16)> {
+        match self.named_symbol(expr, scope, 0)?.value {
+            SymbolValue::Field { offset, size } => Some((offset, size)),
+            _ => None,
+        }
+    }
+
+    /// Symbol that an expression consisting of just a name refers to, following
+    /// macro arguments
+    fn named_symbol(
+        &self,
+        expr: &Spanned<Expr>,
+        scope: &QualifiedName,
+        depth: usize,
+    ) -> Option<&'a Symbol<'ast>> {
+        let names = match &expr.0 {
+            Expr::LocalLabel { .. } => {
+                let name = self.local_labels.get(&(scope.clone(), expr.1.clone()))?;
+                return self.symbols.get(name);
+            }
+            Expr::QualifiedName(names) if depth <= MAX_DEPTH => names,
+            _ => return None,
+        };
+
+        for scope in scope.scopes() {
+            if let [(name, _)] = names.as_slice()
+                && let Some(expansion) = self.expansions.get(&scope)
+            {
+                if let Some(arg) = expansion.args.get(name.as_str()) {
+                    return self.named_symbol(arg, &expansion.caller_scope, depth + 1);
+                }
+                if let Some(default) = expansion.defaults.get(name.as_str()) {
+                    return self.named_symbol(default, &scope, depth + 1);
+                }
+            }
+
+            let candidate = scope.with_names(names.iter().map(|(name, _)| name.as_str()));
+            if let Some(symbol) = self.symbols.get(&candidate) {
+                return Some(symbol);
+            }
+        }
+        None
+    }
+
+    /// Size of the labelled scope, struct or struct field that a name refers to
+    fn size_of(
+        &self,
+        names: &[Spanned<String>],
+        span: &Span,
+        scope: &QualifiedName,
+    ) -> Result<Value, AssemblerError> {
+        let found = self
+            .symbols
+            .lookup(names.iter().map(|(name, _)| name.as_str()), scope);
+        if let Some((name, _)) = &found {
+            self.record_references(names, name);
+        }
+        let names = || names.iter().map(|(name, _)| name.as_str());
+        match found {
+            Some((
+                _,
+                Symbol {
+                    value:
+                        SymbolValue::Label {
+                            size: Some(size), ..
+                        }
+                        | SymbolValue::Struct { size }
+                        | SymbolValue::Field { size, .. },
+                    ..
+                },
+            )) => Ok(Value::Number((*size).into())),
+            Some(_) => Err(AssemblerError::UnknownSize {
+                span: span.clone(),
+                name: names().join("."),
+            }),
+            None => Err(AssemblerError::UndefinedName {
+                span: span.clone(),
+                name: names().join("."),
+            }),
+        }
+    }
+
+    /// Record each part of a name that was found as `found`, `a` in `a.b` also refers
+    /// to the label naming the scope
+    fn record_references(&self, names: &[Spanned<String>], found: &QualifiedName) {
+        let mut references = self.references.borrow_mut();
+        let mut prefix = found.clone();
+        for (_, span) in names.iter().rev() {
+            references.insert((span.clone(), prefix.clone()));
+            prefix.pop();
+        }
+    }
+
+    /// Check whether a name refers to a symbol or macro argument, using the same lookup
+    /// as `resolve`. Register names don't count.
+    fn is_defined(&self, names: &[Spanned<String>], scope: &QualifiedName) -> bool {
+        scope.scopes().any(|scope| {
+            let is_argument = match names {
+                [(name, _)] => self.expansions.get(&scope).is_some_and(|expansion| {
+                    expansion.args.contains_key(name.as_str())
+                        || expansion.defaults.contains_key(name.as_str())
+                        || expansion
+                            .variadic
+                            .is_some_and(|(variadic, _)| variadic == name)
+                }),
+                _ => false,
+            };
+            is_argument
+                || self
+                    .symbols
+                    .get(&scope.with_names(names.iter().map(|(name, _)| name.as_str())))
+                    .is_some()
+        })
+    }
+}
+
+/// Arithmetic on relocatable values, limited to what can be expressed by a relocation
+fn relocatable_op(op: BinOp, l: Value, r: Value, span: &Span) -> Result<Value, AssemblerError> {
+    let relocatable = |base, addend, high_byte| {
+        Ok(Value::Relocatable(Relocatable {
+            base,
+            addend,
+            high_byte,
+        }))
+    };
+
+    match (op, l, r) {
+        (BinOp::Add, Value::Relocatable(l), Value::Number(r))
+        | (BinOp::Add, Value::Number(r), Value::Relocatable(l))
+            if !l.high_byte =>
+        {
+            relocatable(l.base, l.addend.wrapping_add(r), false)
+        }
+        (BinOp::Sub, Value::Relocatable(l), Value::Number(r)) if !l.high_byte => {
+            relocatable(l.base, l.addend.wrapping_sub(r), false)
+        }
+        (BinOp::Sub, Value::Relocatable(l), Value::Relocatable(r))
+            if l.base == r.base && !l.high_byte && !r.high_byte =>
+        {
+            Ok(Value::Number(l.addend.wrapping_sub(r.addend)))
+        }
+        (BinOp::Shr, Value::Relocatable(l), Value::Number(8)) if !l.high_byte => {
+            relocatable(l.base, l.addend, true)
+        }
+        _ => Err(AssemblerError::NotRelocatable { span: span.clone() }),
+    }
+}
+
+fn apply_function(function: Function, v: i64) -> i64 {
+    match function {
+        Function::Hi => (v >> 8) & 0xff,
+        Function::Lo => v & 0xff,
+        Function::Page => (v >> PAGE_BITS) & 0x3f,
+        Function::Offset => v & ((1 << PAGE_BITS) - 1),
+        Function::Bptr => v.wrapping_mul(2),
+    }
+}
+
+fn binary_op(op: BinOp, l: i64, r: i64, rhs_span: &Span) -> Result<i64, AssemblerError> {
+    let shift_amount = || {
+        u32::try_from(r)
+            .ok()
+            .filter(|r| *r < i64::BITS)
+            .ok_or_else(|| AssemblerError::ValueOutOfRange {
+                span: rhs_span.clone(),
+                value: r,
+                min: 0,
+                max: (i64::BITS - 1).into(),
+            })
+    };
+    let divisor = || {
+        if r == 0 {
+            Err(AssemblerError::DivisionByZero {
+                span: rhs_span.clone(),
+            })
+        } else {
+            Ok(r)
+        }
+    };
+
+    Ok(match op {
+        BinOp::Add => l.wrapping_add(r),
+        BinOp::Sub => l.wrapping_sub(r),
+        BinOp::Mul => l.wrapping_mul(r),
+        BinOp::Div => l.wrapping_div(divisor()?),
+        BinOp::Mod => l.wrapping_rem(divisor()?),
+        BinOp::Shl => l << shift_amount()?,
+        BinOp::Shr => l >> shift_amount()?,
+        BinOp::BitAnd => l & r,
+        BinOp::BitOr => l | r,
+        BinOp::BitXor => l ^ r,
+        BinOp::And => (l != 0 && r != 0) as i64,
+        BinOp::Or => (l != 0 || r != 0) as i64,
+        BinOp::Eq => (l == r) as i64,
+        BinOp::Neq => (l != r) as i64,
+        BinOp::Lt => (l < r) as i64,
+        BinOp::Gt => (l > r) as i64,
+        BinOp::Le => (l <= r) as i64,
+        BinOp::Ge => (l >= r) as i64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use crate::{
+        assembler::AssembleOptions,
+        testing::{assemble_with, program},
+        types::AssemblerError,
+    };
+
+    #[test_case("hi(0x1234)" => 0x12 ; "hi")]
+    #[test_case("lo(0x1234)" => 0x34 ; "lo")]
+    #[test_case("page(0x1234)" => 0x04 ; "page")]
+    #[test_case("offset(0x1234)" => 0x234 ; "offset")]
+    #[test_case("bptr(0x1234)" => 0x2468 ; "bptr")]
+    #[test_case("hi(start) + lo(start)" => 0x12 + 0x34 ; "label")]
+    fn function(expr: &str) -> u16 {
+        let words = program(&format!(".org 0x1234\nstart:\n.dw {}\n", expr));
+        words[0]
+    }
+
+    fn relocatable() -> AssembleOptions {
+        AssembleOptions {
+            relocatable: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn hi_is_relocated() {
+        let (assembled, errors) = assemble_with("start:\nldui r1, hi(start)\n", &relocatable());
+        assert!(errors.is_empty());
+        let relocations: Vec<_> = assembled
+            .fragments
+            .iter()
+            .flat_map(|fragment| fragment.relocations.iter())
+            .collect();
+        assert_eq!(relocations.len(), 1);
+    }
+
+    #[test_case("lo" ; "lo")]
+    #[test_case("page" ; "page")]
+    #[test_case("offset" ; "offset")]
+    #[test_case("bptr" ; "bptr")]
+    fn function_not_relocatable(function: &str) {
+        let source = format!("start:\n.dw {}(start)\n", function);
+        let (_, errors) = assemble_with(&source, &relocatable());
+        assert!(
+            matches!(
+                errors.as_slice(),
+                [AssemblerError::FunctionNotRelocatable { function: f, .. }] if *f == function
+            ),
+            "{:?}",
+            errors
+        );
+    }
+}
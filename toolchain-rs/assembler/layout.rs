@@ -0,0 +1,1351 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fs, iter,
+    ops::Range,
+    path::Path,
+    slice,
+    str::FromStr,
+};
+
+use toolchain_core::{image::Segment, instruction::Mnemonic, memory_layout::SEGMENT_SIZE};
+
+use crate::{
+    assembler::{
+        AssembleOptions, Assembler, AssemblerTable, Expansion, IncludedFile, MacroDef,
+        QualifiedName, Symbol, SymbolValue,
+    },
+    encoder,
+    eval::{Evaluator, RelocationBase, Value},
+    parser::{Ast, Contract, Expr, Item, MacroParam, ParamKind},
+    types::{ArgumentCountError, AssemblerError, Span, Spanned},
+};
+
+/// Maximum depth of nested macro expansions
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// Maximum number of iterations of a single `.rept` or `.for` block
+const MAX_ITERATIONS: i64 = 1 << 16;
+
+/// Item of the program after macro expansion, with address assigned
+#[derive(Clone, Debug)]
+pub struct Statement<'ast> {
+    pub span: Span,
+    /// Scope in which the statement's expressions are evaluated
+    pub scope: QualifiedName,
+    /// Spans of macro calls that produced this statement, outermost first
+    pub expansion: Vec<Span>,
+    pub segment: Segment,
+    pub address: u16,
+    pub content: StatementContent<'ast>,
+}
+
+#[derive(Clone, Debug)]
+pub enum StatementContent<'ast> {
+    /// Label definition, doesn't take any space
+    Label,
+    Instruction {
+        name: &'ast Spanned<String>,
+        args: &'ast [Spanned<Expr>],
+        /// Size in words, decided during layout
+        size: u16,
+    },
+    /// Data words from `.dw`
+    Words { values: &'ast [Spanned<Expr>] },
+    /// Contents of a file from `.incbin` or `.incbinw`, read during layout
+    Binary { data: Vec<u16> },
+    /// Dispatch code of `.jumptable` followed by the table of targets for each index.
+    /// With a bounds check, the last target is the default for out of range indices.
+    JumpTable {
+        index: &'ast Spanned<Expr>,
+        tmp: &'ast Spanned<Expr>,
+        targets: Vec<&'ast Spanned<Expr>>,
+        bounds_check: bool,
+    },
+    /// Padding from `.align` or `.space`, `size` copies of the fill value (zero if missing)
+    Fill {
+        value: Option<&'ast Spanned<Expr>>,
+        size: u16,
+    },
+}
+
+/// Macro argument passed to a parameter with a kind, checked once all symbols are known
+#[derive(Clone, Debug)]
+pub struct ArgumentCheck<'ast> {
+    /// Span of the macro call
+    pub call_span: Span,
+    pub param: &'ast MacroParam,
+    pub arg: &'ast Spanned<Expr>,
+    /// Scope of the caller, where the argument is evaluated
+    pub scope: QualifiedName,
+}
+
+/// Statements of a macro expansion or a routine, to be checked against its `.contract`
+#[derive(Clone, Debug)]
+pub struct ContractCheck<'ast> {
+    pub contract: &'ast Contract,
+    /// Span of the `.contract` item
+    pub span: Span,
+    /// Scope in which the registers of the contract are evaluated
+    pub scope: QualifiedName,
+    /// Indices into `Layout::statements`
+    pub statements: Range<usize>,
+}
+
+/// Routine with a `.contract` that is being laid out
+struct OpenRoutine<'ast> {
+    check: ContractCheck<'ast>,
+    /// Index of the label that starts the routine
+    first_item: usize,
+    /// Index of the last item of the routine, `None` if it continues up to the next
+    /// label, `.contract` or to the end of the enclosing block
+    last_item: Option<usize>,
+}
+
+/// `.assert` to be evaluated once all symbols are known
+#[derive(Clone, Debug)]
+pub struct Assertion<'ast> {
+    pub span: Span,
+    pub scope: QualifiedName,
+    pub condition: &'ast Spanned<Expr>,
+    pub message: Option<&'ast str>,
+}
+
+/// Label name listed in `.export`, resolved after layout
+#[derive(Clone, Debug)]
+pub struct ExportRequest<'ast> {
+    pub span: Span,
+    pub scope: QualifiedName,
+    pub name: &'ast str,
+}
+
+#[derive(Debug, Default)]
+pub struct Layout<'ast> {
+    pub symbols: AssemblerTable<Symbol<'ast>>,
+    pub expansions: HashMap<QualifiedName, Expansion<'ast>>,
+    pub statements: Vec<Statement<'ast>>,
+    pub exports: Vec<ExportRequest<'ast>>,
+    pub argument_checks: Vec<ArgumentCheck<'ast>>,
+    pub contract_checks: Vec<ContractCheck<'ast>>,
+    pub assertions: Vec<Assertion<'ast>>,
+    /// Largest `.align` used in each segment, object file sections must keep it
+    pub alignments: HashMap<Segment, u32>,
+    /// Addresses of literal pool entries for `=expr` operands of `ldi`, by the scope
+    /// of the instruction and the span of the operand
+    pub literals: HashMap<(QualifiedName, Span), u16>,
+    /// Definitions that local label references (`1b`, `1f`) point to, by the scope
+    /// in which the reference is evaluated and its span
+    pub local_labels: HashMap<(QualifiedName, Span), QualifiedName>,
+    /// Symbols that expressions evaluated so far referred to, with the span of the
+    /// part of the name that refers to each of them (`a` in `a.b` names the scope `a`)
+    pub references: RefCell<HashSet<(Span, QualifiedName)>>,
+    /// Macros called by expansions, with the span of the macro name in the call
+    pub macro_references: Vec<(Span, QualifiedName)>,
+    /// Binary files read by `.incbin` and `.incbinw`
+    pub included_files: Vec<IncludedFile>,
+}
+
+/// Local labels are numbered separately in each file and macro expansion,
+/// so that references never reach outside of them
+struct LocalLabelDomain {
+    scope: QualifiedName,
+    /// Number of definitions of each local label so far
+    counts: HashMap<i64, usize>,
+}
+
+impl LocalLabelDomain {
+    fn new(scope: QualifiedName) -> Self {
+        LocalLabelDomain {
+            scope,
+            counts: HashMap::new(),
+        }
+    }
+
+    /// Name of the `index`-th definition of a local label
+    fn name(&self, number: i64, index: usize) -> QualifiedName {
+        let mut name = self.scope.clone();
+        name.push_local_label(number, index);
+        name
+    }
+}
+
+/// `=expr` operand of `ldi` waiting for the next literal pool
+struct PendingLiteral<'ast> {
+    /// Whole `=expr` operand
+    literal: &'ast Spanned<Expr>,
+    value: &'ast Spanned<Expr>,
+    /// Scope of the instruction, in which the value is evaluated
+    scope: QualifiedName,
+    /// Address of the `ldpc` that reaches the entry
+    address: u16,
+}
+
+/// Literals with equal keys share a pool entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum LiteralKey {
+    Number(i64),
+    Relocatable(RelocationBase, i64),
+    /// Value not known when the pool is placed, shared only with literals
+    /// naming the same symbol from the same scope
+    Name(QualifiedName, Vec<String>),
+}
+
+struct LayoutState<'a, 'ast> {
+    /// For the paths of source files, binary includes are relative to them
+    assembler: &'a Assembler,
+    macros: &'a AssemblerTable<MacroDef<'ast>>,
+    errors: &'a mut Vec<AssemblerError>,
+    layout: Layout<'ast>,
+    options: &'a AssembleOptions,
+
+    segment: Segment,
+    /// Next free address, kept wider than the address space to detect overflows
+    address: u32,
+    /// Addresses where each segment continues after switching back to it
+    segment_addresses: HashMap<Segment, u32>,
+    overflow_reported: bool,
+    expansion_stack: Vec<Span>,
+    next_expansion_id: usize,
+    next_iteration_id: usize,
+    local_label_domains: Vec<LocalLabelDomain>,
+    pending_literals: Vec<PendingLiteral<'ast>>,
+    /// Literal pool entries placed so far, in the program segment
+    placed_literals: Vec<(LiteralKey, u16)>,
+    /// The last instruction was an unconditional jump and its delay slot comes next
+    delay_slot_pending: bool,
+}
+
+/// Expand macros, assign addresses to all statements and collect symbols.
+pub fn layout<'a, 'ast>(
+    assembler: &'a Assembler,
+    file_scopes: impl Iterator<Item = (QualifiedName, &'ast Ast)>,
+    macros: &'a AssemblerTable<MacroDef<'ast>>,
+    options: &'a AssembleOptions,
+    errors: &'a mut Vec<AssemblerError>,
+) -> Layout<'ast> {
+    // Object files are laid out from zero, the linker moves them to the final place
+    let start_address = |segment| {
+        if options.relocatable {
+            0
+        } else {
+            u32::from(options.memory_layout.start(segment))
+        }
+    };
+    let mut state = LayoutState {
+        assembler,
+        macros,
+        errors,
+        layout: Layout::default(),
+        options,
+        segment: Segment::Program,
+        address: start_address(Segment::Program),
+        segment_addresses: [Segment::Program, Segment::Data]
+            .into_iter()
+            .map(|segment| (segment, start_address(segment)))
+            .collect(),
+        overflow_reported: false,
+        expansion_stack: Vec::new(),
+        next_expansion_id: 0,
+        next_iteration_id: 0,
+        local_label_domains: Vec::new(),
+        pending_literals: Vec::new(),
+        placed_literals: Vec::new(),
+        delay_slot_pending: false,
+    };
+
+    for (mut scope, ast) in file_scopes {
+        state
+            .local_label_domains
+            .push(LocalLabelDomain::new(scope.clone()));
+        state.layout_items(ast, &mut scope);
+        state.local_label_domains.pop();
+
+        // Literals without an explicit `.pool` or a jump go to the end of their file
+        if let Some((_, last)) = ast.last() {
+            state.place_literal_pool(&end_of(last));
+        }
+    }
+
+    state.layout
+}
+
+impl<'a, 'ast> LayoutState<'a, 'ast> {
+    fn layout_items(&mut self, ast: &'ast Ast, scope: &mut QualifiedName) {
+        let mut routine: Option<OpenRoutine> = None;
+        for (index, (item, span)) in ast.iter().enumerate() {
+            for expr in item_expressions(item) {
+                self.resolve_local_labels(expr, scope);
+            }
+
+            if let Some(open) = &routine
+                && (matches!(item, Item::Contract { .. })
+                    || open.last_item.is_some_and(|last| last < index)
+                    || (open.first_item < index && starts_routine(item)))
+            {
+                self.close_routine(routine.take().unwrap());
+            }
+
+            match item {
+                Item::Scope { label, content } => {
+                    if let Some(label) = label {
+                        self.define_label(label, span, scope);
+                        let (segment, start) = (self.segment, self.address);
+
+                        scope.push_name(label.clone());
+                        self.layout_items(content, scope);
+                        scope.pop();
+
+                        let end = if self.segment == segment {
+                            self.address
+                        } else {
+                            self.segment_addresses[&segment]
+                        };
+                        if let Some(Symbol {
+                            value: SymbolValue::Label { size, .. },
+                            ..
+                        }) = self
+                            .layout
+                            .symbols
+                            .get_mut(&scope.with_names([label.as_str()]))
+                        {
+                            *size = Some(end.wrapping_sub(start) as u16);
+                        }
+                    } else {
+                        scope.push_anonymous(index);
+                        self.layout_items(content, scope);
+                        scope.pop();
+                    }
+                }
+                Item::Instruction { name, args } => {
+                    let evaluator = Evaluator::new(&self.layout, self.options);
+                    let size = encoder::instruction_size(&name.0, args, scope, &evaluator);
+                    if let Some(literal @ (Expr::PoolLiteral(value), _)) =
+                        encoder::pool_literal(&name.0, args)
+                    {
+                        self.pending_literals.push(PendingLiteral {
+                            literal,
+                            value,
+                            scope: scope.clone(),
+                            address: self.address as u16,
+                        });
+                    }
+                    let in_delay_slot = std::mem::take(&mut self.delay_slot_pending);
+                    self.push_statement(
+                        span,
+                        scope,
+                        StatementContent::Instruction { name, args, size },
+                        size,
+                    );
+                    if in_delay_slot {
+                        self.place_literal_pool_after_jump(span);
+                    } else if let Some(mnemonic) = self.unconditional_jump(&name.0, args, scope) {
+                        if mnemonic.has_delay_slot() {
+                            self.delay_slot_pending = true;
+                        } else {
+                            self.place_literal_pool_after_jump(span);
+                        }
+                    }
+                }
+                Item::MacroCall { name, args } => self.expand_macro(name, args, span, scope),
+                Item::MacroDefinition { .. } => (), // Already collected
+                Item::Label { name } => self.define_label(name, span, scope),
+                Item::LocalLabel { number } => {
+                    let domain = self.current_domain();
+                    let count = domain.counts.get(number).copied().unwrap_or(0);
+                    let name = domain.name(*number, count);
+                    domain.counts.insert(*number, count + 1);
+
+                    self.insert_symbol(name, span, self.label_value());
+                    self.push_statement(span, scope, StatementContent::Label, 0);
+                }
+                Item::Const { name, value } => self.define_symbol(
+                    name,
+                    span,
+                    scope,
+                    SymbolValue::Constant {
+                        value,
+                        scope: scope.clone(),
+                    },
+                ),
+                Item::RegisterAlias { name, value } => self.define_symbol(
+                    name,
+                    span,
+                    scope,
+                    SymbolValue::Register {
+                        value,
+                        scope: scope.clone(),
+                    },
+                ),
+                Item::Words { values } => {
+                    let size = values.len() as u16;
+                    self.push_statement(span, scope, StatementContent::Words { values }, size);
+                }
+                Item::IncludeBinary {
+                    path,
+                    offset,
+                    length,
+                    words,
+                } => {
+                    self.include_binary(path, offset.as_ref(), length.as_ref(), *words, span, scope)
+                }
+                Item::Export { names } => {
+                    self.layout
+                        .exports
+                        .extend(names.iter().map(|(name, span)| ExportRequest {
+                            span: span.clone(),
+                            scope: scope.clone(),
+                            name,
+                        }))
+                }
+                Item::Import { names } => {
+                    for (name, span) in names {
+                        self.define_symbol(name, span, scope, SymbolValue::Import { name });
+                    }
+                }
+                Item::Org { address } => {
+                    if let Some(address) = self.eval_early(address, scope, 0, u16::MAX.into()) {
+                        self.address = address as u32;
+                    }
+                }
+                Item::Align { alignment, fill } => {
+                    if let Some(alignment) =
+                        self.eval_early(alignment, scope, 1, SEGMENT_SIZE.into())
+                    {
+                        let alignment = alignment as u32;
+                        let max_alignment = self.layout.alignments.entry(self.segment).or_insert(1);
+                        *max_alignment = (*max_alignment).max(alignment);
+
+                        let size = (self.address.next_multiple_of(alignment) - self.address) as u16;
+                        let value = fill.as_ref();
+                        self.push_statement(
+                            span,
+                            scope,
+                            StatementContent::Fill { value, size },
+                            size,
+                        );
+                    }
+                }
+                Item::Space { size, fill } => {
+                    if let Some(size) = self.eval_early(size, scope, 0, u16::MAX.into()) {
+                        let size = size as u16;
+                        let value = fill.as_ref();
+                        self.push_statement(
+                            span,
+                            scope,
+                            StatementContent::Fill { value, size },
+                            size,
+                        );
+                    }
+                }
+                Item::Struct { name, fields } => self.define_struct(name, fields, span, scope),
+                Item::Assert { condition, message } => self.layout.assertions.push(Assertion {
+                    span: span.clone(),
+                    scope: scope.clone(),
+                    condition,
+                    message: message.as_deref(),
+                }),
+                Item::Contract { contract } => match ast.get(index + 1) {
+                    Some((Item::MacroDefinition { .. }, _)) => (), // Checked in each expansion
+                    Some((next, _)) if starts_routine(next) => {
+                        let start = self.layout.statements.len();
+                        routine = Some(OpenRoutine {
+                            check: ContractCheck {
+                                contract,
+                                span: span.clone(),
+                                scope: scope.clone(),
+                                statements: start..start,
+                            },
+                            first_item: index + 1,
+                            last_item: matches!(ast[index + 1].0, Item::Scope { .. })
+                                .then_some(index + 1),
+                        });
+                    }
+                    _ => self
+                        .errors
+                        .push(AssemblerError::MisplacedContract { span: span.clone() }),
+                },
+                Item::Segment { segment } => self.switch_segment(*segment),
+                Item::Pool => self.place_literal_pool(span),
+                Item::JumpTable {
+                    index,
+                    tmp,
+                    cases,
+                    default,
+                } => {
+                    self.layout_jump_table(index, tmp, cases, default.as_ref(), span, scope);
+                    // The dispatch jumps away and the table is never executed
+                    self.place_literal_pool_after_jump(span);
+                }
+                Item::If {
+                    branches,
+                    else_body,
+                } => {
+                    if let Some(body) = self.select_branch(branches, else_body.as_ref(), scope) {
+                        self.layout_items(body, scope);
+                    }
+                }
+                Item::Repeat { count, body } => {
+                    if let Some(count) = self.eval_early(count, scope, 0, MAX_ITERATIONS) {
+                        self.repeat(None, 0..count, body, scope);
+                    }
+                }
+                Item::For {
+                    counter,
+                    start,
+                    end,
+                    body,
+                } => {
+                    if let Some(start) = self.eval_early(start, scope, i64::MIN, i64::MAX)
+                        && let Some(end) = self.eval_early(
+                            end,
+                            scope,
+                            i64::MIN,
+                            start.saturating_add(MAX_ITERATIONS),
+                        )
+                    {
+                        self.repeat(Some(counter), start..end, body, scope);
+                    }
+                }
+            }
+        }
+
+        if let Some(routine) = routine {
+            self.close_routine(routine);
+        }
+    }
+
+    fn expand_macro(
+        &mut self,
+        name: &str,
+        args: &'ast [Spanned<Expr>],
+        span: &Span,
+        scope: &QualifiedName,
+    ) {
+        let names = name.split('.');
+        let Some((macro_name, macro_def)) = self.macros.lookup(names, scope) else {
+            self.errors.push(AssemblerError::UndefinedMacro {
+                span: span.clone(),
+                name: name.to_owned(),
+            });
+            return;
+        };
+        let name_span = Span {
+            file_id: span.file_id,
+            start: span.start,
+            end: span.start + name.len(),
+        };
+        self.layout.macro_references.push((name_span, macro_name));
+
+        let (fixed_params, variadic_param) = match macro_def.params.split_last() {
+            Some((last, rest)) if last.variadic => (rest, Some(last)),
+            _ => (&macro_def.params[..], None),
+        };
+        let required = fixed_params
+            .iter()
+            .take_while(|param| param.default.is_none())
+            .count();
+        if args.len() < required || (variadic_param.is_none() && args.len() > fixed_params.len()) {
+            self.errors
+                .push(AssemblerError::MacroArgumentCount(Box::new(
+                    ArgumentCountError {
+                        span: span.clone(),
+                        name: name.to_owned(),
+                        min: required,
+                        max: variadic_param.is_none().then_some(fixed_params.len()),
+                        found: args.len(),
+                        definition_span: macro_def.span.clone(),
+                    },
+                )));
+            return;
+        }
+
+        if self.expansion_stack.len() >= MAX_EXPANSION_DEPTH {
+            self.errors
+                .push(AssemblerError::MacroRecursionLimit { span: span.clone() });
+            return;
+        }
+
+        let mut expansion_scope = macro_def.scope.clone();
+        expansion_scope.push_expansion(self.next_expansion_id);
+        self.next_expansion_id += 1;
+
+        let (fixed_args, variadic_args) = args.split_at(args.len().min(fixed_params.len()));
+        let param_args = fixed_params.iter().zip(fixed_args).chain(
+            variadic_param
+                .into_iter()
+                .flat_map(|param| iter::repeat(param).zip(variadic_args)),
+        );
+        for (param, arg) in param_args {
+            if param.kind != ParamKind::Expr {
+                self.layout.argument_checks.push(ArgumentCheck {
+                    call_span: span.clone(),
+                    param,
+                    arg,
+                    scope: scope.clone(),
+                });
+            }
+        }
+
+        self.layout.expansions.insert(
+            expansion_scope.clone(),
+            Expansion {
+                caller_scope: scope.clone(),
+                args: fixed_params
+                    .iter()
+                    .map(|param| param.name.0.as_str())
+                    .zip(fixed_args)
+                    .collect(),
+                defaults: fixed_params[fixed_args.len()..]
+                    .iter()
+                    .filter_map(|param| Some((param.name.0.as_str(), param.default.as_ref()?)))
+                    .collect(),
+                variadic: variadic_param.map(|param| (param.name.0.as_str(), variadic_args)),
+            },
+        );
+
+        let contract_check = macro_def.contract.map(|(contract, contract_span)| {
+            let start = self.layout.statements.len();
+            ContractCheck {
+                contract,
+                span: contract_span.clone(),
+                scope: expansion_scope.clone(),
+                statements: start..start,
+            }
+        });
+
+        self.expansion_stack.push(span.clone());
+        self.local_label_domains
+            .push(LocalLabelDomain::new(expansion_scope.clone()));
+        self.layout_items(macro_def.body, &mut expansion_scope);
+        self.local_label_domains.pop();
+        self.expansion_stack.pop();
+
+        if let Some(mut check) = contract_check {
+            check.statements.end = self.layout.statements.len();
+            self.layout.contract_checks.push(check);
+        }
+    }
+
+    /// Body of the first branch whose condition is true, `None` if there is none
+    /// or a condition can't be evaluated.
+    fn select_branch(
+        &mut self,
+        branches: &'ast [(Spanned<Expr>, Ast)],
+        else_body: Option<&'ast Ast>,
+        scope: &QualifiedName,
+    ) -> Option<&'ast Ast> {
+        for (condition, body) in branches {
+            if self.eval_early(condition, scope, i64::MIN, i64::MAX)? != 0 {
+                return Some(body);
+            }
+        }
+        else_body
+    }
+
+    /// Lay out the body once for every value in the range, each time in a new scope
+    /// with the counter (if any) defined as a constant.
+    fn repeat(
+        &mut self,
+        counter: Option<&'ast Spanned<String>>,
+        range: Range<i64>,
+        body: &'ast Ast,
+        scope: &QualifiedName,
+    ) {
+        for value in range {
+            let mut iteration_scope = scope.clone();
+            iteration_scope.push_iteration(self.next_iteration_id);
+            self.next_iteration_id += 1;
+
+            if let Some((name, span)) = counter {
+                self.define_symbol(name, span, &iteration_scope, SymbolValue::Counter { value });
+            }
+            self.layout_items(body, &mut iteration_scope);
+        }
+    }
+
+    /// Evaluate an expression that must be known during layout (placement, conditions
+    /// and repetition counts). It can only use symbols defined before it.
+    fn eval_early(
+        &mut self,
+        expr: &Spanned<Expr>,
+        scope: &QualifiedName,
+        min: i64,
+        max: i64,
+    ) -> Option<i64> {
+        let evaluator = Evaluator::new(&self.layout, self.options);
+        let result = evaluator.eval_number(expr, scope).and_then(|value| {
+            if (min..=max).contains(&value) {
+                Ok(value)
+            } else {
+                Err(AssemblerError::ValueOutOfRange {
+                    span: expr.1.clone(),
+                    value,
+                    min,
+                    max,
+                })
+            }
+        });
+        result.map_err(|e| self.errors.push(e)).ok()
+    }
+
+    /// Read the part of a file selected by `.incbin` or `.incbinw` and place it
+    fn include_binary(
+        &mut self,
+        (path, path_span): &Spanned<String>,
+        offset: Option<&Spanned<Expr>>,
+        length: Option<&Spanned<Expr>>,
+        words: bool,
+        span: &Span,
+        scope: &QualifiedName,
+    ) {
+        let directory = path_span
+            .file_id
+            .and_then(|file_id| self.assembler.get_path(file_id))
+            .and_then(Path::parent)
+            .unwrap_or(Path::new(""));
+        let file_path = directory.join(path);
+        let bytes = match fs::read(&file_path) {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                self.errors.push(AssemblerError::FileOpenFailed {
+                    span: Some(path_span.clone()),
+                    file_path,
+                    error,
+                });
+                return;
+            }
+        };
+
+        // Offset and length count words with `.incbinw`
+        let unit = if words { 2 } else { 1 };
+        let available = (bytes.len() / unit) as i64;
+        let offset = match offset {
+            Some(offset) => match self.eval_early(offset, scope, 0, available) {
+                Some(offset) => offset as usize * unit,
+                None => return,
+            },
+            None => 0,
+        };
+        let end = match length {
+            Some(length) => {
+                let max = available - (offset / unit) as i64;
+                match self.eval_early(length, scope, 0, max) {
+                    Some(length) => offset + length as usize * unit,
+                    None => return,
+                }
+            }
+            None => bytes.len(),
+        };
+        let bytes = &bytes[offset..end];
+        if words && bytes.len() % 2 != 0 {
+            self.errors.push(AssemblerError::OddBinarySize {
+                span: span.clone(),
+                file_path,
+                size: bytes.len(),
+            });
+            return;
+        }
+
+        // Big-endian, an odd last byte is padded with zero
+        let data: Vec<u16> = bytes
+            .chunks(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]))
+            .collect();
+        let Ok(size) = u16::try_from(data.len()) else {
+            self.errors
+                .push(AssemblerError::AddressOverflow { span: span.clone() });
+            return;
+        };
+        self.layout.included_files.push(IncludedFile {
+            span: span.clone(),
+            path: file_path,
+            segment: self.segment,
+            address: self.address as u16,
+            size,
+        });
+        self.push_statement(span, scope, StatementContent::Binary { data }, size);
+    }
+
+    fn close_routine(&mut self, mut routine: OpenRoutine<'ast>) {
+        routine.check.statements.end = self.layout.statements.len();
+        self.layout.contract_checks.push(routine.check);
+    }
+
+    fn current_domain(&mut self) -> &mut LocalLabelDomain {
+        self.local_label_domains
+            .last_mut()
+            .expect("Items are always laid out within a file or a macro expansion")
+    }
+
+    /// Record which definitions the local label references in an expression point to,
+    /// as seen from the current position.
+    fn resolve_local_labels(&mut self, expr: &Spanned<Expr>, scope: &QualifiedName) {
+        let (expr, span) = expr;
+        match expr {
+            Expr::LocalLabel { number, forward } => {
+                let domain = self.current_domain();
+                let count = domain.counts.get(number).copied().unwrap_or(0);
+                // Forward references point to a definition that may never come,
+                // the evaluator reports those as undefined.
+                let index = if *forward {
+                    Some(count)
+                } else {
+                    count.checked_sub(1)
+                };
+                if let Some(index) = index {
+                    let name = domain.name(*number, index);
+                    self.layout
+                        .local_labels
+                        .insert((scope.clone(), span.clone()), name);
+                }
+            }
+            Expr::BinaryOp { lhs, rhs, .. } => {
+                self.resolve_local_labels(lhs, scope);
+                self.resolve_local_labels(rhs, scope);
+            }
+            Expr::UnaryOp { expr, .. }
+            | Expr::PoolLiteral(expr)
+            | Expr::Function { arg: expr, .. }
+            | Expr::Index { index: expr, .. } => self.resolve_local_labels(expr, scope),
+            Expr::Number(_)
+            | Expr::QualifiedName(_)
+            | Expr::Defined(_)
+            | Expr::SizeOf(_)
+            | Expr::Len(_) => (),
+        }
+    }
+
+    fn layout_jump_table(
+        &mut self,
+        index: &'ast Spanned<Expr>,
+        tmp: &'ast Spanned<Expr>,
+        cases: &'ast [(Spanned<Expr>, Spanned<Expr>)],
+        default: Option<&'ast Spanned<Expr>>,
+        span: &Span,
+        scope: &QualifiedName,
+    ) {
+        let bounds_check = default.is_some();
+        let max_case = encoder::max_jump_table_case(bounds_check);
+        let mut targets: Vec<Option<(&Span, &'ast Spanned<Expr>)>> = Vec::new();
+        for (case, target) in cases {
+            let Some(value) = self.eval_early(case, scope, 0, max_case) else {
+                return;
+            };
+            let value = value as usize;
+            if targets.len() <= value {
+                targets.resize(value + 1, None);
+            }
+            if let Some((previous_span, _)) = targets[value] {
+                self.errors.push(AssemblerError::DuplicateJumpTableCase {
+                    span: case.1.clone(),
+                    value: value as i64,
+                    previous_span: previous_span.clone(),
+                });
+                return;
+            }
+            targets[value] = Some((&case.1, target));
+        }
+
+        let mut complete = Vec::new();
+        for (value, target) in targets.into_iter().enumerate() {
+            match (target, default) {
+                (Some((_, target)), _) | (None, Some(target)) => complete.push(target),
+                (None, None) => {
+                    self.errors.push(AssemblerError::MissingJumpTableCase {
+                        span: span.clone(),
+                        value: value as i64,
+                    });
+                    return;
+                }
+            }
+        }
+        complete.extend(default);
+
+        let size = encoder::jump_table_size(complete.len(), bounds_check);
+        self.push_statement(
+            span,
+            scope,
+            StatementContent::JumpTable {
+                index,
+                tmp,
+                targets: complete,
+                bounds_check,
+            },
+            size,
+        );
+    }
+
+    fn switch_segment(&mut self, segment: Segment) {
+        let previous = std::mem::replace(&mut self.segment, segment);
+        self.segment_addresses.insert(previous, self.address);
+        self.address = self.segment_addresses[&segment];
+    }
+
+    /// Mnemonic of an instruction that execution never falls through:
+    /// `reti`, or `jal` and `bz` on `r0`, which always jump and don't link
+    fn unconditional_jump(
+        &self,
+        name: &str,
+        args: &[Spanned<Expr>],
+        scope: &QualifiedName,
+    ) -> Option<Mnemonic> {
+        let on_r0 = || {
+            args.first().is_some_and(|arg| {
+                Evaluator::new(&self.layout, self.options)
+                    .eval_register(arg, scope)
+                    .is_ok_and(|reg| u16::from(reg) == 0)
+            })
+        };
+        match Mnemonic::from_str(name).ok()? {
+            mnemonic @ Mnemonic::Reti => Some(mnemonic),
+            mnemonic @ (Mnemonic::Jal | Mnemonic::Bz) if on_r0() => Some(mnemonic),
+            _ => None,
+        }
+    }
+
+    /// Place the pending literals after code ending at the span, which execution
+    /// never falls through. This keeps the entries close to their `ldpc`.
+    fn place_literal_pool_after_jump(&mut self, span: &Span) {
+        if self.segment == Segment::Program {
+            self.place_literal_pool(&end_of(span));
+        }
+    }
+
+    /// Place the pending literals at the current address of the program segment.
+    /// A literal reuses an earlier entry with the same value if `ldpc` can reach it.
+    fn place_literal_pool(&mut self, span: &Span) {
+        if self.pending_literals.is_empty() {
+            return;
+        }
+        let previous_segment = self.segment;
+        self.switch_segment(Segment::Program);
+
+        for pending in std::mem::take(&mut self.pending_literals) {
+            let key = self.literal_key(pending.value, &pending.scope);
+            let reused = key.as_ref().and_then(|key| {
+                self.placed_literals
+                    .iter()
+                    .rev()
+                    .find(|(placed, address)| {
+                        placed == key
+                            && i64::from(*address) - i64::from(pending.address) >= i8::MIN.into()
+                    })
+                    .map(|(_, address)| *address)
+            });
+            let address = match reused {
+                Some(address) => address,
+                None => {
+                    let address = self.address as u16;
+                    self.push_statement(
+                        span,
+                        &pending.scope,
+                        StatementContent::Words {
+                            values: slice::from_ref(pending.value),
+                        },
+                        1,
+                    );
+                    if let Some(key) = key {
+                        self.placed_literals.push((key, address));
+                    }
+                    address
+                }
+            };
+            self.layout
+                .literals
+                .insert((pending.scope, pending.literal.1.clone()), address);
+        }
+
+        self.switch_segment(previous_segment);
+    }
+
+    fn literal_key(&self, value: &Spanned<Expr>, scope: &QualifiedName) -> Option<LiteralKey> {
+        match Evaluator::new(&self.layout, self.options).eval(value, scope) {
+            Ok(Value::Number(n)) => Some(LiteralKey::Number(n)),
+            Ok(Value::Relocatable(relocatable)) if !relocatable.high_byte => Some(
+                LiteralKey::Relocatable(relocatable.base, relocatable.addend),
+            ),
+            Ok(_) => None,
+            Err(_) => match &value.0 {
+                Expr::QualifiedName(names) => Some(LiteralKey::Name(
+                    scope.clone(),
+                    names.iter().map(|(name, _)| name.clone()).collect(),
+                )),
+                _ => None,
+            },
+        }
+    }
+
+    /// Define constants for a struct and each of its fields, placed one after another
+    fn define_struct(
+        &mut self,
+        name: &str,
+        fields: &[(Spanned<String>, Spanned<Expr>)],
+        span: &Span,
+        scope: &QualifiedName,
+    ) {
+        let mut offset = 0u16;
+        for ((field_name, field_span), size) in fields {
+            let Some(size) = self.eval_early(size, scope, 0, (u16::MAX - offset).into()) else {
+                return;
+            };
+            let size = size as u16;
+            self.insert_symbol(
+                scope.with_names([name, field_name.as_str()]),
+                field_span,
+                SymbolValue::Field { offset, size },
+            );
+            offset += size;
+        }
+        self.define_symbol(name, span, scope, SymbolValue::Struct { size: offset });
+    }
+
+    fn label_value(&self) -> SymbolValue<'ast> {
+        SymbolValue::Label {
+            segment: self.segment,
+            address: self.address as u16,
+            size: None,
+        }
+    }
+
+    fn define_label(&mut self, name: &str, span: &Span, scope: &QualifiedName) {
+        self.define_symbol(name, span, scope, self.label_value());
+        self.push_statement(span, scope, StatementContent::Label, 0);
+    }
+
+    fn define_symbol(
+        &mut self,
+        name: &str,
+        span: &Span,
+        scope: &QualifiedName,
+        value: SymbolValue<'ast>,
+    ) {
+        self.insert_symbol(scope.with_names([name]), span, value);
+    }
+
+    fn insert_symbol(
+        &mut self,
+        qualified_name: QualifiedName,
+        span: &Span,
+        value: SymbolValue<'ast>,
+    ) {
+        let previous = self.layout.symbols.insert(
+            qualified_name.clone(),
+            Symbol {
+                span: span.clone(),
+                value,
+            },
+        );
+        if let Some(previous) = previous {
+            self.errors.push(AssemblerError::DuplicateDefinition {
+                span: span.clone(),
+                name: qualified_name,
+                previous_span: previous.span,
+            });
+        }
+    }
+
+    fn push_statement(
+        &mut self,
+        span: &Span,
+        scope: &QualifiedName,
+        content: StatementContent<'ast>,
+        size: u16,
+    ) {
+        self.layout.statements.push(Statement {
+            span: span.clone(),
+            scope: scope.clone(),
+            expansion: self.expansion_stack.clone(),
+            segment: self.segment,
+            address: self.address as u16,
+            content,
+        });
+
+        self.address += u32::from(size);
+        if self.address > 0x10000 {
+            if !self.overflow_reported {
+                self.errors
+                    .push(AssemblerError::AddressOverflow { span: span.clone() });
+                self.overflow_reported = true;
+            }
+            self.address &= 0xffff;
+        }
+    }
+}
+
+/// Empty span at the end of a span
+fn end_of(span: &Span) -> Span {
+    Span {
+        file_id: span.file_id,
+        start: span.end,
+        end: span.end,
+    }
+}
+
+/// Named label (not a local numeric one) that a `.contract` can apply to
+fn starts_routine(item: &Item) -> bool {
+    matches!(
+        item,
+        Item::Label { .. } | Item::Scope { label: Some(_), .. }
+    )
+}
+
+/// Expressions that are a direct part of an item (not of a nested block),
+/// evaluated in the scope of the item
+fn item_expressions(item: &Item) -> Vec<&Spanned<Expr>> {
+    match item {
+        Item::Instruction { args, .. } | Item::MacroCall { args, .. } => args.iter().collect(),
+        Item::Words { values } => values.iter().collect(),
+        Item::Const { value, .. } | Item::RegisterAlias { value, .. } => vec![value],
+        Item::Org { address } => vec![address],
+        Item::IncludeBinary { offset, length, .. } => offset.iter().chain(length).collect(),
+        Item::Assert { condition, .. } => vec![condition],
+        Item::Struct { fields, .. } => fields.iter().map(|(_, size)| size).collect(),
+        Item::JumpTable {
+            index,
+            tmp,
+            cases,
+            default,
+        } => [index, tmp]
+            .into_iter()
+            .chain(cases.iter().flat_map(|(case, target)| [case, target]))
+            .chain(default)
+            .collect(),
+        Item::Align {
+            alignment: amount,
+            fill,
+        }
+        | Item::Space { size: amount, fill } => iter::once(amount).chain(fill).collect(),
+        Item::If { branches, .. } => branches.iter().map(|(condition, _)| condition).collect(),
+        Item::Repeat { count, .. } => vec![count],
+        Item::For { start, end, .. } => vec![start, end],
+        Item::Contract { contract } => contract
+            .inputs
+            .iter()
+            .chain(&contract.outputs)
+            .chain(&contract.clobbers)
+            .collect(),
+        Item::Scope { .. }
+        | Item::MacroDefinition { .. }
+        | Item::Label { .. }
+        | Item::LocalLabel { .. }
+        | Item::Export { .. }
+        | Item::Import { .. }
+        | Item::Segment { .. }
+        | Item::Pool => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use toolchain_core::{
+        image::Segment,
+        instruction::{Instruction, Reg},
+    };
+
+    use crate::{
+        AssembleOptions,
+        testing::{assemble_with, codes, errors, program},
+        types::AssemblerError,
+    };
+
+    fn r(n: u16) -> Reg {
+        Reg::new(n).unwrap()
+    }
+
+    fn ldpc(rd: u16, offset: i8) -> u16 {
+        Instruction::Ldpc { rd: r(rd), offset }.encode()
+    }
+
+    fn ldp(rd: u16) -> u16 {
+        Instruction::Ldp {
+            rd: r(rd),
+            addr: r(rd),
+        }
+        .encode()
+    }
+
+    fn nop() -> u16 {
+        Instruction::Add {
+            rd: r(0),
+            ra: r(0),
+            rb: r(0),
+        }
+        .encode()
+    }
+
+    #[test]
+    fn placement() {
+        let words =
+            program("nop\n.org 4\na:\nnop\n.align 4, 0xffff\nb:\n.space 2, 7\nc:\n.dw a, b, c\n");
+        assert_eq!(
+            words,
+            [nop(), nop(), 0xffff, 0xffff, 0xffff, 7, 7, 4, 8, 10]
+        );
+    }
+
+    #[test]
+    fn segments() {
+        let options = AssembleOptions::default();
+        let data = options.memory_layout.start(Segment::Data);
+        let (assembled, errors) = assemble_with(
+            ".data\nx:\n.dw 5\n.program\n.dw x, y\n.data\ny:\n.dw 6\n",
+            &options,
+        );
+        assert!(errors.is_empty());
+        let placed: Vec<_> = assembled
+            .fragments
+            .iter()
+            .filter(|fragment| !fragment.words.is_empty())
+            .map(|fragment| (fragment.segment, fragment.address, fragment.words.clone()))
+            .collect();
+        assert_eq!(
+            placed,
+            [
+                (Segment::Data, data, vec![5]),
+                (Segment::Program, 0, vec![data, data + 1]),
+                (Segment::Data, data + 1, vec![6]),
+            ]
+        );
+    }
+
+    #[test]
+    fn overlap() {
+        assert_eq!(codes("nop\nnop\n.org 1\nnop\n"), ["overlap"]);
+        assert!(codes("nop\n.org 1\nnop\n").is_empty());
+    }
+
+    #[test]
+    fn struct_fields() {
+        let words = program(
+            ".struct Point { x: 1, y: 2, z: 1 }\n\
+             .dw Point.x, Point.y, Point.z, sizeof(Point), sizeof(Point.y)\n",
+        );
+        assert_eq!(words, [0, 1, 3, 4, 2]);
+    }
+
+    #[test]
+    fn struct_fields_on_lines() {
+        let words =
+            program(".struct Pair {\n    a: 2\n    b: 1\n}\n.dw Pair.a, Pair.b, sizeof(Pair)\n");
+        assert_eq!(words, [0, 2, 3]);
+    }
+
+    #[test]
+    fn conditional() {
+        let source = |value| {
+            format!(
+                "const X == {}\n.if X == 1 {{\n.dw 1\n}}\n.elif X > 1 && X < 5 {{\n.dw 2\n}}\n\
+                 .else {{\n.dw 3\n}}\n",
+                value
+            )
+        };
+        assert_eq!(program(&source(1)), [1]);
+        assert_eq!(program(&source(4)), [2]);
+        assert_eq!(program(&source(5)), [3]);
+    }
+
+    #[test]
+    fn conditional_needs_known_value() {
+        assert_eq!(codes(".if later {\n.dw 1\n}\nlater:\n"), ["undefined-name"]);
+    }
+
+    #[test]
+    fn repetition() {
+        assert_eq!(program(".rept 3 {\n.dw 7\n}\n"), [7, 7, 7]);
+        assert!(program(".rept 0 {\n.dw 7\n}\n").is_empty());
+        assert_eq!(program(".for i in 1..4 {\n.dw i * 2\n}\n"), [2, 4, 6]);
+    }
+
+    #[test]
+    fn labels_in_each_iteration() {
+        let words = program(".rept 2 {\nx:\n.dw x\n}\n");
+        assert_eq!(words, [0, 1]);
+    }
+
+    #[test]
+    fn equal_literals_share_an_entry() {
+        let words = program("ldi r1, =0x1234\nldi r2, =0x1234\nldi r3, =0x5678\n");
+        assert_eq!(
+            words,
+            [
+                ldpc(1, 6),
+                ldp(1),
+                ldpc(2, 4),
+                ldp(2),
+                ldpc(3, 3),
+                ldp(3),
+                0x1234,
+                0x5678
+            ]
+        );
+    }
+
+    #[test]
+    fn literal_at_the_limit_of_reach() {
+        let words = program("ldi r1, =0x1234\n.space 125\n");
+        assert_eq!(words[..2], [ldpc(1, 127), ldp(1)]);
+        assert_eq!(words[127], 0x1234);
+    }
+
+    #[test]
+    fn literal_out_of_reach() {
+        let errors = errors("ldi r1, =0x1234\n.space 126\n");
+        assert!(
+            matches!(
+                errors.as_slice(),
+                [AssemblerError::LiteralOutOfReach { .. }]
+            ),
+            "{:?}",
+            errors
+        );
+    }
+
+    #[test]
+    fn pool_after_jump() {
+        let words = program("ldi r1, =0x1234\njal r0, r1\nnop\n.space 200\n");
+        assert_eq!(words[..2], [ldpc(1, 4), ldp(1)]);
+        assert_eq!(words[4], 0x1234);
+    }
+
+    #[test]
+    fn pool_after_reti() {
+        let words = program("ldi r1, =0x1234\nreti\n.space 200\n");
+        assert_eq!(words[..2], [ldpc(1, 3), ldp(1)]);
+        assert_eq!(words[3], 0x1234);
+    }
+
+    #[test]
+    fn local_labels_resolve_to_the_nearest_definition() {
+        let words = program("1:\nnop\n1:\nnop\n.dw 1b, 1f\n1:\n");
+        assert_eq!(words[2..], [1, 4]);
+    }
+
+    #[test]
+    fn local_labels_in_each_expansion() {
+        let words = program("macro m {\n1:\n.dw 1b, 1f\n1:\n}\nm!\nm!\n");
+        assert_eq!(words, [0, 2, 2, 4]);
+    }
+
+    #[test]
+    fn local_labels_dont_reach_out_of_expansions() {
+        assert_eq!(codes("1:\nmacro m {\n.dw 1b\n}\nm!\n"), ["undefined-name"]);
+        assert_eq!(
+            codes("macro m {\n1:\nnop\n}\nm!\n.dw 1b\n"),
+            ["undefined-name"]
+        );
+    }
+
+    #[test]
+    fn no_pool_after_call() {
+        // `jal` that links returns to the code after its delay slot
+        let errors = errors("ldi r1, =0x1234\njal r1, r1\nnop\n.space 200\n");
+        assert!(
+            matches!(
+                errors.as_slice(),
+                [AssemblerError::LiteralOutOfReach { .. }]
+            ),
+            "{:?}",
+            errors
+        );
+    }
+}
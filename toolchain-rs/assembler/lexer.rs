@@ -1,12 +1,25 @@
+use std::fmt;
+
 use chumsky::{input::StrInput, prelude::*};
 
-use crate::types::{AssemblerError, FileId, Span, Spanned};
+use crate::{
+    chumsky_util::Describe,
+    types::{AssemblerError, FileId, ParseError, Span, Spanned},
+};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Token<'src> {
     Identifier(&'src str),
     MacroCall(&'src str),
     Number(i64),
+    /// `1b`, reference to the closest preceding local label `1:`
+    LocalLabelBackward(i64),
+    /// `1f`, reference to the closest following local label `1:`
+    LocalLabelForward(i64),
+    /// `"text"`, without the quotes. There are no escape sequences.
+    String(&'src str),
+    /// `; text`, without the semicolon. Only produced by `tokenize_with_comments`.
+    Comment(&'src str),
 
     // Keywords
     Const,
@@ -41,27 +54,106 @@ pub enum Token<'src> {
     Caret,
     Tilde,
     Dot,
+    DoubleDot,
+    Ellipsis,
+    LBracket,
+    RBracket,
 
     Eol,
 }
 
+/// Tokens are displayed in source syntax
+impl fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Identifier(name) => write!(f, "{}", name),
+            Token::MacroCall(name) => write!(f, "{}!", name),
+            Token::Number(n) => write!(f, "{}", n),
+            Token::LocalLabelBackward(n) => write!(f, "{}b", n),
+            Token::LocalLabelForward(n) => write!(f, "{}f", n),
+            Token::String(s) => write!(f, "\"{}\"", s),
+            Token::Comment(text) => write!(f, ";{}", text),
+            Token::Const => f.write_str("const"),
+            Token::Macro => f.write_str("macro"),
+            Token::Colon => f.write_str(":"),
+            Token::Comma => f.write_str(","),
+            Token::LParen => f.write_str("("),
+            Token::RParen => f.write_str(")"),
+            Token::LBrace => f.write_str("{"),
+            Token::RBrace => f.write_str("}"),
+            Token::Lt => f.write_str("<"),
+            Token::Gt => f.write_str(">"),
+            Token::DoubleEqual => f.write_str("=="),
+            Token::Neq => f.write_str("!="),
+            Token::Le => f.write_str("<="),
+            Token::Ge => f.write_str(">="),
+            Token::Equal => f.write_str("="),
+            Token::Plus => f.write_str("+"),
+            Token::Minus => f.write_str("-"),
+            Token::Asterisk => f.write_str("*"),
+            Token::Slash => f.write_str("/"),
+            Token::Percent => f.write_str("%"),
+            Token::DoubleLt => f.write_str("<<"),
+            Token::DoubleGt => f.write_str(">>"),
+            Token::DoubleAmpersand => f.write_str("&&"),
+            Token::DoublePipe => f.write_str("||"),
+            Token::Exclamation => f.write_str("!"),
+            Token::Ampersand => f.write_str("&"),
+            Token::Pipe => f.write_str("|"),
+            Token::Caret => f.write_str("^"),
+            Token::Tilde => f.write_str("~"),
+            Token::Dot => f.write_str("."),
+            Token::DoubleDot => f.write_str(".."),
+            Token::Ellipsis => f.write_str("..."),
+            Token::LBracket => f.write_str("["),
+            Token::RBracket => f.write_str("]"),
+            Token::Eol => f.write_str("\n"),
+        }
+    }
+}
+
+/// Split the input into tokens. Invalid characters are reported and skipped,
+/// so the result is never missing.
 pub fn tokenize<'src>(
     input: &'src str,
     file_id: Option<FileId>,
     errors: &mut Vec<AssemblerError>,
-) -> Option<Vec<Spanned<Token<'src>>>> {
+) -> Vec<Spanned<Token<'src>>> {
+    without_comments(tokenize_with_comments(input, file_id, errors))
+}
+
+/// Remove comment tokens, the parser doesn't expect them
+pub fn without_comments(tokens: Vec<Spanned<Token<'_>>>) -> Vec<Spanned<Token<'_>>> {
+    tokens
+        .into_iter()
+        .filter(|(token, _)| !matches!(token, Token::Comment(_)))
+        .collect()
+}
+
+/// Like `tokenize`, but comments are kept as tokens
+pub fn tokenize_with_comments<'src>(
+    input: &'src str,
+    file_id: Option<FileId>,
+    errors: &mut Vec<AssemblerError>,
+) -> Vec<Spanned<Token<'src>>> {
+    let source = input;
     let input = input.map_span(move |x| Span {
         file_id,
         start: x.start,
         end: x.end,
     });
     let (tokens, tokenize_errors) = lexer().parse(input).into_output_errors();
-    errors.extend(
-        tokenize_errors
-            .into_iter()
-            .map(|e| AssemblerError::InvalidToken(e.into())),
-    );
-    tokens
+    errors.extend(tokenize_errors.into_iter().map(|e| {
+        // What the lexer expected is not useful, the offending character is
+        let mut error = ParseError::from(e);
+        error.found = source[error.span.start..]
+            .chars()
+            .next()
+            .map(|c| c.describe());
+        error.expected.clear();
+        AssemblerError::InvalidToken(Box::new(error))
+    }));
+    tokens.unwrap_or_default()
 }
 
 fn lexer<'src, I>()
@@ -95,6 +187,32 @@ where
         })
         .labelled("number");
 
+    // Must not swallow the beginning of a binary number (`0b101`)
+    let local_label_ref = text::digits(10)
+        .at_least(1)
+        .to_slice()
+        .then(one_of("bf"))
+        .then_ignore(
+            any()
+                .filter(|c: &char| c.is_ascii_alphanumeric() || *c == '_')
+                .not(),
+        )
+        .try_map(
+            |(s, direction): (&str, char), span| match s.parse::<i64>() {
+                Ok(v) if direction == 'b' => Ok(Token::LocalLabelBackward(v)),
+                Ok(v) => Ok(Token::LocalLabelForward(v)),
+                Err(_) => Err(Rich::custom(span, "invalid local label number")),
+            },
+        )
+        .labelled("local label reference");
+
+    let string = none_of("\"\n")
+        .repeated()
+        .to_slice()
+        .delimited_by(just('"'), just('"'))
+        .map(Token::String)
+        .labelled("string");
+
     let symbol = choice([
         just("==").to(Token::DoubleEqual),
         just("!=").to(Token::Neq),
@@ -104,11 +222,15 @@ where
         just(">>").to(Token::DoubleGt),
         just("&&").to(Token::DoubleAmpersand),
         just("||").to(Token::DoublePipe),
+        just("...").to(Token::Ellipsis),
+        just("..").to(Token::DoubleDot),
         just(":").to(Token::Colon),
         just(",").to(Token::Comma),
         just("(").to(Token::LParen),
         just(")").to(Token::RParen),
         just("{").to(Token::LBrace),
+        just("[").to(Token::LBracket),
+        just("]").to(Token::RBracket),
         just("}").to(Token::RBrace),
         just("<").to(Token::Lt),
         just(">").to(Token::Gt),
@@ -133,35 +255,47 @@ where
         .ignored();
 
     let comment = just(';')
-        .then(any().and_is(just('\n').not()).repeated())
-        .ignored()
+        .ignore_then(any().and_is(just('\n').not()).repeated().to_slice())
+        .map(Token::Comment)
         .labelled("comment");
 
-    let comments_and_spaces = comment.or(whitespace).repeated();
+    let whitespace = whitespace.repeated();
 
-    let token = choice((newline, keyword, identifier, number, symbol));
+    let token = choice((
+        newline,
+        comment,
+        keyword,
+        identifier,
+        local_label_ref,
+        number,
+        string,
+        symbol,
+    ));
 
-    let lexer = token
-        .map_with(|t, e| (t, e.span()))
-        .padded_by(comments_and_spaces)
-        // .recover_with(skip_then_retry_until(any().ignored(), end()))
-        .repeated()
-        .collect()
-        .then_ignore(comments_and_spaces);
+    // Invalid characters are reported and skipped
+    let lexer = whitespace.ignore_then(
+        token
+            .map_with(|t, e| (t, e.span()))
+            .recover_with(skip_then_retry_until(any().ignored(), end()))
+            .then_ignore(whitespace)
+            .repeated()
+            .collect(),
+    );
     lexer
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use itertools::Itertools;
     use test_case::test_case;
     use test_strategy::proptest;
 
-    pub fn tokenize<'src>(
-        input: &'src str,
-        errors: &mut Vec<AssemblerError>,
-    ) -> Option<Vec<Token<'src>>> {
-        super::tokenize(input, None, errors).map(|v| v.into_iter().map(|(t, _)| t).collect())
+    pub fn tokenize<'src>(input: &'src str, errors: &mut Vec<AssemblerError>) -> Vec<Token<'src>> {
+        super::tokenize(input, None, errors)
+            .into_iter()
+            .map(|(t, _)| t)
+            .collect()
     }
 
     #[test_case("abcd", &[Token::Identifier("abcd")]; "identifier_simple")]
@@ -174,9 +308,14 @@ mod tests {
     #[test_case("9223372036854775807", &[Token::Number(9223372036854775807)]; "number_max")]
     #[test_case("0x2a", &[Token::Number(0x2a)]; "number_hex")]
     #[test_case("0b1010", &[Token::Number(0b1010)]; "number_binary")]
+    #[test_case("1b 0b 12f", &[Token::LocalLabelBackward(1), Token::LocalLabelBackward(0), Token::LocalLabelForward(12)]; "local_label_refs")]
+    #[test_case("1:", &[Token::Number(1), Token::Colon]; "local_label")]
+    #[test_case("\"a; b\"", &[Token::String("a; b")]; "string")]
     #[test_case("==", &[Token::DoubleEqual]; "symbol_eq")]
+    #[test_case("args...", &[Token::Identifier("args"), Token::Ellipsis]; "ellipsis")]
     #[test_case("<=", &[Token::Le]; "symbol_le")]
     #[test_case("{ }", &[Token::LBrace, Token::RBrace]; "braces")]
+    #[test_case("0..a", &[Token::Number(0), Token::DoubleDot, Token::Identifier("a")]; "range")]
     #[test_case("a\nb", &[Token::Identifier("a"), Token::Eol, Token::Identifier("b")]; "newline")]
     #[test_case("; c", &[]; "only_comment1")]
     #[test_case("; c\n", &[Token::Eol]; "only_comment2")]
@@ -188,7 +327,7 @@ mod tests {
         let mut errors = Vec::new();
         let tokens = tokenize(input, &mut errors);
         assert!(errors.is_empty());
-        assert_eq!(tokens.unwrap(), expected);
+        assert_eq!(tokens, expected);
     }
 
     #[test_case("0x"; "num_only_prefix")]
@@ -202,10 +341,55 @@ mod tests {
         assert!(!errors.is_empty());
     }
 
+    #[test]
+    fn recovers_after_invalid_character() {
+        let mut errors = Vec::new();
+        let tokens = tokenize("a $ b\n@c", &mut errors);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            tokens,
+            &[
+                Token::Identifier("a"),
+                Token::Identifier("b"),
+                Token::Eol,
+                Token::Identifier("c")
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_kept() {
+        let mut errors = Vec::new();
+        let tokens: Vec<_> = super::tokenize_with_comments("a ; one\n; two", None, &mut errors)
+            .into_iter()
+            .map(|(t, _)| t)
+            .collect();
+        assert!(errors.is_empty());
+        assert_eq!(
+            tokens,
+            &[
+                Token::Identifier("a"),
+                Token::Comment(" one"),
+                Token::Eol,
+                Token::Comment(" two")
+            ]
+        );
+    }
+
+    #[test_case("foo bar! 0x2a 1b 2f \"s\" const macro"; "words")]
+    #[test_case(": , ( ) { } [ ] < > == != <= >= = + - * / % << >> && || ! & | ^ ~ . .. ..."; "symbols")]
+    fn display_roundtrip(input: &str) {
+        let mut errors = Vec::new();
+        let tokens = tokenize(input, &mut errors);
+        let displayed = tokens.iter().map(ToString::to_string).join(" ");
+        assert_eq!(tokenize(&displayed, &mut errors), tokens);
+        assert!(errors.is_empty());
+    }
+
     #[proptest]
     fn comment(#[strategy(r"abc ;[^\n]*\ndef")] input: String) {
         let mut errors = Vec::new();
-        let tokens: Vec<_> = tokenize(input.as_ref(), &mut errors).unwrap();
+        let tokens = tokenize(input.as_ref(), &mut errors);
         assert!(errors.is_empty());
         assert_eq!(
             tokens,
@@ -0,0 +1,122 @@
+//! Assembler for the Pickle CPU, usable without touching the file system.
+//!
+//! Sources are added to an [`Assembler`] from files or from strings under virtual
+//! names, [`Assembler::build`] then returns the image, symbols and diagnostics:
+//!
+//! ```
+//! use pickle_assembler::{AssembleOptions, Assembler};
+//!
+//! let mut assembler = Assembler::default();
+//! let mut errors = Vec::new();
+//! assembler.add_source("test.asm", "start:\n    add r1, r2, r3\n".to_owned(), &mut errors);
+//! let output = assembler.build(&AssembleOptions::default(), errors);
+//!
+//! assert!(output.diagnostics.is_empty());
+//! let image = output.image.unwrap();
+//! let (address, words) = image.iter_blocks().next().unwrap();
+//! assert_eq!((address, words.len()), (0, 1));
+//! assert_eq!(output.symbols.unwrap().labels[0].address, 0);
+//! ```
+
+pub mod assembler;
+mod chumsky_util;
+mod dataflow;
+pub mod depfile;
+pub mod diagnostic;
+pub mod encoder;
+mod eval;
+mod layout;
+pub mod lexer;
+mod lints;
+pub mod listing;
+mod parser;
+pub mod symbols;
+#[cfg(test)]
+mod testing;
+pub mod types;
+pub mod warnings;
+
+pub use crate::{
+    assembler::{AssembleOptions, Assembled, Assembler, Output},
+    diagnostic::{DiagnosticRecord, RecordSpan},
+    types::{AssemblerError, FileId, Severity, Span},
+    warnings::WarningOptions,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagnostics_point_into_virtual_files() {
+        let mut assembler = Assembler::default();
+        let mut errors = Vec::new();
+        assembler.add_source("first.asm", "nop\n".to_owned(), &mut errors);
+        assembler.add_source(
+            "second.asm",
+            "nop\n  add r1, r2, missing\n".to_owned(),
+            &mut errors,
+        );
+        let output = assembler.build(&AssembleOptions::default(), errors);
+
+        assert!(output.has_errors());
+        assert!(output.image.is_none());
+        assert!(output.symbols.is_none());
+        let span = output.diagnostics[0].primary_span.as_ref().unwrap();
+        assert_eq!(span.file, "second.asm");
+        assert_eq!((span.line, span.column), (2, 15));
+    }
+
+    #[test]
+    fn parse_errors_skip_assembly() {
+        let mut assembler = Assembler::default();
+        let mut errors = Vec::new();
+        assembler.add_source("test.asm", "add r1,,\n".to_owned(), &mut errors);
+        let output = assembler.build(&AssembleOptions::default(), errors);
+
+        assert_eq!(output.diagnostics.len(), 1);
+        assert_eq!(output.diagnostics[0].severity, Severity::Error);
+        assert_eq!(output.diagnostics[0].code, "syntax-error");
+    }
+
+    #[test]
+    fn object_and_listing() {
+        let mut assembler = Assembler::default();
+        let mut errors = Vec::new();
+        assembler.add_source("test.asm", "start:\n    nop\n".to_owned(), &mut errors);
+        let options = AssembleOptions {
+            relocatable: true,
+            listing: true,
+            ..Default::default()
+        };
+        let output = assembler.build(&options, errors);
+
+        assert!(output.diagnostics.is_empty());
+        assert!(output.image.is_none());
+        assert_eq!(output.object.unwrap().sections.len(), 1);
+        assert!(output.listing.unwrap().contains("P:0000  0003"));
+        assert!(output.dependencies.is_empty());
+    }
+
+    #[test]
+    fn include_binary() {
+        let directory = std::env::temp_dir().join(format!("incbin-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("data.bin"), [1, 2, 3, 4, 5]).unwrap();
+
+        let mut assembler = Assembler::default();
+        let mut errors = Vec::new();
+        let source = ".incbin \"data.bin\"\n\
+                      .incbin \"data.bin\", 1, 2\n\
+                      .incbinw \"data.bin\", 1, 1\n";
+        assembler.add_source(directory.join("test.asm"), source.to_owned(), &mut errors);
+        let output = assembler.build(&AssembleOptions::default(), errors);
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert!(output.diagnostics.is_empty());
+        assert_eq!(output.dependencies, vec![directory.join("data.bin"); 3]);
+        let image = output.image.unwrap();
+        let (_, words) = image.iter_blocks().next().unwrap();
+        assert_eq!(words, [0x0102, 0x0304, 0x0500, 0x0203, 0x0304]);
+    }
+}
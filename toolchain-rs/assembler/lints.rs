@@ -0,0 +1,181 @@
+//! Checks of code that assembles fine, but probably doesn't do what was intended.
+//! They only produce warnings.
+use std::collections::{HashMap, HashSet};
+
+use toolchain_core::instruction::Instruction;
+
+use crate::{
+    assembler::{Fragment, SymbolValue},
+    layout::{Layout, StatementContent},
+    types::{AssemblerError, DelaySlotHazard, Span},
+};
+
+/// Warn about labels that no expression or `.export` refers to.
+/// Labels from macro expansions and local numeric labels are not checked.
+pub fn check_unused_labels(layout: &Layout, errors: &mut Vec<AssemblerError>) {
+    let mut used: HashSet<_> = layout
+        .references
+        .borrow()
+        .iter()
+        .map(|(_, name)| name.clone())
+        .collect();
+    for export in layout.exports.iter() {
+        if let Some((name, _)) = layout.symbols.lookup([export.name], &export.scope) {
+            used.insert(name);
+        }
+    }
+
+    let mut unused: Vec<_> = layout
+        .symbols
+        .iter()
+        .filter(|(name, symbol)| {
+            matches!(symbol.value, SymbolValue::Label { .. })
+                && !name.is_generated()
+                && !used.contains(*name)
+        })
+        .filter_map(|(name, symbol)| {
+            let (_, label) = name.split_last_name()?;
+            Some(AssemblerError::UnusedLabel {
+                span: symbol.span.clone(),
+                name: label.to_owned(),
+            })
+        })
+        .collect();
+    unused.sort_by_key(|warning| source_order(warning.warning_span()));
+    errors.extend(unused);
+}
+
+/// Warn about definitions that hide a symbol of the same name from an outer scope.
+pub fn check_shadowed_names(layout: &Layout, errors: &mut Vec<AssemblerError>) {
+    let mut shadowed: Vec<_> = layout
+        .symbols
+        .iter()
+        .filter(|(name, symbol)| {
+            // Fields are only ever accessed through the struct name
+            !name.is_generated() && !matches!(symbol.value, SymbolValue::Field { .. })
+        })
+        .filter_map(|(name, symbol)| {
+            let (scope, last) = name.split_last_name()?;
+            let outer = scope
+                .scopes()
+                .skip(1)
+                .find_map(|outer| layout.symbols.get(&outer.with_names([last])))?;
+            Some(AssemblerError::ShadowedName {
+                span: symbol.span.clone(),
+                name: last.to_owned(),
+                previous_span: outer.span.clone(),
+            })
+        })
+        .collect();
+    shadowed.sort_by_key(|warning| source_order(warning.warning_span()));
+    errors.extend(shadowed);
+}
+
+/// Warn about branches whose delay slot holds another branch, only a part of
+/// a pseudo instruction, or data.
+pub fn check_delay_slots(
+    layout: &Layout,
+    fragments: &[Fragment],
+    errors: &mut Vec<AssemblerError>,
+) {
+    let by_address: HashMap<_, _> = fragments
+        .iter()
+        .enumerate()
+        .filter(|(_, fragment)| !fragment.words.is_empty())
+        .map(|(i, fragment)| ((fragment.segment, fragment.address), i))
+        .collect();
+    // Statements of an expansion share the span, report each place once
+    let mut reported = HashSet::new();
+
+    for (statement, fragment) in layout.statements.iter().zip(fragments) {
+        if !matches!(statement.content, StatementContent::Instruction { .. }) {
+            continue;
+        }
+        let is_branch = fragment
+            .words
+            .last()
+            .and_then(|word| Instruction::decode(*word))
+            .is_some_and(|instruction| instruction.mnemonic().has_delay_slot());
+        if !is_branch {
+            continue;
+        }
+
+        let slot_address = fragment.address.wrapping_add(fragment.words.len() as u16);
+        let Some(&slot) = by_address.get(&(fragment.segment, slot_address)) else {
+            continue;
+        };
+        let hazard = match &layout.statements[slot].content {
+            StatementContent::Instruction { .. } if fragments[slot].words.len() > 1 => {
+                DelaySlotHazard::PartialInstruction
+            }
+            StatementContent::Instruction { .. } => {
+                let is_branch = Instruction::decode(fragments[slot].words[0])
+                    .is_some_and(|instruction| instruction.mnemonic().has_delay_slot());
+                if !is_branch {
+                    continue;
+                }
+                DelaySlotHazard::Branch
+            }
+            StatementContent::JumpTable { .. } => DelaySlotHazard::PartialInstruction,
+            // Zero padding executes as `nop`
+            StatementContent::Fill { .. } if fragments[slot].words[0] == 0 => continue,
+            StatementContent::Words { .. }
+            | StatementContent::Binary { .. }
+            | StatementContent::Fill { .. } => DelaySlotHazard::Data,
+            StatementContent::Label => continue,
+        };
+
+        let span = layout.statements[slot].span.clone();
+        if reported.insert((span.clone(), statement.span.clone())) {
+            errors.push(AssemblerError::DelaySlot {
+                span,
+                branch_span: statement.span.clone(),
+                hazard,
+            });
+        }
+    }
+}
+
+/// Sort key that orders warnings by file and position in it
+fn source_order(span: Option<&Span>) -> Option<(Option<usize>, usize)> {
+    span.map(|span| (span.file_id.map(|id| id.index()), span.start))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{codes, codes_with_flags};
+
+    #[test]
+    fn unused_label() {
+        let source = "start:\nnop\n";
+        assert!(codes(source).is_empty(), "disabled by default");
+        assert_eq!(
+            codes_with_flags(source, &["unused-label"]),
+            ["unused-label"]
+        );
+        assert!(codes_with_flags("start:\n.dw start\n", &["unused-label"]).is_empty());
+    }
+
+    #[test]
+    fn shadowed_name() {
+        assert_eq!(
+            codes("x:\nouter: {\n    x:\n    .dw x\n}\n.dw x\n"),
+            ["shadowed-name"]
+        );
+        assert!(codes("x:\nouter: {\n    y:\n    .dw y\n}\n.dw x\n").is_empty());
+    }
+
+    #[test]
+    fn delay_slot() {
+        assert_eq!(codes("bz r1, r2\nbnz r1, r2\nnop\n"), ["delay-slot"]);
+        assert_eq!(codes("bz r1, r2\n.dw 5\n"), ["delay-slot"]);
+        assert!(codes("bz r1, r2\nnop\n").is_empty());
+        assert!(codes("bz r1, r2\n.space 1\n").is_empty());
+    }
+
+    #[test]
+    fn truncated_immediate() {
+        assert_eq!(codes("addi r1, 0xff\n"), ["truncated-immediate"]);
+        assert!(codes("addi r1, -1\n").is_empty());
+    }
+}
@@ -0,0 +1,325 @@
+//! Human readable listing of the assembled program, with addresses and encoded words
+//! next to the source lines.
+
+use std::{collections::HashMap, io};
+
+use itertools::Itertools;
+use toolchain_core::image::Segment;
+
+use crate::{
+    assembler::{Assembled, Assembler, Fragment},
+    types::{FileId, LineIndex},
+};
+
+/// How many encoded words fit on a single listing line
+const WORDS_PER_LINE: usize = 3;
+
+/// Width of the source indentation per macro expansion level
+const EXPANSION_INDENT: usize = 4;
+
+pub fn write_listing(
+    out: &mut impl io::Write,
+    assembler: &Assembler,
+    assembled: &Assembled,
+) -> io::Result<()> {
+    let mut sources = Sources::new(assembler);
+
+    // Fragments grouped by the file and line where they appear at top level
+    // (macro expansions are anchored at their outermost call site)
+    let mut by_line: HashMap<(FileId, usize), Vec<&Fragment>> = HashMap::new();
+    for fragment in assembled.fragments.iter() {
+        let anchor = fragment.expansion.first().unwrap_or(&fragment.span);
+        let Some(file_id) = anchor.file_id else {
+            continue;
+        };
+        let line = sources.index(file_id).line(anchor.start);
+        by_line.entry((file_id, line)).or_default().push(fragment);
+    }
+
+    for file_id in assembler.file_ids() {
+        let path = assembler
+            .get_path(file_id)
+            .ok_or_else(|| unknown_file(file_id))?;
+        writeln!(out, "; {}", path.display())?;
+        writeln!(
+            out,
+            "{:>5}  {:<7} {:<14} {:>3}  source",
+            "line", "address", "words", "cyc"
+        )?;
+
+        let source = assembler
+            .get_source(file_id)
+            .ok_or_else(|| unknown_file(file_id))?;
+        let line_count = sources.index(file_id).line_count();
+        for line in 0..line_count {
+            let text = sources.index(file_id).line_text(source, line);
+            let fragments = by_line
+                .get(&(file_id, line))
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+
+            let (top_level, expanded): (Vec<&Fragment>, Vec<&Fragment>) = fragments
+                .iter()
+                .partition(|fragment| fragment.expansion.is_empty());
+
+            // Iterations of `.rept` and `.for` place the same line several times,
+            // each contiguous run of it gets its own row
+            let runs = contiguous_runs(&top_level);
+            if runs.is_empty() {
+                write_row(out, Some(line + 1), None, &[], 0, 0, text)?;
+            }
+            for (i, run) in runs.iter().enumerate() {
+                let words: Vec<u16> = run
+                    .iter()
+                    .flat_map(|fragment| fragment.words.iter().copied())
+                    .collect();
+                let cycles = run.iter().map(|fragment| fragment.cycles).sum();
+                let location = (run[0].segment, run[0].address);
+                let line_number = (i == 0).then_some(line + 1);
+                write_row(out, line_number, Some(location), &words, cycles, 0, text)?;
+            }
+
+            for fragment in expanded {
+                let text = match fragment.span.file_id {
+                    Some(id) => {
+                        let source = assembler.get_source(id).ok_or_else(|| unknown_file(id))?;
+                        let index = sources.index(id);
+                        index.line_text(source, index.line(fragment.span.start))
+                    }
+                    None => "",
+                };
+                write_row(
+                    out,
+                    None,
+                    Some((fragment.segment, fragment.address)),
+                    &fragment.words,
+                    fragment.cycles,
+                    fragment.expansion.len(),
+                    text.trim_start(),
+                )?;
+            }
+        }
+        writeln!(out)?;
+    }
+
+    writeln!(out, "; Labels")?;
+    for (name, label) in assembled.labels.iter_sorted() {
+        writeln!(
+            out,
+            "{}  {}",
+            format_location(label.segment, label.address),
+            name
+        )?;
+    }
+    writeln!(out)?;
+
+    writeln!(out, "; Constants")?;
+    for (name, constant) in assembled.constants.iter_sorted() {
+        writeln!(
+            out,
+            "{:#06x} {:>6}  {}",
+            constant.value as u16, constant.value, name
+        )?;
+    }
+
+    if !assembled.included_files.is_empty() {
+        writeln!(out)?;
+        writeln!(out, "; Included files")?;
+        for file in assembled.included_files.iter() {
+            writeln!(
+                out,
+                "{}  {:>6}  {}",
+                format_location(file.segment, file.address),
+                file.size,
+                file.path.display()
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Split fragments into runs that follow each other directly in the same segment.
+fn contiguous_runs<'a>(fragments: &[&'a Fragment]) -> Vec<Vec<&'a Fragment>> {
+    let mut runs: Vec<Vec<&Fragment>> = Vec::new();
+    for &fragment in fragments {
+        if let Some(run) = runs.last_mut()
+            && let Some(previous) = run.last()
+            && previous.segment == fragment.segment
+            && previous.address.wrapping_add(previous.words.len() as u16) == fragment.address
+        {
+            run.push(fragment);
+        } else {
+            runs.push(vec![fragment]);
+        }
+    }
+    runs
+}
+
+fn unknown_file(file_id: FileId) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("source file {:?} is not part of the assembly", file_id),
+    )
+}
+
+fn format_location(segment: Segment, address: u16) -> String {
+    let segment = match segment {
+        Segment::Data => 'D',
+        Segment::Program => 'P',
+    };
+    format!("{}:{:04x}", segment, address)
+}
+
+/// Write one line of the listing, wrapping the words to continuation lines if necessary.
+fn write_row(
+    out: &mut impl io::Write,
+    line_number: Option<usize>,
+    location: Option<(Segment, u16)>,
+    words: &[u16],
+    cycles: u32,
+    expansion_depth: usize,
+    text: &str,
+) -> io::Result<()> {
+    let line_number = line_number.map(|n| n.to_string()).unwrap_or_default();
+    let location_str = location
+        .map(|(segment, address)| format_location(segment, address))
+        .unwrap_or_default();
+    let cycles = if words.is_empty() {
+        String::new()
+    } else {
+        cycles.to_string()
+    };
+    let indent = expansion_depth * EXPANSION_INDENT;
+
+    let mut chunks = words.chunks(WORDS_PER_LINE);
+    let first_words = chunks
+        .next()
+        .unwrap_or_default()
+        .iter()
+        .map(|w| format!("{:04x}", w))
+        .join(" ");
+    writeln!(
+        out,
+        "{:>5}  {:<7} {:<14} {:>3}  {:indent$}{}",
+        line_number, location_str, first_words, cycles, "", text
+    )?;
+
+    for (i, chunk) in chunks.enumerate() {
+        let location_str = location
+            .map(|(segment, address)| {
+                let offset = (i + 1) * WORDS_PER_LINE;
+                format_location(segment, address.wrapping_add(offset as u16))
+            })
+            .unwrap_or_default();
+        let words = chunk.iter().map(|w| format!("{:04x}", w)).join(" ");
+        writeln!(out, "{:>5}  {:<7} {}", "", location_str, words)?;
+    }
+
+    Ok(())
+}
+
+/// Lazily built line indices of the source files
+struct Sources<'a> {
+    assembler: &'a Assembler,
+    indices: HashMap<FileId, LineIndex>,
+}
+
+impl<'a> Sources<'a> {
+    fn new(assembler: &'a Assembler) -> Self {
+        Sources {
+            assembler,
+            indices: HashMap::new(),
+        }
+    }
+
+    fn index(&mut self, file_id: FileId) -> &LineIndex {
+        self.indices.entry(file_id).or_insert_with(|| {
+            LineIndex::new(self.assembler.get_source(file_id).unwrap_or_default())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AssembleOptions;
+
+    #[test]
+    fn listing() {
+        let directory = std::env::temp_dir().join(format!("listing-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("data.bin"), [1, 2, 3, 4]).unwrap();
+
+        let mut assembler = Assembler::default();
+        let mut errors = Vec::new();
+        let source = "macro twice a {\n    add a, a, a\n    add a, a, a\n}\n\
+                      start:\n    ldi r1, 0x1234\n    twice! r2\n\
+                      .incbin \"data.bin\"\n";
+        assembler.add_source(directory.join("test.asm"), source.to_owned(), &mut errors);
+        let assembled = assembler.assemble(&AssembleOptions::default(), &mut errors);
+        let mut out = Vec::new();
+        write_listing(&mut out, &assembler, &assembled).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert!(errors.is_empty());
+        let listing = String::from_utf8(out).unwrap();
+        let expected = format!(
+            "; {}\n\
+             \x20line  address words          cyc  source\n\
+             \x20   1                              macro twice a {{\n\
+             \x20   2                                  add a, a, a\n\
+             \x20   3                                  add a, a, a\n\
+             \x20   4                              }}\n\
+             \x20   5  P:0000                      start:\n\
+             \x20   6  P:0000  1000 143a 1218   3      ldi r1, 0x1234\n\
+             \x20   7                                  twice! r2\n\
+             \x20      P:0003  2223             1      add a, a, a\n\
+             \x20      P:0004  2223             1      add a, a, a\n\
+             \x20   8  P:0005  0102 0304        0  .incbin \"data.bin\"\n\
+             \n\
+             ; Labels\n\
+             P:0000  <anonymous 0>.start\n\
+             \n\
+             ; Constants\n\
+             \n\
+             ; Included files\n\
+             P:0005       2  {}\n",
+            directory.join("test.asm").display(),
+            directory.join("data.bin").display(),
+        );
+        assert_eq!(listing, expected);
+    }
+
+    #[test]
+    fn listing_repeated_body() {
+        let mut assembler = Assembler::default();
+        let mut errors = Vec::new();
+        let source = ".for i in 0..3 {\n    nop\n    .dw i\n}\n.rept 2 {\n    add r1, r1, r1\n}\n";
+        assembler.add_source("test.asm", source.to_owned(), &mut errors);
+        let assembled = assembler.assemble(&AssembleOptions::default(), &mut errors);
+        let mut out = Vec::new();
+        write_listing(&mut out, &assembler, &assembled).unwrap();
+
+        assert!(errors.is_empty());
+        let listing = String::from_utf8(out).unwrap();
+        let expected = "; test.asm\n\
+                        \x20line  address words          cyc  source\n\
+                        \x20   1                              .for i in 0..3 {\n\
+                        \x20   2  P:0000  0003             1      nop\n\
+                        \x20      P:0002  0003             1      nop\n\
+                        \x20      P:0004  0003             1      nop\n\
+                        \x20   3  P:0001  0000             0      .dw i\n\
+                        \x20      P:0003  0001             0      .dw i\n\
+                        \x20      P:0005  0002             0      .dw i\n\
+                        \x20   4                              }\n\
+                        \x20   5                              .rept 2 {\n\
+                        \x20   6  P:0006  1113 1113        2      add r1, r1, r1\n\
+                        \x20   7                              }\n";
+        assert!(
+            listing.starts_with(expected),
+            "unexpected listing:\n{}",
+            listing
+        );
+    }
+}
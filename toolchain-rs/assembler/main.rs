@@ -1,22 +1,26 @@
-mod assembler;
-mod chumsky_util;
-mod lexer;
-mod parser;
-mod types;
-
-use ariadne::{Color, Label, Report, ReportKind};
 use clap::Parser as _;
 
-use std::{collections::HashMap, fs, io, path::PathBuf};
-
-use crate::{
-    assembler::Assembler,
-    types::{AssemblerError, FileId, Span},
+use pickle_assembler::{
+    assembler::{AssembleOptions, Assembler, Output},
+    depfile::write_depfile,
+    diagnostic::{AriadneCache, Locations, diagnostic},
+    types::AssemblerError,
+    warnings::{WarningFlag, WarningOptions},
 };
-
-// use assembler::{AsmResult, AssemblerState, files::InputFiles};
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+use toolchain_core::memory_layout::MemoryLayout;
 
 #[derive(clap::Parser, Debug)]
+#[command(
+    after_help = "Exit status is 0 on success (even with warnings), 1 if there were errors \
+                  and 2 if the command line is invalid."
+)]
 struct Cli {
     /// Paths to input assembler files
     input_files: Vec<PathBuf>,
@@ -24,160 +28,208 @@ struct Cli {
     /// Path to the output file
     #[arg(short, long)]
     output: Option<PathBuf>,
-}
 
-#[derive(Debug)]
-struct AriadneCache<'assembler> {
-    assembler: &'assembler Assembler,
-    cache: HashMap<FileId, ariadne::Source<String>>,
-    empty_source: ariadne::Source<String>,
+    /// Write a relocatable object file for the linker instead of an image
+    #[arg(short = 'c', long)]
+    object: bool,
+
+    /// Define a constant visible from all files, `NAME` alone is defined as 1
+    #[arg(short = 'D', value_name = "NAME=EXPR")]
+    define: Vec<String>,
+
+    /// Accept calling convention register names (`zero`, `ra`, `sp`, `a0`-`a3`, `t0`-`t3`,
+    /// `s0`-`s4`) in addition to `r0`-`r15`
+    #[arg(long)]
+    abi_names: bool,
+
+    /// Memory layout file with regions for the program and data segments
+    #[arg(long)]
+    layout: Option<PathBuf>,
+
+    /// Path to write the assembly listing to
+    #[arg(long)]
+    listing: Option<PathBuf>,
+
+    /// Path to write the symbol file (labels, constants and line table) to
+    #[arg(long)]
+    symbols: Option<PathBuf>,
+
+    /// Write a Makefile rule with all files read for the output, next to it with a `.d`
+    /// extension unless `-MF` is given
+    #[arg(long = "MD", requires = "output")]
+    dependencies: bool,
+
+    /// Path to write the Makefile dependency rule to, implies `-MD`
+    #[arg(long = "MF", value_name = "PATH", requires = "output")]
+    dependency_file: Option<PathBuf>,
+
+    /// Enable a warning class (`-Wunused-label`), disable it (`-Wno-unused-label`),
+    /// enable all of them (`-Wall`) or report warnings as errors (`-Werror`)
+    #[arg(short = 'W', value_name = "WARNING")]
+    warnings: Vec<WarningFlag>,
+
+    /// How to print errors and warnings: reports for people on stderr,
+    /// or one JSON record per line on stdout
+    #[arg(long, value_enum, default_value_t = MessageFormat::Human)]
+    message_format: MessageFormat,
 }
 
-impl<'assembler> ariadne::Cache<Option<FileId>> for AriadneCache<'assembler> {
-    type Storage = String;
-
-    fn fetch(
-        &mut self,
-        id: &Option<FileId>,
-    ) -> Result<&ariadne::Source<Self::Storage>, impl std::fmt::Debug> {
-        Ok::<_, io::Error>(if let Some(id) = id {
-            match self.cache.entry(*id) {
-                std::collections::hash_map::Entry::Occupied(occupied_entry) => {
-                    occupied_entry.into_mut()
-                }
-                std::collections::hash_map::Entry::Vacant(vacant_entry) => {
-                    let path = self
-                        .assembler
-                        .get_path(*id)
-                        .expect("It should be impossible to get an invalid FileId");
-                    let content = fs::read_to_string(path)?;
-                    vacant_entry.insert(content.into())
-                }
-            }
-        } else {
-            &self.empty_source
-        })
-    }
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum MessageFormat {
+    Human,
+    Json,
+}
 
-    fn display<'a>(&self, id: &'a Option<FileId>) -> Option<impl std::fmt::Display + 'a> {
-        if let Some(id) = id {
-            let path = self
-                .assembler
-                .get_path(*id)
-                .expect("It should be impossible to get an invalid FileId");
-            Some(format!("{}", path.display()))
-        } else {
-            Some("<no file>".to_string())
-        }
-    }
+/// Memory layout from the command line, the default one covering both segments if none
+/// was given
+fn memory_layout(cli: &Cli, errors: &mut Vec<AssemblerError>) -> MemoryLayout {
+    let Some(path) = &cli.layout else {
+        return MemoryLayout::default();
+    };
+    MemoryLayout::load(path).unwrap_or_else(|error| {
+        errors.push(AssemblerError::InvalidLayoutFile {
+            file_path: path.clone(),
+            message: error.to_string(),
+        });
+        MemoryLayout::default()
+    })
 }
 
-impl<'assembler> AriadneCache<'assembler> {
-    fn new(assembler: &Assembler) -> AriadneCache<'_> {
-        AriadneCache {
-            assembler,
-            cache: Default::default(),
-            empty_source: String::new().into(),
+/// Save the results of a successful build to the files requested on the command line
+fn write_outputs(cli: &Cli, output: &Output, errors: &mut Vec<AssemblerError>) {
+    let mut report = |path: &Path, result: Result<(), io::Error>| {
+        if let Err(error) = result {
+            errors.push(AssemblerError::FileWriteFailed {
+                file_path: path.to_owned(),
+                error,
+            });
         }
+    };
+
+    if let Some(path) = &cli.output {
+        let result = match (&output.image, &output.object) {
+            (Some(image), _) => image.save_ihex(path),
+            (None, Some(object)) => object.save(path),
+            (None, None) => Ok(()),
+        };
+        report(path, result.map_err(io::Error::other));
     }
-}
 
-impl ariadne::Span for &Span {
-    type SourceId = Option<FileId>;
+    if let Some(path) = &cli.symbols
+        && let Some(symbols) = &output.symbols
+    {
+        report(path, symbols.save(path).map_err(io::Error::other));
+    }
 
-    fn source(&self) -> &Self::SourceId {
-        &self.file_id
+    if let Some((path, target)) = dependency_file_path(cli) {
+        let dependencies: Vec<&Path> = output
+            .dependencies
+            .iter()
+            .map(PathBuf::as_path)
+            .chain(cli.layout.as_deref())
+            .collect();
+        let result = fs::File::create(&path).and_then(|file| {
+            let mut writer = io::BufWriter::new(file);
+            write_depfile(&mut writer, target, &dependencies)
+        });
+        report(&path, result);
     }
 
-    fn start(&self) -> usize {
-        self.start
+    if let Some(path) = &cli.listing
+        && let Some(listing) = &output.listing
+    {
+        report(path, fs::write(path, listing));
     }
+}
 
-    fn end(&self) -> usize {
-        self.end
+/// Where `-MD` and `-MF` ask for the dependency rule to be written, and the output
+/// it is written for
+fn dependency_file_path(cli: &Cli) -> Option<(PathBuf, &Path)> {
+    let output = cli.output.as_deref()?;
+    match &cli.dependency_file {
+        Some(path) => Some((path.clone(), output)),
+        None if cli.dependencies => Some((output.with_extension("d"), output)),
+        None => None,
     }
 }
 
-fn print_error(error: &'_ AssemblerError) -> ariadne::Report<'_, &'_ Span> {
-    match error {
-        AssemblerError::InvalidToken(err) | AssemblerError::SyntaxError(err) => {
-            let mut report = Report::build(ReportKind::Error, &err.span)
-                .with_message(format!(
-                    "syntax error: found {}",
-                    err.found.as_deref().unwrap_or("end of input")
-                ))
-                .with_label(
-                    Label::new(&err.span)
-                        .with_message("error occurred here")
-                        .with_color(Color::Red),
-                );
-
-            for (ctx, span) in err.context.iter() {
-                report = report.with_label(
-                    Label::new(span)
-                        .with_message(ctx.clone())
-                        .with_color(Color::Yellow),
-                );
-            }
+/// Accept the `-MD` and `-MF` spellings of C compilers, which clap only knows as long
+/// options
+fn compiler_style_args() -> impl Iterator<Item = OsString> {
+    std::env::args_os().map(|arg| match arg.to_str() {
+        Some("-MD") => "--MD".into(),
+        Some("-MF") => "--MF".into(),
+        Some(arg) if arg.starts_with("-MF") => format!("--MF={}", &arg[3..]).into(),
+        _ => arg,
+    })
+}
 
-            report
+/// Print all errors and warnings in the chosen format.
+/// Failures to print are ignored, there is nowhere left to report them.
+fn print_diagnostics(
+    assembler: &Assembler,
+    errors: &[AssemblerError],
+    warnings: &WarningOptions,
+    format: MessageFormat,
+) {
+    match format {
+        MessageFormat::Human => {
+            let mut sources = AriadneCache::new(assembler);
+            for error in errors {
+                let report = diagnostic(error, warnings.severity(error)).to_report();
+                let _ = report.eprint(&mut sources);
+            }
+        }
+        MessageFormat::Json => {
+            let mut locations = Locations::new(assembler);
+            let mut stdout = io::stdout().lock();
+            for error in errors {
+                let record = diagnostic(error, warnings.severity(error)).to_json(&mut locations);
+                let _ = writeln!(stdout, "{}", record);
+            }
         }
-
-        AssemblerError::NestedMacro {
-            span,
-            nested_in_name,
-            nested_in_span,
-        } => Report::build(ReportKind::Error, span)
-            .with_message(format!("nested macro inside `{}`", nested_in_name))
-            .with_label(
-                Label::new(span)
-                    .with_message("macro defined here")
-                    .with_color(Color::Red),
-            )
-            .with_label(
-                Label::new(nested_in_span)
-                    .with_message("enclosing macro")
-                    .with_color(Color::Yellow),
-            ),
-
-        AssemblerError::FileOpenFailed {
-            span,
-            file_path,
-            error,
-        } => Report::build(
-            ReportKind::Error,
-            span.as_ref().unwrap_or_else(|| &Span {
-                file_id: None,
-                start: 0,
-                end: 0,
-            }),
-        )
-        .with_message(format!(
-            "could not open file {}: {}",
-            file_path.display(),
-            error
-        )),
     }
-    .finish()
 }
 
-fn main() {
-    let cli = Cli::parse();
+fn main() -> ExitCode {
+    let cli = Cli::parse_from(compiler_style_args());
 
     let mut assembler = Assembler::default();
     let mut errors = Vec::new();
 
-    for file_name in cli.input_files {
-        let _ = assembler.add_file(file_name, None, &mut errors);
+    if !cli.define.is_empty() {
+        assembler.add_definitions(&cli.define, &mut errors);
     }
-
-    if errors.is_empty() {
-        dbg!(assembler.assemble());
+    for file_name in cli.input_files.iter() {
+        let _ = assembler.add_file(file_name.clone(), None, &mut errors);
     }
 
-    let mut sources = AriadneCache::new(&assembler);
-
-    for err in errors {
-        print_error(&err).eprint(&mut sources).unwrap();
+    let options = AssembleOptions {
+        relocatable: cli.object,
+        memory_layout: memory_layout(&cli, &mut errors),
+        abi_names: cli.abi_names,
+        warnings: WarningOptions::from_flags(&cli.warnings),
+        listing: cli.listing.is_some(),
+    };
+    let mut output = assembler.build(&options, errors);
+
+    let mut write_errors = Vec::new();
+    if !output.has_errors() {
+        write_outputs(&cli, &output, &mut write_errors);
+    }
+    let failed = output.has_errors() || !write_errors.is_empty();
+    output.errors.append(&mut write_errors);
+
+    print_diagnostics(
+        &assembler,
+        &output.errors,
+        &options.warnings,
+        cli.message_format,
+    );
+
+    if !failed {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
     }
 }
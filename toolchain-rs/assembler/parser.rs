@@ -1,7 +1,8 @@
 use chumsky::{
-    input::{BorrowInput, Input},
+    input::{Input, ValueInput},
     prelude::*,
 };
+use toolchain_core::image::Segment;
 
 use crate::{
     lexer::Token,
@@ -17,7 +18,7 @@ pub enum Item {
         content: Ast,
     },
     Instruction {
-        name: String,
+        name: Spanned<String>,
         args: Vec<Spanned<Expr>>,
     },
     MacroCall {
@@ -26,16 +27,160 @@ pub enum Item {
     },
     MacroDefinition {
         name: String,
-        params: Vec<String>,
+        params: Vec<MacroParam>,
         body: Ast,
     },
     Label {
         name: String,
     },
+    /// Numeric label (`1:`) that can be defined many times, referenced by `1b` and `1f`
+    LocalLabel {
+        number: i64,
+    },
     Const {
         name: String,
         value: Spanned<Expr>,
     },
+    /// `.reg`, alternative name for a register
+    RegisterAlias {
+        name: String,
+        value: Spanned<Expr>,
+    },
+    /// `.contract`, applies to the macro definition or label that follows
+    Contract {
+        contract: Contract,
+    },
+    /// `.assert`, condition checked after layout
+    Assert {
+        condition: Spanned<Expr>,
+        message: Option<String>,
+    },
+    /// `.struct`, defines constants for offsets of fields with the given sizes in words
+    Struct {
+        name: String,
+        fields: Vec<(Spanned<String>, Spanned<Expr>)>,
+    },
+    /// `.jumptable index, tmp { case: target, ... }`, jumps to the target of the case
+    /// equal to the index register. With a `default` target, indices without a case
+    /// (including out of range ones) jump there.
+    /// The bounds check needs a place to go for out of range indices, so it is emitted
+    /// exactly when there is a `default`: to fall through, the default names a label
+    /// right after the table, to trap it names a `break`. Without it the index must
+    /// be in range.
+    JumpTable {
+        index: Spanned<Expr>,
+        tmp: Spanned<Expr>,
+        cases: Vec<(Spanned<Expr>, Spanned<Expr>)>,
+        default: Option<Spanned<Expr>>,
+    },
+    /// `.pool`, places the literals of preceding `ldi rd, =expr` instructions here
+    Pool,
+    /// `.dw`, data words placed directly into the output
+    Words {
+        values: Vec<Spanned<Expr>>,
+    },
+    /// `.incbin "file", offset, length` packs bytes of a file two to a word (big-endian,
+    /// an odd last byte padded with zero), `.incbinw` reads it as big-endian words.
+    /// The offset and length count bytes or words respectively, the length defaults to
+    /// the rest of the file.
+    IncludeBinary {
+        path: Spanned<String>,
+        offset: Option<Spanned<Expr>>,
+        length: Option<Spanned<Expr>>,
+        words: bool,
+    },
+    /// `.export`, labels made visible to other object files
+    Export {
+        names: Vec<Spanned<String>>,
+    },
+    /// `.import`, symbols defined in other object files
+    Import {
+        names: Vec<Spanned<String>>,
+    },
+    /// `.org`, moves the current address of the segment
+    Org {
+        address: Spanned<Expr>,
+    },
+    /// `.align`, pads with `fill` (zero by default) up to a multiple of `alignment` words
+    Align {
+        alignment: Spanned<Expr>,
+        fill: Option<Spanned<Expr>>,
+    },
+    /// `.space`, reserves `size` words filled with `fill` (zero by default)
+    Space {
+        size: Spanned<Expr>,
+        fill: Option<Spanned<Expr>>,
+    },
+    /// `.program` or `.data`, following items are placed into this segment
+    Segment {
+        segment: Segment,
+    },
+    /// `.if`, `.elif` and `.else` chain, the body of the first branch with a non-zero
+    /// condition is assembled in the enclosing scope
+    If {
+        branches: Vec<(Spanned<Expr>, Ast)>,
+        else_body: Option<Ast>,
+    },
+    /// `.rept`, body assembled `count` times
+    Repeat {
+        count: Spanned<Expr>,
+        body: Ast,
+    },
+    /// `.for counter in start..end`, body assembled once for each value of the counter
+    For {
+        counter: Spanned<String>,
+        start: Spanned<Expr>,
+        end: Spanned<Expr>,
+        body: Ast,
+    },
+}
+
+/// `name: kind = default` in a macro definition, or `name...: kind` for a variadic parameter
+#[derive(Debug, Clone, PartialEq)]
+pub struct MacroParam {
+    pub name: Spanned<String>,
+    pub kind: ParamKind,
+    pub default: Option<Spanned<Expr>>,
+    /// Collects all remaining arguments, used as `name[i]` and `len(name)`
+    pub variadic: bool,
+}
+
+/// Registers that a macro or routine reads and writes,
+/// listed as `.contract in(...) out(...) clobbers(...)`.
+/// The name `C` stands for the carry flag.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Contract {
+    /// Defined on entry
+    pub inputs: Vec<Spanned<Expr>>,
+    /// Written for the caller
+    pub outputs: Vec<Spanned<Expr>>,
+    /// Written, but with no meaningful value on exit
+    pub clobbers: Vec<Spanned<Expr>>,
+}
+
+/// Kind of value a macro parameter accepts, checked at every call
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParamKind {
+    /// `reg`
+    Reg,
+    /// `imm`, number or address
+    Imm,
+    /// `label`, name of a label
+    Label,
+    /// `expr`, anything (the default)
+    Expr,
+}
+
+impl ParamKind {
+    /// What the kind expects, for error messages
+    pub fn description(&self) -> &'static str {
+        match self {
+            ParamKind::Reg => "a register",
+            ParamKind::Imm => "an immediate value",
+            ParamKind::Label => "a label",
+            ParamKind::Expr => "an expression",
+        }
+    }
 }
 
 /// Expressions for instruction arguments and constants
@@ -43,6 +188,29 @@ pub enum Item {
 pub enum Expr {
     Number(i64),
     QualifiedName(Vec<Spanned<String>>),
+    /// `1b` or `1f`, closest local label with the number before or after the reference
+    LocalLabel {
+        number: i64,
+        forward: bool,
+    },
+    /// `defined(name)`, 1 if the name refers to a symbol, 0 otherwise
+    Defined(Vec<Spanned<String>>),
+    /// `sizeof(name)`, size in words of a labelled scope
+    SizeOf(Vec<Spanned<String>>),
+    /// `len(name)`, number of arguments collected by a variadic macro parameter
+    Len(Spanned<String>),
+    /// `name[index]`, single argument collected by a variadic macro parameter
+    Index {
+        name: Spanned<String>,
+        index: Box<Spanned<Expr>>,
+    },
+    /// `=expr` operand of `ldi`, value loaded from a literal pool in program memory
+    PoolLiteral(Box<Spanned<Expr>>),
+    /// Built-in function of a single value
+    Function {
+        function: Function,
+        arg: Box<Spanned<Expr>>,
+    },
     BinaryOp {
         op: BinOp,
         lhs: Box<Spanned<Expr>>,
@@ -83,6 +251,32 @@ pub enum UnOp {
     BitNot,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Function {
+    /// `hi(x)`, upper byte of a word
+    Hi,
+    /// `lo(x)`, lower byte of a word
+    Lo,
+    /// `page(x)`, 6 bit page number of a virtual address
+    Page,
+    /// `offset(x)`, 10 bit offset of a virtual address within its page
+    Offset,
+    /// `bptr(x)`, extended pointer to the first byte of a word (`x * 2`)
+    Bptr,
+}
+
+impl Function {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Function::Hi => "hi",
+            Function::Lo => "lo",
+            Function::Page => "page",
+            Function::Offset => "offset",
+            Function::Bptr => "bptr",
+        }
+    }
+}
+
 pub fn parse<'src>(
     tokens: &'src [Spanned<Token<'src>>],
     file_id: Option<FileId>,
@@ -97,22 +291,87 @@ pub fn parse<'src>(
         },
         |(token, span)| (token, span),
     );
-    let (ast, parse_errors) = parser().parse(input).into_output_errors();
+    // Closing braces without a matching opening one are reported and skipped
+    let unmatched_brace = just(Token::RBrace).validate(|_, e, emitter| {
+        emitter.emit(Rich::custom(e.span(), "unmatched closing brace"));
+    });
+    let (ast, parse_errors) = parser()
+        .separated_by(unmatched_brace)
+        .collect::<Vec<_>>()
+        .map(|parts| parts.into_iter().flatten().collect())
+        .parse(input)
+        .into_output_errors();
     errors.extend(
         parse_errors
             .into_iter()
-            .map(|e| AssemblerError::SyntaxError(e.into())),
+            .map(|e| AssemblerError::SyntaxError(Box::new(e.into()))),
     );
     ast
 }
 
+/// Parse `NAME=EXPR` definitions given on the command line, one per line, into constants.
+pub fn parse_definitions<'src>(
+    tokens: &'src [Spanned<Token<'src>>],
+    file_id: Option<FileId>,
+    input_len: usize,
+    errors: &mut Vec<AssemblerError>,
+) -> Option<Ast> {
+    let input = tokens.map(
+        Span {
+            file_id,
+            start: input_len,
+            end: input_len,
+        },
+        |(token, span)| (token, span),
+    );
+    let (ast, parse_errors) = definitions_parser().parse(input).into_output_errors();
+    errors.extend(
+        parse_errors
+            .into_iter()
+            .map(|e| AssemblerError::SyntaxError(Box::new(e.into()))),
+    );
+    ast
+}
+
+fn definitions_parser<'tokens, 'src: 'tokens, I>()
+-> impl Parser<'tokens, I, Ast, extra::Err<Rich<'tokens, Token<'src>, Span>>>
+where
+    I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+    select! { Token::Identifier(name) => name.to_owned() }
+        .then_ignore(just(Token::Equal))
+        .then(expression_parser())
+        .map(|(name, value)| Item::Const { name, value })
+        .labelled("definition")
+        .map_with(|item, e| (item, e.span()))
+        .separated_by(just(Token::Eol))
+        .allow_trailing()
+        .collect()
+}
+
 fn parser<'tokens, 'src: 'tokens, I>()
 -> impl Parser<'tokens, I, Ast, extra::Err<Rich<'tokens, Token<'src>, Span>>>
 where
-    I: BorrowInput<'tokens, Token = Token<'src>, Span = Span>,
+    I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
 {
     recursive(|ast| {
         let identifier = select! { Token::Identifier(name) => name }.map(ToOwned::to_owned);
+
+        // Invalid items are skipped up to the end of the line, or past the block they
+        // open, so that errors in the following items get reported too
+        let block = recursive(|block| {
+            just(Token::LBrace)
+                .then(choice((block, none_of([Token::LBrace, Token::RBrace]).ignored())).repeated())
+                .then(just(Token::RBrace).ignored().or(end()))
+                .ignored()
+        });
+        let skip_item = choice((
+            block,
+            none_of([Token::Eol, Token::LBrace, Token::RBrace]).ignored(),
+        ))
+        .repeated()
+        .at_least(1);
+
         let scoped_ast = ast.delimited_by(just(Token::LBrace), just(Token::RBrace));
 
         let label_name = identifier.then_ignore(just(Token::Colon));
@@ -128,22 +387,35 @@ where
 
         let expression = expression_parser();
 
+        let end_of_item = choice((
+            just(Token::Eol).ignored(),
+            just(Token::RBrace).rewind().ignored(),
+            end().ignored(),
+        ));
         let instruction_tail = expression
             .clone()
             .separated_by(just(Token::Comma))
             .collect()
-            .then_ignore(choice((
-                just(Token::Eol).ignored(),
-                just(Token::RBrace).rewind().ignored(),
-                end().ignored(),
-            )));
+            .then_ignore(end_of_item.clone());
+        let instruction_operand = choice((
+            just(Token::Equal)
+                .ignore_then(expression.clone())
+                .map_with(|value, e| (Expr::PoolLiteral(Box::new(value)), e.span())),
+            expression.clone(),
+        ));
         let instruction = identifier
-            .then(instruction_tail.clone())
+            .map_with(|name, e| (name, e.span()))
+            .then(
+                instruction_operand
+                    .separated_by(just(Token::Comma))
+                    .collect()
+                    .then_ignore(end_of_item.clone()),
+            )
             .map(|(name, args)| Item::Instruction { name, args })
             .labelled("instruction")
             .as_context();
         let macro_call = select! { Token::MacroCall(name) => name }
-            .then(instruction_tail)
+            .then(instruction_tail.clone())
             .map(|(name, args)| Item::MacroCall {
                 name: name.to_owned(),
                 args,
@@ -151,12 +423,54 @@ where
             .labelled("macro call")
             .as_context();
 
+        let param_kind =
+            select! { Token::Identifier(name) => name }.try_map(|name, span| match name {
+                "reg" => Ok(ParamKind::Reg),
+                "imm" => Ok(ParamKind::Imm),
+                "label" => Ok(ParamKind::Label),
+                "expr" => Ok(ParamKind::Expr),
+                _ => Err(Rich::custom(
+                    span,
+                    format!(
+                        "unknown parameter kind `{name}`, expected `reg`, `imm`, `label` or `expr`"
+                    ),
+                )),
+            });
+        let macro_param = identifier
+            .map_with(|name, e| (name, e.span()))
+            .then(just(Token::Ellipsis).or_not().map(|x| x.is_some()))
+            .then(just(Token::Colon).ignore_then(param_kind).or_not())
+            .then(just(Token::Equal).ignore_then(expression.clone()).or_not())
+            .map(|(((name, variadic), kind), default)| MacroParam {
+                name,
+                kind: kind.unwrap_or(ParamKind::Expr),
+                default,
+                variadic,
+            });
+        let macro_params = macro_param
+            .separated_by(just(Token::Comma))
+            .collect::<Vec<_>>()
+            .validate(|params, _, emitter| {
+                for (i, param) in params.iter().enumerate() {
+                    let message = if param.variadic && i + 1 < params.len() {
+                        "variadic parameter must be the last one"
+                    } else if param.variadic && param.default.is_some() {
+                        "variadic parameter cannot have a default value"
+                    } else if !param.variadic
+                        && param.default.is_none()
+                        && params[..i].iter().any(|p| p.default.is_some())
+                    {
+                        "parameters following one with a default value need a default too"
+                    } else {
+                        continue;
+                    };
+                    emitter.emit(Rich::custom(param.name.1.clone(), message));
+                }
+                params
+            });
+
         let macro_def = just(Token::Macro)
-            .ignore_then(group((
-                identifier,
-                identifier.separated_by(just(Token::Comma)).collect(),
-                scoped_ast,
-            )))
+            .ignore_then(group((identifier, macro_params, scoped_ast.clone())))
             .map(|(name, params, body)| Item::MacroDefinition { name, params, body })
             .labelled("macro definition")
             .as_context();
@@ -164,27 +478,289 @@ where
         let label = label_name
             .map(|name| Item::Label { name })
             .labelled("label definition");
+        let local_label = select! { Token::Number(number) => number }
+            .then_ignore(just(Token::Colon))
+            .map(|number| Item::LocalLabel { number })
+            .labelled("local label definition");
 
         let constant = just(Token::Const)
             .ignore_then(identifier)
             .then_ignore(just(Token::DoubleEqual))
-            .then(expression)
+            .then(expression.clone())
             .map(|(name, value)| Item::Const { name, value })
             .labelled("constant definition");
 
+        let directive = |name| just(Token::Dot).then(just(Token::Identifier(name)));
+        let name_list = identifier
+            .map_with(|name, e| (name, e.span()))
+            .separated_by(just(Token::Comma))
+            .at_least(1)
+            .collect()
+            .then_ignore(end_of_item.clone());
+
+        let register_alias = directive("reg")
+            .ignore_then(identifier)
+            .then_ignore(just(Token::Equal))
+            .then(expression.clone())
+            .map(|(name, value)| Item::RegisterAlias { name, value })
+            .labelled("register alias");
+
+        let words = directive("dw")
+            .ignore_then(instruction_tail.clone())
+            .map(|values| Item::Words { values })
+            .labelled("data words");
+        let include_binary = choice((directive("incbin").to(false), directive("incbinw").to(true)))
+            .then(select! { Token::String(s) => s.to_owned() }.map_with(|path, e| (path, e.span())))
+            .then(
+                just(Token::Comma)
+                    .ignore_then(expression.clone())
+                    .then(just(Token::Comma).ignore_then(expression.clone()).or_not())
+                    .or_not(),
+            )
+            .then_ignore(end_of_item.clone())
+            .map(|((words, path), range)| {
+                let (offset, length) =
+                    range.map_or((None, None), |(offset, length)| (Some(offset), length));
+                Item::IncludeBinary {
+                    path,
+                    offset,
+                    length,
+                    words,
+                }
+            })
+            .labelled("binary include");
+        let export = directive("export")
+            .ignore_then(name_list.clone())
+            .map(|names| Item::Export { names })
+            .labelled("export");
+        let import = directive("import")
+            .ignore_then(name_list)
+            .map(|names| Item::Import { names })
+            .labelled("import");
+
+        let optional_fill = just(Token::Comma)
+            .ignore_then(expression.clone())
+            .or_not()
+            .then_ignore(end_of_item.clone());
+        let org = directive("org")
+            .ignore_then(expression.clone())
+            .then_ignore(end_of_item.clone())
+            .map(|address| Item::Org { address })
+            .labelled("origin");
+        let align = directive("align")
+            .ignore_then(expression.clone())
+            .then(optional_fill.clone())
+            .map(|(alignment, fill)| Item::Align { alignment, fill })
+            .labelled("alignment");
+        let space = directive("space")
+            .ignore_then(expression.clone())
+            .then(optional_fill)
+            .map(|(size, fill)| Item::Space { size, fill })
+            .labelled("reserved space");
+        let pool = directive("pool")
+            .then_ignore(end_of_item.clone())
+            .to(Item::Pool)
+            .labelled("literal pool");
+        let assert = directive("assert")
+            .ignore_then(expression.clone())
+            .then(
+                just(Token::Comma)
+                    .ignore_then(select! { Token::String(s) => s.to_owned() })
+                    .or_not(),
+            )
+            .then_ignore(end_of_item.clone())
+            .map(|(condition, message)| Item::Assert { condition, message })
+            .labelled("assertion");
+
+        let field_separator = choice((just(Token::Comma), just(Token::Eol)))
+            .repeated()
+            .at_least(1);
+        let struct_def = directive("struct")
+            .ignore_then(identifier)
+            .then(
+                identifier
+                    .map_with(|name, e| (name, e.span()))
+                    .then_ignore(just(Token::Colon))
+                    .then(expression.clone())
+                    .separated_by(field_separator.clone())
+                    .allow_leading()
+                    .allow_trailing()
+                    .collect()
+                    .delimited_by(
+                        just(Token::LBrace).then(field_separator.clone().or_not()),
+                        just(Token::RBrace),
+                    ),
+            )
+            .map(|(name, fields)| Item::Struct { name, fields })
+            .labelled("struct definition")
+            .as_context();
+
+        let jump_table = directive("jumptable")
+            .ignore_then(expression.clone())
+            .then_ignore(just(Token::Comma))
+            .then(expression.clone())
+            .then(
+                choice((
+                    just(Token::Identifier("default")).to(None),
+                    expression.clone().map(Some),
+                ))
+                .map_with(|case, e| (case, e.span()))
+                .then_ignore(just(Token::Colon))
+                .then(expression.clone())
+                .separated_by(field_separator.clone())
+                .allow_leading()
+                .allow_trailing()
+                .collect::<Vec<_>>()
+                .delimited_by(
+                    just(Token::LBrace).then(field_separator.or_not()),
+                    just(Token::RBrace),
+                ),
+            )
+            .validate(|((index, tmp), entries), _, emitter| {
+                let mut cases = Vec::new();
+                let mut default = None;
+                for ((case, case_span), target) in entries {
+                    match case {
+                        Some(case) => cases.push((case, target)),
+                        None if default.is_some() => emitter.emit(Rich::custom(
+                            case_span,
+                            "jump table can only have one default target",
+                        )),
+                        None => default = Some(target),
+                    }
+                }
+                Item::JumpTable {
+                    index,
+                    tmp,
+                    cases,
+                    default,
+                }
+            })
+            .labelled("jump table")
+            .as_context();
+
+        let register_list = expression
+            .clone()
+            .separated_by(just(Token::Comma))
+            .collect::<Vec<_>>()
+            .delimited_by(just(Token::LParen), just(Token::RParen));
+        let contract = directive("contract")
+            .ignore_then(
+                choice((
+                    just(Token::Identifier("in")),
+                    just(Token::Identifier("out")),
+                    just(Token::Identifier("clobbers")),
+                ))
+                .then(register_list)
+                .repeated()
+                .collect::<Vec<_>>(),
+            )
+            .then_ignore(end_of_item.clone())
+            .map(|lists| {
+                let mut contract = Contract::default();
+                for (list_name, registers) in lists {
+                    match list_name {
+                        Token::Identifier("in") => contract.inputs.extend(registers),
+                        Token::Identifier("out") => contract.outputs.extend(registers),
+                        _ => contract.clobbers.extend(registers),
+                    }
+                }
+                Item::Contract { contract }
+            })
+            .labelled("contract");
+
+        let segment = choice((
+            directive("program").to(Segment::Program),
+            directive("data").to(Segment::Data),
+        ))
+        .then_ignore(end_of_item)
+        .map(|segment| Item::Segment { segment })
+        .labelled("segment");
+
+        let conditional_body = expression.clone().then(scoped_ast.clone());
+        let if_block = directive("if")
+            .ignore_then(conditional_body.clone())
+            .then(
+                just(Token::Eol)
+                    .repeated()
+                    .ignore_then(directive("elif"))
+                    .ignore_then(conditional_body)
+                    .repeated()
+                    .collect::<Vec<_>>(),
+            )
+            .then(
+                just(Token::Eol)
+                    .repeated()
+                    .ignore_then(directive("else"))
+                    .ignore_then(scoped_ast.clone())
+                    .or_not(),
+            )
+            .map(|((first, mut rest), else_body)| {
+                rest.insert(0, first);
+                Item::If {
+                    branches: rest,
+                    else_body,
+                }
+            })
+            .labelled("conditional block")
+            .as_context();
+        let repeat = directive("rept")
+            .ignore_then(expression.clone())
+            .then(scoped_ast.clone())
+            .map(|(count, body)| Item::Repeat { count, body })
+            .labelled("repeat block")
+            .as_context();
+        let for_loop = directive("for")
+            .ignore_then(identifier.map_with(|name, e| (name, e.span())))
+            .then_ignore(just(Token::Identifier("in")))
+            .then(expression.clone())
+            .then_ignore(just(Token::DoubleDot))
+            .then(expression.clone())
+            .then(scoped_ast)
+            .map(|(((counter, start), end), body)| Item::For {
+                counter,
+                start,
+                end,
+                body,
+            })
+            .labelled("for loop")
+            .as_context();
+
         just(Token::Eol)
             .repeated()
-            .ignore_then(choice((
-                scope,
-                instruction,
-                macro_call,
-                macro_def,
-                label,
-                constant,
-            )))
-            .map_with(|item, e| (item, e.span()))
+            .ignore_then(
+                choice((
+                    scope,
+                    instruction,
+                    macro_call,
+                    macro_def,
+                    label,
+                    local_label,
+                    constant,
+                    register_alias,
+                    contract,
+                    assert,
+                    struct_def,
+                    pool,
+                    jump_table,
+                    words,
+                    include_binary,
+                    export,
+                    import,
+                    org,
+                    align,
+                    space,
+                    segment,
+                    if_block,
+                    repeat,
+                    for_loop,
+                ))
+                .map_with(|item, e| Some((item, e.span())))
+                .recover_with(via_parser(skip_item.to(None))),
+            )
             .repeated()
-            .collect()
+            .collect::<Vec<_>>()
+            .map(|items| items.into_iter().flatten().collect())
             .then_ignore(just(Token::Eol).repeated())
     })
 }
@@ -192,16 +768,86 @@ where
 fn expression_parser<'tokens, 'src: 'tokens, I>()
 -> impl Parser<'tokens, I, Spanned<Expr>, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
 where
-    I: BorrowInput<'tokens, Token = Token<'src>, Span = Span>,
+    I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
 {
     recursive(|expression| {
+        let names = select! { Token::Identifier(name) => name }
+            .map_with(|name, e| (name.to_owned(), e.span()))
+            .separated_by(just(Token::Dot))
+            .at_least(1)
+            .collect();
         let atom = choice((
             select! { Token::Number(i) => i }.map_with(|i, e| (Expr::Number(i), e.span())),
-            select! { Token::Identifier(name) => name }
-                .map_with(|name, e| (name.to_owned(), e.span()))
-                .separated_by(just(Token::Dot))
-                .at_least(1)
-                .collect()
+            select! {
+                Token::LocalLabelBackward(number) => Expr::LocalLabel { number, forward: false },
+                Token::LocalLabelForward(number) => Expr::LocalLabel { number, forward: true },
+            }
+            .map_with(|expr, e| (expr, e.span()))
+            .labelled("local label reference"),
+            just(Token::Identifier("defined"))
+                .ignore_then(
+                    names
+                        .clone()
+                        .delimited_by(just(Token::LParen), just(Token::RParen)),
+                )
+                .map_with(|names, e| (Expr::Defined(names), e.span()))
+                .labelled("defined()"),
+            just(Token::Identifier("len"))
+                .ignore_then(
+                    select! { Token::Identifier(name) => name.to_owned() }
+                        .map_with(|name, e| (name, e.span()))
+                        .delimited_by(just(Token::LParen), just(Token::RParen)),
+                )
+                .map_with(|name, e| (Expr::Len(name), e.span()))
+                .labelled("len()"),
+            select! { Token::Identifier(name) => name.to_owned() }
+                .map_with(|name, e| (name, e.span()))
+                .then(
+                    expression
+                        .clone()
+                        .delimited_by(just(Token::LBracket), just(Token::RBracket)),
+                )
+                .map_with(|(name, index), e| {
+                    (
+                        Expr::Index {
+                            name,
+                            index: Box::new(index),
+                        },
+                        e.span(),
+                    )
+                })
+                .labelled("variadic argument"),
+            just(Token::Identifier("sizeof"))
+                .ignore_then(
+                    names
+                        .clone()
+                        .delimited_by(just(Token::LParen), just(Token::RParen)),
+                )
+                .map_with(|names, e| (Expr::SizeOf(names), e.span()))
+                .labelled("sizeof()"),
+            choice((
+                just(Token::Identifier("hi")).to(Function::Hi),
+                just(Token::Identifier("lo")).to(Function::Lo),
+                just(Token::Identifier("page")).to(Function::Page),
+                just(Token::Identifier("offset")).to(Function::Offset),
+                just(Token::Identifier("bptr")).to(Function::Bptr),
+            ))
+            .then(
+                expression
+                    .clone()
+                    .delimited_by(just(Token::LParen), just(Token::RParen)),
+            )
+            .map_with(|(function, arg), e| {
+                (
+                    Expr::Function {
+                        function,
+                        arg: Box::new(arg),
+                    },
+                    e.span(),
+                )
+            })
+            .labelled("function call"),
+            names
                 .map_with(|names, e| (Expr::QualifiedName(names), e.span()))
                 .labelled("qualified name")
                 .as_context(),
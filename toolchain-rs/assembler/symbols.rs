@@ -0,0 +1,141 @@
+//! Conversion of the assembly result to the symbol file format from `toolchain_core`.
+
+use std::collections::HashMap;
+
+use toolchain_core::debug_info::{ConstantInfo, DebugInfo, LabelInfo, LineEntry, SourceLocation};
+
+use crate::{
+    assembler::{Assembled, Assembler},
+    types::{ColumnUnit, FileId, LineIndex, Span},
+};
+
+pub fn debug_info(assembler: &Assembler, assembled: &Assembled) -> DebugInfo {
+    let locations = Locations::new(assembler);
+
+    let labels = assembled
+        .labels
+        .iter_sorted()
+        .map(|(name, label)| LabelInfo {
+            name: name.to_string(),
+            segment: label.segment,
+            address: label.address,
+            location: locations.get(&label.span),
+        })
+        .collect();
+
+    let constants = assembled
+        .constants
+        .iter_sorted()
+        .map(|(name, constant)| ConstantInfo {
+            name: name.to_string(),
+            value: constant.value,
+            location: locations.get(&constant.span),
+        })
+        .collect();
+
+    let mut lines: Vec<LineEntry> = assembled
+        .fragments
+        .iter()
+        .filter(|fragment| !fragment.words.is_empty())
+        .map(|fragment| LineEntry {
+            segment: fragment.segment,
+            address: fragment.address,
+            size: fragment.words.len() as u16,
+            location: locations.get_placed(&fragment.span),
+            expansion: fragment
+                .expansion
+                .iter()
+                .map(|span| locations.get_placed(span))
+                .collect(),
+        })
+        .collect();
+    lines.sort_by_key(|entry| (entry.segment, entry.address));
+
+    DebugInfo {
+        files: assembler
+            .file_ids()
+            .map(|file_id| assembler.get_path(file_id).unwrap().to_owned())
+            .collect(),
+        labels,
+        constants,
+        lines,
+    }
+}
+
+/// Converts spans to line and column positions
+struct Locations<'a> {
+    assembler: &'a Assembler,
+    /// File index in the symbol file and line index for each source file
+    files: HashMap<FileId, (usize, LineIndex)>,
+}
+
+impl<'a> Locations<'a> {
+    fn new(assembler: &'a Assembler) -> Self {
+        let files = assembler
+            .file_ids()
+            .enumerate()
+            .map(|(i, file_id)| {
+                let index = LineIndex::new(assembler.get_source(file_id).unwrap());
+                (file_id, (i, index))
+            })
+            .collect();
+        Locations { assembler, files }
+    }
+
+    /// Location of a span of the assembled code, which always comes from one of the files
+    fn get_placed(&self, span: &Span) -> SourceLocation {
+        self.get(span)
+            .expect("assembled code comes from the files of the assembler")
+    }
+
+    fn get(&self, span: &Span) -> Option<SourceLocation> {
+        let file_id = span.file_id?;
+        let (file, index) = self.files.get(&file_id)?;
+        let source = self.assembler.get_source(file_id)?;
+
+        let (line, column) = index.position(source, span.start, ColumnUnit::Char);
+
+        Some(SourceLocation {
+            file: *file,
+            line: line as u32 + 1,
+            column: column as u32 + 1,
+            start: span.start,
+            end: span.end,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use toolchain_core::image::Segment;
+
+    use super::*;
+    use crate::AssembleOptions;
+
+    #[test]
+    fn macro_expansion_lines() {
+        let mut assembler = Assembler::default();
+        let mut errors = Vec::new();
+        let source = "macro twice a {\n    add a, a, a\n    add a, a, a\n}\n\
+                      start:\n    nop\n    twice! r2\n";
+        assembler.add_source("test.asm", source.to_owned(), &mut errors);
+        let assembled = assembler.assemble(&AssembleOptions::default(), &mut errors);
+        assert!(errors.is_empty());
+        let info = debug_info(&assembler, &assembled);
+
+        assert_eq!(info.lines.len(), 3);
+        let nop = info.line_at(Segment::Program, 0).unwrap();
+        assert_eq!(nop.location.line, 6);
+        assert!(nop.expansion.is_empty());
+
+        let second_add = info.line_at(Segment::Program, 2).unwrap();
+        assert_eq!(second_add.location.line, 3);
+        let call: Vec<_> = second_add
+            .expansion
+            .iter()
+            .map(|location| (location.line, location.column))
+            .collect();
+        assert_eq!(call, [(7, 5)]);
+        assert!(info.line_at(Segment::Program, 3).is_none());
+    }
+}
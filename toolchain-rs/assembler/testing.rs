@@ -0,0 +1,63 @@
+//! Assembling snippets in tests.
+
+use toolchain_core::image::Segment;
+
+use crate::{
+    AssembleOptions, Assembled, Assembler, AssemblerError, Severity, WarningOptions,
+    warnings::WarningFlag,
+};
+
+/// Assemble a single source, returning the result and all errors and warnings
+pub fn assemble_with(source: &str, options: &AssembleOptions) -> (Assembled, Vec<AssemblerError>) {
+    let mut assembler = Assembler::default();
+    let mut errors = Vec::new();
+    assembler.add_source("test.asm", source.to_owned(), &mut errors);
+    let assembled = assembler.assemble(options, &mut errors);
+    (assembled, errors)
+}
+
+/// Words of the program segment in the order of addresses, panics on errors
+pub fn program(source: &str) -> Vec<u16> {
+    let options = AssembleOptions::default();
+    let (assembled, errors) = assemble_with(source, &options);
+    let fatal: Vec<_> = errors
+        .iter()
+        .filter(|error| options.warnings.severity(error) == Severity::Error)
+        .collect();
+    assert!(fatal.is_empty(), "{:?}", fatal);
+
+    let mut fragments: Vec<_> = assembled
+        .fragments
+        .iter()
+        .filter(|fragment| fragment.segment == Segment::Program)
+        .collect();
+    fragments.sort_by_key(|fragment| fragment.address);
+    fragments
+        .iter()
+        .flat_map(|fragment| fragment.words.iter().copied())
+        .collect()
+}
+
+/// All errors and warnings reported for a source
+pub fn errors(source: &str) -> Vec<AssemblerError> {
+    assemble_with(source, &AssembleOptions::default()).1
+}
+
+/// Codes of all errors and warnings, in the order they were reported
+pub fn codes(source: &str) -> Vec<&'static str> {
+    codes_with_flags(source, &[])
+}
+
+/// Codes of all errors and warnings reported with the given `-W` flags
+pub fn codes_with_flags(source: &str, flags: &[&str]) -> Vec<&'static str> {
+    let flags: Vec<WarningFlag> = flags.iter().map(|flag| flag.parse().unwrap()).collect();
+    let options = AssembleOptions {
+        warnings: WarningOptions::from_flags(&flags),
+        ..Default::default()
+    };
+    assemble_with(source, &options)
+        .1
+        .iter()
+        .map(<&str>::from)
+        .collect()
+}
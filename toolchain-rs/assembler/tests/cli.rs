@@ -0,0 +1,67 @@
+//! Runs the assembler binary to check its exit status and machine readable output.
+use std::{
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+/// Assemble a source written to a temporary file with extra arguments
+fn run(name: &str, source: &str, args: &[&str]) -> Output {
+    let directory = std::env::temp_dir().join(format!("cli-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let input: PathBuf = directory.join("test.asm");
+    std::fs::write(&input, source).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_assembler"))
+        .arg(&input)
+        .arg("-o")
+        .arg(directory.join("test.hex"))
+        .args(args)
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&directory).unwrap();
+    output
+}
+
+#[test]
+fn success() {
+    let output = run("success", "nop\n", &[]);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn warnings_succeed() {
+    let output = run("warnings", "bz r1, r2\n.dw 5\n", &[]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(!output.stderr.is_empty());
+}
+
+#[test]
+fn warnings_as_errors_fail() {
+    let output = run("werror", "bz r1, r2\n.dw 5\n", &["-Werror"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn errors_fail() {
+    let output = run(
+        "errors",
+        "add r1, r2, missing\n",
+        &["--message-format", "json"],
+    );
+    assert_eq!(output.status.code(), Some(1));
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let records: Vec<serde_json::Value> = stdout
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["severity"], "error");
+    assert_eq!(records[0]["code"], "undefined-name");
+}
+
+#[test]
+fn invalid_command_line() {
+    let output = run("usage", "nop\n", &["-Wno-such-warning"]);
+    assert_eq!(output.status.code(), Some(2));
+}
@@ -1,10 +1,12 @@
 use std::{io, path::PathBuf};
 
-use crate::assembler::QualifiedName;
+use toolchain_core::image::Segment;
+
+use crate::{assembler::QualifiedName, parser::ParamKind};
 
 pub use crate::assembler::FileId;
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Span {
     pub file_id: Option<FileId>,
     pub start: usize,
@@ -13,18 +15,167 @@ pub struct Span {
 
 pub type Spanned<T> = (T, Span);
 
+/// Byte offsets of line starts in a source file, for converting spans to line numbers.
+#[derive(Clone, Debug)]
+pub struct LineIndex {
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(source: &str) -> LineIndex {
+        LineIndex {
+            line_starts: std::iter::once(0)
+                .chain(
+                    source
+                        .match_indices('\n')
+                        .map(|(i, _)| i + 1)
+                        .filter(|start| *start < source.len()),
+                )
+                .collect(),
+        }
+    }
+
+    /// Zero based index of the line containing the byte offset
+    pub fn line(&self, offset: usize) -> usize {
+        self.line_starts
+            .partition_point(|start| *start <= offset)
+            .saturating_sub(1)
+    }
+
+    /// Byte offset of the start of a line (zero based)
+    pub fn line_start(&self, line: usize) -> usize {
+        self.line_starts[line]
+    }
+
+    /// Text of a line (zero based) without the line terminator
+    pub fn line_text<'src>(&self, source: &'src str, line: usize) -> &'src str {
+        let start = self.line_start(line);
+        let end = self
+            .line_starts
+            .get(line + 1)
+            .copied()
+            .unwrap_or(source.len());
+        source[start..end].trim_end_matches(['\n', '\r'])
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /// Zero based line and column of a byte offset, clamped to the source
+    pub fn position(&self, source: &str, offset: usize, unit: ColumnUnit) -> (usize, usize) {
+        let offset = offset.min(source.len());
+        let line = self.line(offset);
+        let text = source
+            .get(self.line_start(line)..offset)
+            .unwrap_or_default();
+        let column = match unit {
+            ColumnUnit::Char => text.chars().count(),
+            ColumnUnit::Utf16 => text.encode_utf16().count(),
+        };
+        (line, column)
+    }
+}
+
+/// What columns of a [`LineIndex::position`] count
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnUnit {
+    /// Characters, like the terminal reports
+    Char,
+    /// UTF-16 code units, like the language server protocol
+    Utf16,
+}
+
+/// Named group of warnings that can be enabled, disabled or suppressed together
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    strum::EnumString,
+    strum::IntoStaticStr,
+    strum::EnumIter,
+    strum::Display,
+)]
+#[strum(serialize_all = "kebab-case")]
+pub enum WarningClass {
+    /// Code doesn't match its `.contract`
+    Contract,
+    /// Label that is never referenced
+    UnusedLabel,
+    /// Delay slot of a branch holds something other than a single instruction
+    DelaySlot,
+    /// Immediate given as an unsigned bit pattern of a signed field
+    TruncatedImmediate,
+    /// Definition hides a symbol with the same name from an outer scope
+    ShadowedName,
+}
+
+impl WarningClass {
+    pub fn enabled_by_default(self) -> bool {
+        self != WarningClass::UnusedLabel
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// What occupies the delay slot of a branch instead of a single instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DelaySlotHazard {
+    /// Another branch or jump
+    Branch,
+    /// First instruction of a multi-word pseudo instruction or jump table
+    PartialInstruction,
+    /// Data words
+    Data,
+}
+
 #[derive(Debug)]
 pub struct ParseError {
     pub span: Span,
+    /// Message of a custom error raised by the parser itself
+    pub message: Option<String>,
     pub found: Option<String>,
     pub expected: Vec<String>,
     pub context: Vec<(String, Span)>,
 }
 
+/// Macro called with too few or too many arguments
+#[derive(Debug)]
+pub struct ArgumentCountError {
+    pub span: Span,
+    pub name: String,
+    pub min: usize,
+    /// `None` for macros with a variadic parameter
+    pub max: Option<usize>,
+    pub found: usize,
+    pub definition_span: Span,
+}
+
+/// `.assert` whose condition evaluated to zero
 #[derive(Debug)]
+pub struct AssertionFailure {
+    pub span: Span,
+    pub condition_span: Span,
+    pub message: Option<String>,
+    /// Values of the operands of the condition
+    pub operands: Vec<(Span, i64)>,
+}
+
+/// Variant names in kebab-case serve as error codes.
+/// Rarely reported variants with large payloads are boxed to keep results small.
+#[derive(Debug, strum::IntoStaticStr)]
+#[strum(serialize_all = "kebab-case")]
 pub enum AssemblerError {
-    InvalidToken(ParseError),
-    SyntaxError(ParseError),
+    InvalidToken(Box<ParseError>),
+    SyntaxError(Box<ParseError>),
     NestedMacro {
         span: Span,
         nested_in_name: QualifiedName,
@@ -35,4 +186,218 @@ pub enum AssemblerError {
         file_path: PathBuf,
         error: io::Error,
     },
+    FileWriteFailed {
+        file_path: PathBuf,
+        error: io::Error,
+    },
+    DuplicateDefinition {
+        span: Span,
+        name: QualifiedName,
+        previous_span: Span,
+    },
+    UndefinedName {
+        span: Span,
+        name: String,
+    },
+    UndefinedMacro {
+        span: Span,
+        name: String,
+    },
+    MacroArgumentCount(Box<ArgumentCountError>),
+    MacroArgumentKind {
+        span: Span,
+        param: String,
+        kind: ParamKind,
+        param_span: Span,
+    },
+    VariadicParameter {
+        span: Span,
+        name: String,
+    },
+    DuplicateJumpTableCase {
+        span: Span,
+        value: i64,
+        previous_span: Span,
+    },
+    MissingJumpTableCase {
+        span: Span,
+        value: i64,
+    },
+    /// Temporary register of a jump table is r0 or the index register
+    JumpTableRegisters {
+        span: Span,
+    },
+    MisplacedPoolLiteral {
+        span: Span,
+    },
+    LiteralOutOfReach {
+        span: Span,
+        offset: i64,
+    },
+    UnplacedLiteral {
+        span: Span,
+    },
+    MisplacedContract {
+        span: Span,
+    },
+    AssertionFailed(Box<AssertionFailure>),
+    /// Memory operand offset points outside of the struct field it names
+    FieldOverrun {
+        span: Span,
+        field_span: Span,
+        offset: i64,
+        size: u16,
+    },
+    /// Warning, location read before being defined in a routine or macro with a contract
+    UndefinedRead {
+        span: Span,
+        location: String,
+        contract_span: Span,
+    },
+    /// Warning, location written without being declared in a contract
+    UndeclaredWrite {
+        span: Span,
+        location: String,
+        contract_span: Span,
+    },
+    /// Warning, label is defined but never referenced
+    UnusedLabel {
+        span: Span,
+        name: String,
+    },
+    /// Warning, the instruction following a branch doesn't fully execute in its delay slot
+    DelaySlot {
+        span: Span,
+        branch_span: Span,
+        hazard: DelaySlotHazard,
+    },
+    /// Warning, immediate is out of the signed range, but fits as a bit pattern
+    TruncatedImmediate {
+        span: Span,
+        value: i64,
+        truncated: i64,
+    },
+    /// Warning, definition hides a symbol of the same name from an outer scope
+    ShadowedName {
+        span: Span,
+        name: String,
+        previous_span: Span,
+    },
+    /// `allow(...)` comment names an unknown warning class
+    UnknownWarningClass {
+        span: Span,
+        name: String,
+    },
+    MacroRecursionLimit {
+        span: Span,
+    },
+    RecursiveDefinition {
+        span: Span,
+    },
+    UnknownInstruction {
+        span: Span,
+        name: String,
+        /// Most similar known instruction name
+        suggestion: Option<&'static str>,
+    },
+    OperandCount {
+        span: Span,
+        expected: usize,
+        found: usize,
+    },
+    ExpectedRegister {
+        span: Span,
+    },
+    ExpectedControlRegister {
+        span: Span,
+    },
+    ExpectedValue {
+        span: Span,
+    },
+    ValueOutOfRange {
+        span: Span,
+        value: i64,
+        min: i64,
+        max: i64,
+    },
+    DivisionByZero {
+        span: Span,
+    },
+    AddressOverflow {
+        span: Span,
+    },
+    NotRelocatable {
+        span: Span,
+    },
+    FunctionNotRelocatable {
+        span: Span,
+        function: &'static str,
+    },
+    UnresolvedImport {
+        span: Span,
+        name: String,
+    },
+    ExpectedLabel {
+        span: Span,
+        name: String,
+    },
+    InvalidLayoutFile {
+        file_path: PathBuf,
+        message: String,
+    },
+    RegionOverflow {
+        span: Span,
+        segment: Segment,
+    },
+    PageBoundary {
+        span: Span,
+        region: String,
+    },
+    Overlap {
+        span: Span,
+        previous_span: Span,
+    },
+    UnknownSize {
+        span: Span,
+        name: String,
+    },
+    /// `.incbinw` of the whole rest of a file that doesn't end at a word boundary
+    OddBinarySize {
+        span: Span,
+        file_path: PathBuf,
+        size: usize,
+    },
+}
+
+impl AssemblerError {
+    /// Warnings are reported, but don't prevent producing the output
+    pub fn is_warning(&self) -> bool {
+        self.warning_class().is_some()
+    }
+
+    pub fn warning_class(&self) -> Option<WarningClass> {
+        match self {
+            AssemblerError::UndefinedRead { .. } | AssemblerError::UndeclaredWrite { .. } => {
+                Some(WarningClass::Contract)
+            }
+            AssemblerError::UnusedLabel { .. } => Some(WarningClass::UnusedLabel),
+            AssemblerError::DelaySlot { .. } => Some(WarningClass::DelaySlot),
+            AssemblerError::TruncatedImmediate { .. } => Some(WarningClass::TruncatedImmediate),
+            AssemblerError::ShadowedName { .. } => Some(WarningClass::ShadowedName),
+            _ => None,
+        }
+    }
+
+    /// Location of a warning, suppression comments are matched against it
+    pub fn warning_span(&self) -> Option<&Span> {
+        match self {
+            AssemblerError::UndefinedRead { span, .. }
+            | AssemblerError::UndeclaredWrite { span, .. }
+            | AssemblerError::UnusedLabel { span, .. }
+            | AssemblerError::DelaySlot { span, .. }
+            | AssemblerError::TruncatedImmediate { span, .. }
+            | AssemblerError::ShadowedName { span, .. } => Some(span),
+            _ => None,
+        }
+    }
 }
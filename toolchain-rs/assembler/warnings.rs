@@ -0,0 +1,299 @@
+//! Selecting which warnings are reported: `-W` command line flags and
+//! `; allow(class, ...)` comments in the source.
+use std::{collections::HashSet, ops::Range, str::FromStr};
+
+use itertools::Itertools;
+use strum::IntoEnumIterator;
+
+use crate::{
+    lexer::Token,
+    types::{AssemblerError, Severity, Span, Spanned, WarningClass},
+};
+
+/// Value of a `-W` command line flag
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WarningFlag {
+    /// `-W<class>`
+    Enable(WarningClass),
+    /// `-Wno-<class>`
+    Disable(WarningClass),
+    /// `-Wall`, enable all classes
+    All,
+    /// `-Werror`, report enabled warnings as errors
+    Error,
+}
+
+impl FromStr for WarningFlag {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(WarningFlag::All),
+            "error" => Ok(WarningFlag::Error),
+            _ => {
+                let (name, disable) = match s.strip_prefix("no-") {
+                    Some(name) => (name, true),
+                    None => (s, false),
+                };
+                let class = WarningClass::from_str(name).map_err(|_| {
+                    format!(
+                        "unknown warning `{}`, expected `all`, `error` or a class: {}",
+                        name,
+                        WarningClass::iter().join(", ")
+                    )
+                })?;
+                Ok(if disable {
+                    WarningFlag::Disable(class)
+                } else {
+                    WarningFlag::Enable(class)
+                })
+            }
+        }
+    }
+}
+
+/// Warning classes that are reported and how
+#[derive(Clone, Debug)]
+pub struct WarningOptions {
+    enabled: HashSet<WarningClass>,
+    /// Enabled warnings are reported as errors
+    pub as_errors: bool,
+}
+
+impl Default for WarningOptions {
+    fn default() -> Self {
+        WarningOptions {
+            enabled: WarningClass::iter()
+                .filter(|class| class.enabled_by_default())
+                .collect(),
+            as_errors: false,
+        }
+    }
+}
+
+impl WarningOptions {
+    /// Apply the flags in order to the defaults, later flags override earlier ones.
+    pub fn from_flags(flags: &[WarningFlag]) -> Self {
+        let mut options = WarningOptions::default();
+        for flag in flags {
+            match flag {
+                WarningFlag::Enable(class) => {
+                    options.enabled.insert(*class);
+                }
+                WarningFlag::Disable(class) => {
+                    options.enabled.remove(class);
+                }
+                WarningFlag::All => options.enabled.extend(WarningClass::iter()),
+                WarningFlag::Error => options.as_errors = true,
+            }
+        }
+        options
+    }
+
+    pub fn is_enabled(&self, class: WarningClass) -> bool {
+        self.enabled.contains(&class)
+    }
+
+    pub fn severity(&self, error: &AssemblerError) -> Severity {
+        if error.is_warning() && !self.as_errors {
+            Severity::Warning
+        } else {
+            Severity::Error
+        }
+    }
+}
+
+/// Source ranges of a file in which `; allow(class, ...)` comments silence warnings.
+///
+/// A comment following other tokens on a line covers that line, unless the last of them
+/// is `{`, then it covers the whole block. A comment on a line of its own covers the next
+/// line with code.
+#[derive(Clone, Debug, Default)]
+pub struct Suppressions(Vec<(Range<usize>, Vec<WarningClass>)>);
+
+impl Suppressions {
+    /// Collect suppressions from tokens of a file, including comments.
+    pub fn new(tokens: &[Spanned<Token>], errors: &mut Vec<AssemblerError>) -> Self {
+        let mut suppressions = Vec::new();
+        for (i, (token, span)) in tokens.iter().enumerate() {
+            let Token::Comment(text) = token else {
+                continue;
+            };
+            let Some(classes) = allowed_classes(text, span, errors) else {
+                continue;
+            };
+
+            let line_start = tokens[..i]
+                .iter()
+                .rposition(|(token, _)| *token == Token::Eol)
+                .map_or(0, |eol| eol + 1);
+            let range = match &tokens[line_start..i] {
+                [.., (Token::LBrace, brace_span)] => {
+                    brace_span.start..block_end(&tokens[i + 1..]).unwrap_or(usize::MAX)
+                }
+                [(_, first_span), ..] => first_span.start..span.end,
+                [] => {
+                    let next_line = tokens[i + 1..]
+                        .split(|(token, _)| *token == Token::Eol)
+                        .map(|line| {
+                            line.iter()
+                                .filter(|(token, _)| !matches!(token, Token::Comment(_)))
+                                .collect::<Vec<_>>()
+                        })
+                        .find(|line| !line.is_empty());
+                    let Some(line) = next_line else {
+                        continue;
+                    };
+                    line[0].1.start..line[line.len() - 1].1.end
+                }
+            };
+            suppressions.push((range, classes));
+        }
+        Suppressions(suppressions)
+    }
+
+    /// Check whether a warning of the class starting at a byte offset is silenced
+    pub fn covers(&self, class: WarningClass, offset: usize) -> bool {
+        self.0
+            .iter()
+            .any(|(range, classes)| range.contains(&offset) && classes.contains(&class))
+    }
+}
+
+/// Classes listed in an `allow(...)` comment, `None` for other comments
+fn allowed_classes(
+    text: &str,
+    span: &Span,
+    errors: &mut Vec<AssemblerError>,
+) -> Option<Vec<WarningClass>> {
+    let list = text.trim().strip_prefix("allow(")?.strip_suffix(')')?;
+    // Position of the list in the source, after `;` and the leading whitespace
+    let mut offset = span.start + 1 + (text.len() - text.trim_start().len()) + "allow(".len();
+
+    let mut classes = Vec::new();
+    for item in list.split(',') {
+        let name = item.trim();
+        match WarningClass::from_str(name) {
+            Ok(class) => classes.push(class),
+            Err(_) => {
+                let start = offset + (item.len() - item.trim_start().len());
+                errors.push(AssemblerError::UnknownWarningClass {
+                    span: Span {
+                        file_id: span.file_id,
+                        start,
+                        end: start + name.len(),
+                    },
+                    name: name.to_owned(),
+                });
+            }
+        }
+        offset += item.len() + 1;
+    }
+    Some(classes)
+}
+
+/// End offset of the `}` closing a block whose contents start at `tokens`
+fn block_end(tokens: &[Spanned<Token>]) -> Option<usize> {
+    let mut depth = 1usize;
+    for (token, span) in tokens {
+        match token {
+            Token::LBrace => depth += 1,
+            Token::RBrace => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(span.end);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+    use crate::testing::{codes, codes_with_flags};
+
+    #[test_case("all" => Ok(WarningFlag::All) ; "all")]
+    #[test_case("error" => Ok(WarningFlag::Error) ; "error")]
+    #[test_case("unused-label" => Ok(WarningFlag::Enable(WarningClass::UnusedLabel)) ; "enable")]
+    #[test_case("no-delay-slot" => Ok(WarningFlag::Disable(WarningClass::DelaySlot)) ; "disable")]
+    fn flag(flag: &str) -> Result<WarningFlag, String> {
+        flag.parse()
+    }
+
+    #[test]
+    fn unknown_flag() {
+        let message = "no-unused".parse::<WarningFlag>().unwrap_err();
+        assert!(message.starts_with("unknown warning `unused`"));
+    }
+
+    #[test]
+    fn later_flags_override() {
+        let options = WarningOptions::from_flags(&[
+            WarningFlag::All,
+            WarningFlag::Disable(WarningClass::DelaySlot),
+        ]);
+        assert!(options.is_enabled(WarningClass::UnusedLabel));
+        assert!(!options.is_enabled(WarningClass::DelaySlot));
+
+        let options = WarningOptions::from_flags(&[
+            WarningFlag::Disable(WarningClass::DelaySlot),
+            WarningFlag::Enable(WarningClass::DelaySlot),
+        ]);
+        assert!(options.is_enabled(WarningClass::DelaySlot));
+    }
+
+    #[test]
+    fn warnings_as_errors() {
+        let warning = AssemblerError::UnusedLabel {
+            span: Span {
+                file_id: None,
+                start: 0,
+                end: 0,
+            },
+            name: "start".to_owned(),
+        };
+        let options = WarningOptions::default();
+        assert_eq!(options.severity(&warning), Severity::Warning);
+        let options = WarningOptions::from_flags(&[WarningFlag::Error]);
+        assert_eq!(options.severity(&warning), Severity::Error);
+    }
+
+    const SLOTS: &str = "bz r1, r2\nldi r3, 0x1234\n";
+
+    #[test]
+    fn disabled_class() {
+        assert_eq!(codes(SLOTS), ["delay-slot"]);
+        assert!(codes_with_flags(SLOTS, &["no-delay-slot"]).is_empty());
+    }
+
+    #[test]
+    fn allow_on_a_line() {
+        let source = "bz r1, r2\nldi r3, 0x1234 ; allow(delay-slot)\nbz r1, r2\nldi r3, 0x1234\n";
+        assert_eq!(codes(source), ["delay-slot"]);
+    }
+
+    #[test]
+    fn allow_on_the_line_before() {
+        let source = "bz r1, r2\n; allow(delay-slot)\nldi r3, 0x1234\n";
+        assert!(codes(source).is_empty());
+    }
+
+    #[test]
+    fn allow_in_a_scope() {
+        let source = format!("{{ ; allow(delay-slot)\n{}{}}}\n{}", SLOTS, SLOTS, SLOTS);
+        assert_eq!(codes(&source), ["delay-slot"]);
+    }
+
+    #[test]
+    fn allow_unknown_class() {
+        assert_eq!(
+            codes("nop ; allow(delay-slots)\n"),
+            ["unknown-warning-class"]
+        );
+    }
+}
@@ -25,6 +25,12 @@ impl<'a> Disassembler<'a> {
     }
 }
 
+impl Item {
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+}
+
 impl<'a> Iterator for Disassembler<'a> {
     type Item = Item;
 
@@ -1,6 +1,6 @@
 use clap::Parser;
-use std::path::PathBuf;
-use toolchain_core::image::load_ihex;
+use std::path::{Path, PathBuf};
+use toolchain_core::{debug_info::DebugInfo, image::Segment, image::load_ihex};
 
 use crate::disassembler::Disassembler;
 
@@ -10,14 +10,36 @@ mod disassembler;
 struct Cli {
     /// Path to intel hex image of the boot rom
     image_path: PathBuf,
+
+    /// Path to symbol file written by the assembler, used to show labels and source lines
+    #[arg(long)]
+    symbols: Option<PathBuf>,
 }
 
 fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
     let img = load_ihex(cli.image_path)?;
+    let debug_info = cli.symbols.map(DebugInfo::load).transpose()?;
+
     for entry in Disassembler::new(&img) {
-        println!("{}", entry);
+        let Some(debug_info) = &debug_info else {
+            println!("{}", entry);
+            continue;
+        };
+
+        for label in debug_info.labels_at(Segment::Program, entry.address()) {
+            println!("{}:", label.name);
+        }
+        match debug_info.line_at(Segment::Program, entry.address()) {
+            Some(line) => {
+                let path = debug_info
+                    .file_path(&line.location)
+                    .unwrap_or(Path::new("?"));
+                println!("{}  ; {}:{}", entry, path.display(), line.location.line)
+            }
+            None => println!("{}", entry),
+        }
     }
     Ok(())
 }
@@ -0,0 +1,388 @@
+//! Canonical layout of assembly source. Only whitespace between tokens and the spelling
+//! of numbers change, so the result has the same tokens as the input.
+use pickle_assembler::{lexer::Token, types::Spanned};
+
+/// Columns per level of nesting in blocks
+const INDENT: usize = 4;
+/// Operands start this many columns after the start of the instruction name,
+/// unless the name is longer
+const MNEMONIC_WIDTH: usize = 8;
+/// Trailing comments start at this column, unless the code is longer
+const COMMENT_COLUMN: usize = 32;
+/// Longer runs of blank lines are shortened to this
+const MAX_BLANK_LINES: usize = 2;
+
+/// Block opened by `{`
+struct Block {
+    /// Indentation of the line with the `{`, the closing `}` goes there too
+    indent: usize,
+    /// Contains `.struct` fields or `.jumptable` cases instead of statements
+    entries: bool,
+}
+
+enum Line {
+    Blank,
+    Code {
+        indent: usize,
+        code: String,
+        comment: Option<String>,
+    },
+    /// Comment on a line of its own, indented like the code that follows it
+    Comment {
+        /// Indentation of labels in the enclosing block, used when no code follows
+        block_indent: usize,
+        text: String,
+    },
+}
+
+/// Format a whole file given its tokens, including comments.
+pub fn format_source(source: &str, tokens: &[Spanned<Token>]) -> String {
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut lines = Vec::new();
+
+    for line in tokens.split(|(token, _)| *token == Token::Eol) {
+        let (code, comment) = match line {
+            [code @ .., (Token::Comment(text), _)] => (code, Some(format!(";{}", text.trim_end()))),
+            _ => (line, None),
+        };
+        if code.is_empty() {
+            lines.push(match comment {
+                Some(text) => Line::Comment {
+                    block_indent: blocks.len() * INDENT,
+                    text,
+                },
+                None => Line::Blank,
+            });
+            continue;
+        }
+        let (indent, code) = format_line(source, code, &mut blocks);
+        lines.push(Line::Code {
+            indent,
+            code,
+            comment,
+        });
+    }
+
+    layout_lines(&lines)
+}
+
+/// Indentation and text of a line of code, keeping track of the blocks it opens and closes
+fn format_line(
+    source: &str,
+    tokens: &[Spanned<Token>],
+    blocks: &mut Vec<Block>,
+) -> (usize, String) {
+    let base = blocks.len() * INDENT;
+    let in_entries = blocks.last().is_some_and(|block| block.entries);
+
+    let (indent, text, rest) = match tokens {
+        // Closing braces line up with the line that opened the block
+        [(Token::RBrace, _), ..] => {
+            let indent = blocks.pop().map_or(0, |block| block.indent);
+            (indent, render(source, tokens), &tokens[1..])
+        }
+        _ if in_entries => (base + INDENT, render(source, tokens), tokens),
+        [
+            label @ (Token::Identifier(_) | Token::Number(_), _),
+            (Token::Colon, _),
+            rest @ ..,
+        ] => {
+            let label = format!("{}:", token_text(source, label));
+            let text = match rest {
+                [] => label,
+                [(Token::LBrace, _), ..] => format!("{} {}", label, render(source, rest)),
+                // Local and named labels alike are followed by a single space
+                _ => format!("{} {}", label, format_statement(source, rest)),
+            };
+            (base, text, tokens)
+        }
+        // Anonymous scopes are indented like labelled ones
+        [(Token::LBrace, _), ..] | [(Token::Const | Token::Macro, _), ..] => {
+            (base, render(source, tokens), tokens)
+        }
+        _ => (base + INDENT, format_statement(source, tokens), tokens),
+    };
+
+    let entries = matches!(
+        tokens,
+        [
+            (Token::Dot, _),
+            (Token::Identifier("struct" | "jumptable"), _),
+            ..
+        ]
+    );
+    for (token, _) in rest {
+        match token {
+            Token::LBrace => blocks.push(Block { indent, entries }),
+            Token::RBrace => {
+                blocks.pop();
+            }
+            _ => {}
+        }
+    }
+    (indent, text)
+}
+
+/// Instruction, macro call or directive, with the operands aligned after the name
+fn format_statement(source: &str, tokens: &[Spanned<Token>]) -> String {
+    let name_length = match tokens {
+        [(Token::Dot, _), (Token::Identifier(_), _), ..] => 2,
+        _ => 1,
+    };
+    let (name, operands) = tokens.split_at(name_length.min(tokens.len()));
+    let name = render(source, name);
+    if operands.is_empty() {
+        name
+    } else if operands.iter().any(|(token, _)| *token == Token::LBrace) {
+        // Block directives are not aligned with instructions
+        format!("{} {}", name, render(source, operands))
+    } else {
+        format!(
+            "{:width$}{}",
+            name + " ",
+            render(source, operands),
+            width = MNEMONIC_WIDTH
+        )
+    }
+}
+
+/// Tokens separated by the canonical spacing
+fn render(source: &str, tokens: &[Spanned<Token>]) -> String {
+    let mut text = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 && space_before(&tokens[..i], &token.0) {
+            text.push(' ');
+        }
+        text += &token_text(source, token);
+    }
+    text
+}
+
+fn space_before(preceding: &[Spanned<Token>], next: &Token) -> bool {
+    let last = preceding.len() - 1;
+    match (&preceding[last].0, next) {
+        (
+            _,
+            Token::Comma
+            | Token::Colon
+            | Token::RParen
+            | Token::RBracket
+            | Token::DoubleDot
+            | Token::Ellipsis,
+        ) => false,
+        (Token::Dot | Token::DoubleDot | Token::LParen | Token::LBracket, _) => false,
+        // Qualified names, function calls and indexing
+        (Token::Identifier(_), Token::Dot | Token::LParen | Token::LBracket) => false,
+        (Token::LBrace, _) | (_, Token::RBrace) => true,
+        _ => !is_prefix_operator(preceding, last),
+    }
+}
+
+/// Check whether the token at `index` is a unary operator (or the `=` of a pool literal),
+/// which has no space after it
+fn is_prefix_operator(tokens: &[Spanned<Token>], index: usize) -> bool {
+    matches!(
+        tokens[index].0,
+        Token::Minus | Token::Exclamation | Token::Tilde | Token::Equal
+    ) && (index == 0 || !ends_operand(&tokens[index - 1].0))
+}
+
+fn ends_operand(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Identifier(_)
+            | Token::Number(_)
+            | Token::LocalLabelBackward(_)
+            | Token::LocalLabelForward(_)
+            | Token::String(_)
+            | Token::RParen
+            | Token::RBracket
+    )
+}
+
+fn token_text(source: &str, (token, span): &Spanned<Token>) -> String {
+    match token {
+        Token::Number(_) => normalize_number(&source[span.start..span.end]),
+        _ => token.to_string(),
+    }
+}
+
+/// Numbers keep their base, with lowercase hexadecimal digits and no leading zeros
+/// in any base (`0x00AB` becomes `0xab`, `0b0010` becomes `0b10`).
+fn normalize_number(text: &str) -> String {
+    let (prefix, digits) = match text.split_at_checked(2) {
+        Some((prefix @ ("0x" | "0b"), digits)) => (prefix, digits),
+        _ => ("", text),
+    };
+    let digits = digits.trim_start_matches('0');
+    let digits = if digits.is_empty() { "0" } else { digits };
+    format!("{}{}", prefix, digits.to_ascii_lowercase())
+}
+
+/// Join the lines, aligning trailing comments within each run of consecutive lines
+/// and dropping blank lines at the start, at the end and beyond the limit.
+fn layout_lines(lines: &[Line]) -> String {
+    let mut comment_columns = vec![COMMENT_COLUMN; lines.len()];
+    for run in lines
+        .iter()
+        .enumerate()
+        .collect::<Vec<_>>()
+        .split(|(_, line)| !matches!(line, Line::Code { .. }))
+    {
+        let column = run
+            .iter()
+            .filter_map(|(_, line)| match line {
+                Line::Code {
+                    indent,
+                    code,
+                    comment: Some(_),
+                } => Some(indent + code.len() + 1),
+                _ => None,
+            })
+            .fold(COMMENT_COLUMN, usize::max);
+        for (i, _) in run {
+            comment_columns[*i] = column;
+        }
+    }
+
+    let mut output = String::new();
+    let mut blank_lines = 0;
+    for (i, line) in lines.iter().enumerate() {
+        let text = match line {
+            Line::Blank => {
+                blank_lines += 1;
+                continue;
+            }
+            Line::Code {
+                indent,
+                code,
+                comment: None,
+            } => format!("{}{}", " ".repeat(*indent), code),
+            Line::Code {
+                indent,
+                code,
+                comment: Some(comment),
+            } => format!(
+                "{:width$}{}",
+                format!("{}{}", " ".repeat(*indent), code),
+                comment,
+                width = comment_columns[i]
+            ),
+            Line::Comment { block_indent, text } => {
+                // Comments directly above code belong to it
+                let indent = lines[i + 1..]
+                    .iter()
+                    .find(|line| !matches!(line, Line::Comment { .. }))
+                    .and_then(|line| match line {
+                        Line::Code { indent, .. } => Some(*indent),
+                        _ => None,
+                    })
+                    .unwrap_or(*block_indent);
+                format!("{}{}", " ".repeat(indent), text)
+            }
+        };
+        if !output.is_empty() {
+            for _ in 0..blank_lines.min(MAX_BLANK_LINES) {
+                output.push('\n');
+            }
+        }
+        blank_lines = 0;
+        output += &text;
+        output.push('\n');
+    }
+    output
+}
+
+/// Check that two token sequences are the same apart from positions, the spelling
+/// of numbers, trailing whitespace in comments and the number of consecutive line ends
+pub fn same_tokens(a: &[Spanned<Token>], b: &[Spanned<Token>]) -> bool {
+    significant_tokens(a) == significant_tokens(b)
+}
+
+fn significant_tokens<'src>(tokens: &[Spanned<Token<'src>>]) -> Vec<Token<'src>> {
+    let mut result: Vec<Token> = Vec::new();
+    for (token, _) in tokens {
+        let token = match token {
+            Token::Comment(text) => Token::Comment(text.trim_end()),
+            _ => token.clone(),
+        };
+        if token == Token::Eol && result.last().is_none_or(|last| *last == Token::Eol) {
+            continue;
+        }
+        result.push(token);
+    }
+    if result.last() == Some(&Token::Eol) {
+        result.pop();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pickle_assembler::lexer::tokenize_with_comments;
+    use test_case::test_case;
+
+    fn format(source: &str) -> String {
+        let mut errors = Vec::new();
+        let tokens = tokenize_with_comments(source, None, &mut errors);
+        assert!(errors.is_empty());
+        format_source(source, &tokens)
+    }
+
+    #[test_case("add r1,r2 ,r3", "    add     r1, r2, r3\n"; "operands")]
+    #[test_case("\tldi r1 , =-X+1", "    ldi     r1, =-X + 1\n"; "unary_and_pool_literal")]
+    #[test_case("ld r1, a.b[ 2 ]", "    ld      r1, a.b[2]\n"; "qualified_and_indexed")]
+    #[test_case(".dw 0xAB, 007, 0b0010", "    .dw     0xab, 7, 0b10\n"; "numbers")]
+    #[test_case(".dw 0x00, 0b000, 0x000F", "    .dw     0x0, 0b0, 0xf\n"; "zero_numbers")]
+    #[test_case("loop: nop", "loop: nop\n"; "label_with_statement")]
+    #[test_case("1: nop", "1: nop\n"; "local_label_with_statement")]
+    #[test_case("1: add r1,r2,r3\nloop: add r1,r2,r3", "1: add     r1, r2, r3\nloop: add     r1, r2, r3\n"; "local_and_named_labels")]
+    #[test_case("const   x==2*(1+y)", "const x == 2 * (1 + y)\n"; "constant")]
+    #[test_case(".for i in 0 .. 4 {\nnop\n}", "    .for i in 0..4 {\n        nop\n    }\n"; "for_loop")]
+    #[test_case("{\nx: nop\n}", "{\n    x: nop\n}\n"; "anonymous_scope")]
+    fn line(source: &str, expected: &str) {
+        assert_eq!(format(source), expected);
+    }
+
+    #[test]
+    fn blocks_and_comments() {
+        let source = "\n\n; Header\n\nmacro m a, b: reg = 1, rest... {\n; before\n\
+                      inner: add a,b,b ; trailing\nnop;x   \n}\n\n\n\n\nscope: {\n\
+                      .jumptable r1, r2 {\n0: a\ndefault: b\n}\n} ; end\n\n";
+        let expected = "; Header\n\
+                        \n\
+                        macro m a, b: reg = 1, rest... {\n    \
+                        ; before\n    \
+                        inner: add     a, b, b      ; trailing\n        \
+                        nop                     ;x\n\
+                        }\n\
+                        \n\
+                        \n\
+                        scope: {\n        \
+                        .jumptable r1, r2 {\n            \
+                        0: a\n            \
+                        default: b\n        \
+                        }\n\
+                        }                               ; end\n";
+        assert_eq!(format(source), expected);
+    }
+
+    #[test]
+    fn idempotent() {
+        let source = "x: {\n  ; c\n  foo! 1 , 2 ; call\n  .if defined(y) {\nbz r1,r2\n} .else {\n\
+                      ldi r1,~1 ; long comment\n}\n}\n";
+        let once = format(source);
+        assert_eq!(format(&once), once);
+    }
+
+    #[test]
+    fn same_tokens_ignores_layout() {
+        let mut errors = Vec::new();
+        let a = tokenize_with_comments("\n\nadd r1, 0x0f ; c  \n\n\n", None, &mut errors);
+        let b = tokenize_with_comments("add r1,15; c\n", None, &mut errors);
+        let c = tokenize_with_comments("add r1, 16 ; c\n", None, &mut errors);
+        assert!(same_tokens(&a, &b));
+        assert!(!same_tokens(&a, &c));
+    }
+}
@@ -0,0 +1,128 @@
+//! Formatter for assembler source files.
+
+mod format;
+
+use clap::Parser as _;
+use std::{
+    fs,
+    io::{self, Read as _, Write as _},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use pickle_assembler::{
+    assembler::Assembler,
+    diagnostic::{AriadneCache, diagnostic},
+    lexer,
+    types::{AssemblerError, FileId, Severity},
+};
+
+#[derive(clap::Parser, Debug)]
+#[command(
+    after_help = "Exit status is 0 on success, 1 if a file could not be formatted or `--check` \
+                  found unformatted files and 2 if the command line is invalid."
+)]
+struct Cli {
+    /// Assembler files to format in place. Without any, standard input is formatted
+    /// to standard output.
+    input_files: Vec<PathBuf>,
+
+    /// Don't write anything, only list the files that are not formatted
+    #[arg(long)]
+    check: bool,
+}
+
+/// Formatted source of a file, `None` if it has errors
+fn format_file(assembler: &Assembler, file_id: FileId) -> Option<String> {
+    let source = assembler.get_source(file_id)?;
+    let tokens = lexer::tokenize_with_comments(source, None, &mut Vec::new());
+    let formatted = format::format_source(source, &tokens);
+
+    let mut errors = Vec::new();
+    let formatted_tokens = lexer::tokenize_with_comments(&formatted, None, &mut errors);
+    if !errors.is_empty() || !format::same_tokens(&tokens, &formatted_tokens) {
+        let path = assembler.get_path(file_id)?;
+        eprintln!(
+            "{}: formatting would change the meaning, leaving the file unchanged",
+            path.display()
+        );
+        return None;
+    }
+    Some(formatted)
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let mut assembler = Assembler::default();
+    let mut errors = Vec::new();
+    let mut success = true;
+
+    let inputs: Vec<Option<&PathBuf>> = if cli.input_files.is_empty() {
+        vec![None]
+    } else {
+        cli.input_files.iter().map(Some).collect()
+    };
+
+    for input in inputs {
+        // Only files that were read and parsed without errors are formatted
+        let mut file_errors = Vec::new();
+        let file_id = match input {
+            Some(path) => assembler.add_file(path.clone(), None, &mut file_errors),
+            None => {
+                let mut source = String::new();
+                if let Err(error) = io::stdin().read_to_string(&mut source) {
+                    eprintln!("could not read standard input: {}", error);
+                    return ExitCode::FAILURE;
+                }
+                assembler.add_source("<stdin>", source, &mut file_errors)
+            }
+        };
+        if !file_errors.is_empty() {
+            errors.extend(file_errors);
+            success = false;
+            continue;
+        }
+
+        let Some(formatted) = format_file(&assembler, file_id) else {
+            success = false;
+            continue;
+        };
+        let source = assembler
+            .get_source(file_id)
+            .expect("The file was just added");
+        let path = assembler
+            .get_path(file_id)
+            .expect("The file was just added");
+
+        if cli.check {
+            if formatted != source {
+                println!("{}", path.display());
+                success = false;
+            }
+        } else if input.is_none() {
+            let _ = io::stdout().write_all(formatted.as_bytes());
+        } else if formatted != source
+            && let Err(error) = fs::write(path, formatted)
+        {
+            errors.push(AssemblerError::FileWriteFailed {
+                file_path: path.to_owned(),
+                error,
+            });
+            success = false;
+        }
+    }
+
+    let mut sources = AriadneCache::new(&assembler);
+    for error in errors.iter() {
+        let _ = diagnostic(error, Severity::Error)
+            .to_report()
+            .eprint(&mut sources);
+    }
+
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
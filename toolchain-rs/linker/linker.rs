@@ -0,0 +1,493 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use thiserror::Error;
+use toolchain_core::{
+    image::{Image, Segment},
+    memory_layout::{MemoryLayout, PlacementError, Region},
+    object::{ObjectFile, RelocationError, RelocationTarget},
+};
+
+#[derive(Debug, Error)]
+pub enum LinkError {
+    #[error("symbol `{name}` is exported from both {} and {}", first.display(), second.display())]
+    DuplicateExport {
+        name: String,
+        first: PathBuf,
+        second: PathBuf,
+    },
+    #[error("{}: undefined symbol `{name}`", path.display())]
+    UndefinedSymbol { path: PathBuf, name: String },
+    #[error("{}: invalid {what} index {index}", path.display())]
+    InvalidIndex {
+        path: PathBuf,
+        what: &'static str,
+        index: usize,
+    },
+    #[error("{}: relocation at {segment} address {address:#06x}: {error}", path.display())]
+    Relocation {
+        path: PathBuf,
+        segment: Segment,
+        address: u32,
+        error: RelocationError,
+    },
+    #[error("{}: {segment} section of {size} words does not fit into any memory region", path.display())]
+    RegionOverflow {
+        path: PathBuf,
+        segment: Segment,
+        size: u32,
+    },
+    #[error("{}: section is larger than a page and cannot be placed in region `{region}`", path.display())]
+    PageBoundary { path: PathBuf, region: String },
+    #[error("{}: {segment} section at address {address:#06x} is loaded over another section", path.display())]
+    Overlap {
+        path: PathBuf,
+        segment: Segment,
+        address: u32,
+    },
+}
+
+/// Object file together with the path it was loaded from, for error messages
+#[derive(Clone, Debug)]
+pub struct InputObject {
+    pub path: PathBuf,
+    pub object: ObjectFile,
+}
+
+/// Place all sections into the regions of the memory layout, resolve symbols and apply
+/// relocations. Only regions that have a load address end up in the image.
+pub fn link(inputs: &[InputObject], memory_layout: &MemoryLayout) -> Result<Image, Vec<LinkError>> {
+    let mut errors = Vec::new();
+
+    let section_addresses = place_sections(inputs, memory_layout, &mut errors);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let exports = collect_exports(inputs, &section_addresses, &mut errors);
+
+    let mut image = Image::default();
+    for (input_index, input) in inputs.iter().enumerate() {
+        let addresses = &section_addresses[input_index];
+        for (section, address) in input.object.sections.iter().zip(addresses.iter()) {
+            let mut data = section.data.clone();
+
+            for relocation in section.relocations.iter() {
+                let target = match relocation.target {
+                    RelocationTarget::Section(index) => {
+                        addresses
+                            .get(index)
+                            .copied()
+                            .ok_or(LinkError::InvalidIndex {
+                                path: input.path.clone(),
+                                what: "section",
+                                index,
+                            })
+                    }
+                    RelocationTarget::Import(index) => input
+                        .object
+                        .imports
+                        .get(index)
+                        .ok_or(LinkError::InvalidIndex {
+                            path: input.path.clone(),
+                            what: "import",
+                            index,
+                        })
+                        .and_then(|name| {
+                            exports.get(name.as_str()).copied().ok_or_else(|| {
+                                LinkError::UndefinedSymbol {
+                                    path: input.path.clone(),
+                                    name: name.clone(),
+                                }
+                            })
+                        }),
+                };
+
+                let place = address + u32::from(relocation.offset);
+                let result = target.and_then(|target| {
+                    let value = i64::from(target) + relocation.addend;
+                    relocation
+                        .kind
+                        .apply(
+                            data.get_mut(usize::from(relocation.offset)..)
+                                .unwrap_or_default(),
+                            value,
+                            place as u16,
+                        )
+                        .map_err(|error| LinkError::Relocation {
+                            path: input.path.clone(),
+                            segment: section.segment,
+                            address: place,
+                            error,
+                        })
+                });
+                if let Err(e) = result {
+                    errors.push(e);
+                }
+            }
+
+            if !data.is_empty()
+                && let Some(load_address) =
+                    memory_layout.load_address(section.segment, *address as u16)
+                && image.add(load_address, &data).is_err()
+            {
+                errors.push(LinkError::Overlap {
+                    path: input.path.clone(),
+                    segment: section.segment,
+                    address: *address,
+                });
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(image)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Next free address in a region
+struct RegionFill<'a> {
+    region: &'a Region,
+    next: u32,
+}
+
+/// Assign addresses to sections of all inputs, in the order in which they appear.
+/// Each section goes to the first region of its segment (in layout order) that still has
+/// room for it, at the next multiple of its alignment. Sections are moved to the start of
+/// the next page rather than crossing a page boundary in regions that require it.
+fn place_sections(
+    inputs: &[InputObject],
+    memory_layout: &MemoryLayout,
+    errors: &mut Vec<LinkError>,
+) -> Vec<Vec<u32>> {
+    let mut fills: Vec<_> = memory_layout
+        .regions
+        .iter()
+        .map(|region| RegionFill {
+            region,
+            next: region.origin.into(),
+        })
+        .collect();
+
+    inputs
+        .iter()
+        .map(|input| {
+            input
+                .object
+                .sections
+                .iter()
+                .map(|section| {
+                    let length = section.data.len() as u32;
+                    let mut larger_than_page = None;
+                    let placed = fills
+                        .iter_mut()
+                        .filter(|fill| fill.region.segment == section.segment)
+                        .find_map(|fill| {
+                            let start = fill.next.next_multiple_of(section.alignment.max(1));
+                            match fill.region.first_placement(start, length) {
+                                Ok(start) => {
+                                    fill.next = start + length;
+                                    Some(start)
+                                }
+                                Err(PlacementError::PageBoundary) => {
+                                    larger_than_page.get_or_insert(&fill.region.name);
+                                    None
+                                }
+                                Err(PlacementError::OutOfRegion) => None,
+                            }
+                        });
+
+                    placed.unwrap_or_else(|| {
+                        errors.push(match larger_than_page {
+                            Some(region) => LinkError::PageBoundary {
+                                path: input.path.clone(),
+                                region: region.clone(),
+                            },
+                            None => LinkError::RegionOverflow {
+                                path: input.path.clone(),
+                                segment: section.segment,
+                                size: length,
+                            },
+                        });
+                        0
+                    })
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Build a table of exported symbol addresses
+fn collect_exports<'a>(
+    inputs: &'a [InputObject],
+    section_addresses: &[Vec<u32>],
+    errors: &mut Vec<LinkError>,
+) -> HashMap<&'a str, u32> {
+    let mut exports: HashMap<&str, (u32, usize)> = HashMap::new();
+    for (input_index, input) in inputs.iter().enumerate() {
+        for export in input.object.exports.iter() {
+            let Some(section_address) = section_addresses[input_index].get(export.section) else {
+                errors.push(LinkError::InvalidIndex {
+                    path: input.path.clone(),
+                    what: "section",
+                    index: export.section,
+                });
+                continue;
+            };
+            let address = section_address + u32::from(export.offset);
+            if let Some((_, previous_index)) = exports.insert(&export.name, (address, input_index))
+            {
+                errors.push(LinkError::DuplicateExport {
+                    name: export.name.clone(),
+                    first: inputs[previous_index].path.clone(),
+                    second: input.path.clone(),
+                });
+            }
+        }
+    }
+
+    exports
+        .into_iter()
+        .map(|(name, (address, _))| (name, address))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toolchain_core::{
+        instruction::{Instruction, Reg},
+        object::{Export, Relocation, RelocationKind, Section},
+    };
+
+    fn r(i: u16) -> Reg {
+        Reg::new(i).unwrap()
+    }
+
+    fn input(name: &str, object: ObjectFile) -> InputObject {
+        InputObject {
+            path: name.into(),
+            object,
+        }
+    }
+
+    /// Object with a `ldpc` to an imported symbol, followed by a word with its own address
+    fn caller() -> ObjectFile {
+        ObjectFile {
+            sections: vec![Section {
+                segment: Segment::Program,
+                alignment: 1,
+                data: vec![
+                    Instruction::Ldpc {
+                        rd: r(1),
+                        offset: 0,
+                    }
+                    .encode(),
+                    0,
+                ],
+                relocations: vec![
+                    Relocation {
+                        offset: 0,
+                        kind: RelocationKind::Ldpc,
+                        target: RelocationTarget::Import(0),
+                        addend: 0,
+                    },
+                    Relocation {
+                        offset: 1,
+                        kind: RelocationKind::Word,
+                        target: RelocationTarget::Section(0),
+                        addend: 1,
+                    },
+                ],
+            }],
+            exports: Vec::new(),
+            imports: vec!["function".to_string()],
+        }
+    }
+
+    fn callee() -> ObjectFile {
+        ObjectFile {
+            sections: vec![Section {
+                segment: Segment::Program,
+                alignment: 1,
+                data: vec![Instruction::Break.encode(); 3],
+                relocations: Vec::new(),
+            }],
+            exports: vec![Export {
+                name: "function".to_string(),
+                section: 0,
+                offset: 2,
+            }],
+            imports: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn link_resolves_imports() {
+        let image = link(
+            &[input("caller", caller()), input("callee", callee())],
+            &MemoryLayout::default(),
+        )
+        .unwrap();
+        let blocks: Vec<_> = image.iter_blocks().collect();
+        let break_word = Instruction::Break.encode();
+        assert_eq!(
+            blocks,
+            vec![(
+                0,
+                &[
+                    Instruction::Ldpc {
+                        rd: r(1),
+                        offset: 4
+                    }
+                    .encode(),
+                    1,
+                    break_word,
+                    break_word,
+                    break_word
+                ][..]
+            )]
+        );
+    }
+
+    #[test]
+    fn link_undefined_symbol() {
+        let errors = link(&[input("caller", caller())], &MemoryLayout::default()).unwrap_err();
+        assert!(matches!(
+            errors.as_slice(),
+            [LinkError::UndefinedSymbol { name, .. }] if name == "function"
+        ));
+    }
+
+    #[test]
+    fn link_duplicate_export() {
+        let errors = link(
+            &[
+                input("caller", caller()),
+                input("a", callee()),
+                input("b", callee()),
+            ],
+            &MemoryLayout::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            errors.as_slice(),
+            [LinkError::DuplicateExport { name, .. }] if name == "function"
+        ));
+    }
+
+    fn data_section(segment: Segment, size: usize) -> ObjectFile {
+        ObjectFile {
+            sections: vec![Section {
+                segment,
+                alignment: 1,
+                data: vec![0x1234; size],
+                relocations: Vec::new(),
+            }],
+            exports: Vec::new(),
+            imports: Vec::new(),
+        }
+    }
+
+    const LAYOUT: &str = r#"
+        [[region]]
+        name = "boot"
+        segment = "program"
+        origin = 0x0000
+        length = 0x0800
+        within_page = true
+
+        [[region]]
+        name = "init_data"
+        segment = "data"
+        origin = 0x0100
+        length = 0x0100
+        load = 0x8000
+    "#;
+
+    #[test]
+    fn link_into_regions() {
+        let layout = MemoryLayout::parse(LAYOUT).unwrap();
+        let image = link(
+            &[
+                input("a", data_section(Segment::Program, 0x300)),
+                input("b", data_section(Segment::Program, 0x200)),
+                input("c", data_section(Segment::Data, 0x10)),
+            ],
+            &layout,
+        )
+        .unwrap();
+        let blocks: Vec<_> = image
+            .iter_blocks()
+            .map(|(address, data)| (address, data.len()))
+            .collect();
+        // Second section does not fit into the rest of the first page
+        assert_eq!(blocks, vec![(0, 0x300), (0x400, 0x200), (0x8000, 0x10)]);
+    }
+
+    #[test]
+    fn link_region_overflow() {
+        let layout = MemoryLayout::parse(LAYOUT).unwrap();
+        let errors = link(&[input("a", data_section(Segment::Data, 0x101))], &layout).unwrap_err();
+        assert!(matches!(
+            errors.as_slice(),
+            [LinkError::RegionOverflow {
+                segment: Segment::Data,
+                size: 0x101,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn link_larger_than_page() {
+        let layout = MemoryLayout::parse(LAYOUT).unwrap();
+        let errors = link(
+            &[input("a", data_section(Segment::Program, 0x401))],
+            &layout,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            errors.as_slice(),
+            [LinkError::PageBoundary { region, .. }] if region == "boot"
+        ));
+    }
+
+    #[test]
+    fn link_overlapping_load_addresses() {
+        let mut layout = MemoryLayout::default();
+        layout.regions[1].load = Some(0);
+        let errors = link(
+            &[
+                input("a", data_section(Segment::Program, 2)),
+                input("b", data_section(Segment::Data, 2)),
+            ],
+            &layout,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            errors.as_slice(),
+            [LinkError::Overlap {
+                segment: Segment::Data,
+                address: 0,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn link_aligned_section() {
+        let mut aligned = data_section(Segment::Program, 2);
+        aligned.sections[0].alignment = 0x100;
+        let image = link(
+            &[
+                input("a", data_section(Segment::Program, 3)),
+                input("b", aligned),
+            ],
+            &MemoryLayout::default(),
+        )
+        .unwrap();
+        let starts: Vec<_> = image.iter_blocks().map(|(address, _)| address).collect();
+        assert_eq!(starts, vec![0, 0x100]);
+    }
+}
@@ -0,0 +1,55 @@
+use anyhow::Context;
+use clap::Parser;
+use std::{path::PathBuf, process::ExitCode};
+use toolchain_core::{memory_layout::MemoryLayout, object::ObjectFile};
+
+use crate::linker::{InputObject, link};
+
+mod linker;
+
+#[derive(Parser, Debug)]
+struct Cli {
+    /// Paths to object files produced by `assembler --object`
+    object_files: Vec<PathBuf>,
+
+    /// Path to the output intel hex image
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Memory layout file with regions for the program and data segments
+    #[arg(long)]
+    layout: Option<PathBuf>,
+}
+
+fn main() -> anyhow::Result<ExitCode> {
+    let cli = Cli::parse();
+
+    let inputs = cli
+        .object_files
+        .into_iter()
+        .map(|path| {
+            let object = ObjectFile::load(&path)
+                .with_context(|| format!("could not load object file {}", path.display()))?;
+            Ok(InputObject { path, object })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let memory_layout = match &cli.layout {
+        Some(path) => MemoryLayout::load(path)
+            .with_context(|| format!("could not load memory layout {}", path.display()))?,
+        None => MemoryLayout::default(),
+    };
+
+    match link(&inputs, &memory_layout) {
+        Ok(image) => {
+            image.save_ihex(&cli.output)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Err(errors) => {
+            for error in errors {
+                eprintln!("error: {}", error);
+            }
+            Ok(ExitCode::FAILURE)
+        }
+    }
+}
@@ -0,0 +1,388 @@
+//! Results of assembling a single document, queried by position.
+use std::{path::PathBuf, str::FromStr};
+
+use itertools::Itertools;
+use lsp_types::{
+    CompletionItem, CompletionItemKind, Diagnostic, DiagnosticRelatedInformation,
+    DiagnosticSeverity, DiagnosticTag, Location, NumberOrString, Position, Range, Uri,
+};
+use pickle_assembler::{
+    assembler::{AssembleOptions, Assembled, Assembler, NameTarget},
+    diagnostic::diagnostic,
+    encoder::PSEUDO_INSTRUCTIONS,
+    types::{AssemblerError, ColumnUnit, FileId, LineIndex, Severity, Span},
+    warnings::WarningOptions,
+};
+use strum::IntoEnumIterator;
+use toolchain_core::instruction::{ControlRegister, Instruction, Mnemonic, Reg};
+
+/// Document assembled on its own with the default options
+pub struct Analysis {
+    uri: Uri,
+    assembler: Assembler,
+    file_id: FileId,
+    lines: LineIndex,
+    assembled: Assembled,
+    errors: Vec<AssemblerError>,
+    warnings: WarningOptions,
+}
+
+impl Analysis {
+    pub fn new(uri: Uri, text: String) -> Self {
+        let mut assembler = Assembler::default();
+        let mut errors = Vec::new();
+        let lines = LineIndex::new(&text);
+        let path = PathBuf::from(uri.path().as_str());
+        let file_id = assembler.add_source(path, text, &mut errors);
+
+        // Like the command line tool, only syntax errors are reported while there are any,
+        // but whatever could be parsed is still assembled for navigation
+        let options = AssembleOptions::default();
+        let assembled = if errors.is_empty() {
+            assembler.assemble(&options, &mut errors)
+        } else {
+            assembler.assemble(&options, &mut Vec::new())
+        };
+
+        Analysis {
+            uri,
+            assembler,
+            file_id,
+            lines,
+            assembled,
+            errors,
+            warnings: options.warnings,
+        }
+    }
+
+    fn source(&self) -> &str {
+        self.assembler
+            .get_source(self.file_id)
+            .expect("The analysed file is always present")
+    }
+
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.errors
+            .iter()
+            .map(|error| {
+                let severity = self.warnings.severity(error);
+                let diagnostic = diagnostic(error, severity);
+
+                let mut message = diagnostic.message.clone();
+                for help in diagnostic.help.iter() {
+                    message += &format!("\nhelp: {}", help);
+                }
+                for note in diagnostic.notes.iter() {
+                    message += &format!("\nnote: {}", note);
+                }
+                let related_information = diagnostic
+                    .labels
+                    .iter()
+                    .filter(|label| label.span != diagnostic.span)
+                    .filter_map(|label| {
+                        Some(DiagnosticRelatedInformation {
+                            location: self.location(label.span)?,
+                            message: label.message.clone().unwrap_or_default(),
+                        })
+                    })
+                    .collect_vec();
+
+                Diagnostic {
+                    range: self.range_in_file(diagnostic.span),
+                    severity: Some(match severity {
+                        Severity::Error => DiagnosticSeverity::ERROR,
+                        Severity::Warning => DiagnosticSeverity::WARNING,
+                    }),
+                    code: Some(NumberOrString::String(diagnostic.code.to_owned())),
+                    source: Some("assembler".to_owned()),
+                    message,
+                    related_information: (!related_information.is_empty())
+                        .then_some(related_information),
+                    tags: matches!(error, AssemblerError::UnusedLabel { .. })
+                        .then(|| vec![DiagnosticTag::UNNECESSARY]),
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+
+    pub fn definition(&self, position: Position) -> Option<Location> {
+        let target = self.target_at(self.offset(position))?;
+        self.location(self.assembled.names.definitions.get(target)?)
+    }
+
+    /// All names referring to the same definition as the name at the position.
+    /// Names in macro bodies refer to a different symbol in each expansion, so the
+    /// references are matched by the place of the definition.
+    pub fn references(&self, position: Position, include_declaration: bool) -> Vec<Location> {
+        let names = &self.assembled.names;
+        let Some(definition) = self
+            .target_at(self.offset(position))
+            .and_then(|target| names.definitions.get(target))
+        else {
+            return Vec::new();
+        };
+
+        let references = names
+            .references
+            .iter()
+            .filter(|(_, target)| names.definitions.get(target) == Some(definition))
+            .map(|(span, _)| span);
+        include_declaration
+            .then_some(definition)
+            .into_iter()
+            .chain(references)
+            .unique()
+            .filter_map(|span| self.location(span))
+            .collect()
+    }
+
+    /// Value of the symbol named at the position, or encoding and cycle counts
+    /// of the instructions there
+    pub fn hover(&self, position: Position) -> Option<(Range, String)> {
+        let offset = self.offset(position);
+        self.hover_symbol(offset)
+            .or_else(|| self.hover_instructions(offset))
+            .or_else(|| self.hover_mnemonic(offset))
+    }
+
+    /// Fragments generated by the statement at the offset, including all statements
+    /// of a macro expansion when hovering over the call
+    fn hover_instructions(&self, offset: usize) -> Option<(Range, String)> {
+        let fragments = self
+            .assembled
+            .fragments
+            .iter()
+            .filter(|fragment| fragment.cycles > 0)
+            .filter(|fragment| {
+                let anchor = fragment.expansion.first().unwrap_or(&fragment.span);
+                self.contains(anchor, offset)
+            })
+            .collect_vec();
+        let first = fragments.first()?;
+        let anchor = first.expansion.first().unwrap_or(&first.span);
+
+        let mut rows = Vec::new();
+        for fragment in fragments.iter() {
+            for (i, word) in fragment.words.iter().enumerate() {
+                let address = fragment.address.wrapping_add(i as u16);
+                let decoded = match Instruction::decode(*word) {
+                    Some(instruction) => format!("{:?}", instruction),
+                    None => "?".to_owned(),
+                };
+                rows.push(format!("{:04x}: {:04x}  {}", address, word, decoded));
+            }
+        }
+        let words: usize = fragments.iter().map(|fragment| fragment.words.len()).sum();
+        let cycles: u32 = fragments.iter().map(|fragment| fragment.cycles).sum();
+        let text = format!(
+            "```\n{}\n```\n{} {}, {} {}",
+            rows.join("\n"),
+            words,
+            if words == 1 { "word" } else { "words" },
+            cycles,
+            if cycles == 1 { "cycle" } else { "cycles" },
+        );
+        Some((self.range_in_file(anchor), text))
+    }
+
+    fn hover_symbol(&self, offset: usize) -> Option<(Range, String)> {
+        let (span, target) = self.name_at(offset)?;
+        let NameTarget::Symbol(name) = target else {
+            return None;
+        };
+        // As written at the position, qualified names include the file scope
+        let written = &self.source()[span.start..span.end];
+        let text = if let Some(label) = self.assembled.labels.get(name) {
+            format!(
+                "label `{}`: {:#06x} in {}",
+                written, label.address, label.segment
+            )
+        } else if let Some(constant) = self.assembled.constants.get(name) {
+            format!("constant `{}` = {}", written, constant.value)
+        } else {
+            return None;
+        };
+        Some((self.range_in_file(span), text))
+    }
+
+    /// Cycle count of a mnemonic, for instructions that did not assemble
+    fn hover_mnemonic(&self, offset: usize) -> Option<(Range, String)> {
+        let (start, word) = self.word_at(offset)?;
+        let mnemonic = Mnemonic::from_str(&word.to_ascii_lowercase()).ok()?;
+        let cycles = mnemonic.cycles();
+        let text = format!(
+            "`{}`: {} {}",
+            <&str>::from(mnemonic),
+            cycles,
+            if cycles == 1 { "cycle" } else { "cycles" }
+        );
+        let span = Span {
+            file_id: Some(self.file_id),
+            start,
+            end: start + word.len(),
+        };
+        Some((self.range_in_file(&span), text))
+    }
+
+    /// Instruction names at the start of a statement, register names elsewhere
+    pub fn completions(&self, position: Position) -> Vec<CompletionItem> {
+        let offset = self.offset(position);
+        let line_start = self.lines.line_start(self.lines.line(offset));
+        let before = &self.source()[line_start..offset];
+
+        if is_statement_start(before) {
+            let mnemonics = Mnemonic::iter().map(|mnemonic| {
+                let cycles = mnemonic.cycles();
+                completion(
+                    <&str>::from(mnemonic),
+                    CompletionItemKind::KEYWORD,
+                    format!(
+                        "{} {}",
+                        cycles,
+                        if cycles == 1 { "cycle" } else { "cycles" }
+                    ),
+                )
+            });
+            let pseudo = PSEUDO_INSTRUCTIONS.iter().map(|name| {
+                completion(
+                    name,
+                    CompletionItemKind::KEYWORD,
+                    "pseudo instruction".to_owned(),
+                )
+            });
+            mnemonics.chain(pseudo).collect()
+        } else {
+            let registers = (0..16).filter_map(|i| Reg::new(i).ok()).map(|reg| {
+                completion(
+                    &reg.to_string(),
+                    CompletionItemKind::VARIABLE,
+                    reg.abi_name().to_owned(),
+                )
+            });
+            let control_registers = ControlRegister::iter().map(|cr| {
+                completion(
+                    &cr.to_string(),
+                    CompletionItemKind::ENUM_MEMBER,
+                    "control register".to_owned(),
+                )
+            });
+            registers.chain(control_registers).collect()
+        }
+    }
+
+    /// Symbol or macro named at the offset, either by a reference or by its definition
+    fn target_at(&self, offset: usize) -> Option<&NameTarget> {
+        self.name_at(offset).map(|(_, target)| target)
+    }
+
+    fn name_at(&self, offset: usize) -> Option<(&Span, &NameTarget)> {
+        let names = &self.assembled.names;
+        names
+            .references
+            .iter()
+            .find(|(span, _)| self.contains(span, offset))
+            .map(|(span, target)| (span, target))
+            .or_else(|| {
+                names
+                    .definitions
+                    .iter()
+                    .find(|(_, span)| self.contains(span, offset))
+                    .map(|(target, span)| (span, target))
+            })
+    }
+
+    /// Identifier touching the offset, with its start
+    fn word_at(&self, offset: usize) -> Option<(usize, &str)> {
+        let source = self.source();
+        let is_word_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+        let start = source[..offset]
+            .rfind(|c| !is_word_char(c))
+            .map_or(0, |i| i + 1);
+        let end = source[offset..]
+            .find(|c| !is_word_char(c))
+            .map_or(source.len(), |i| offset + i);
+        (start < end).then(|| (start, &source[start..end]))
+    }
+
+    /// Check whether a span in this file contains the offset, touching its end counts
+    fn contains(&self, span: &Span, offset: usize) -> bool {
+        span.file_id == Some(self.file_id) && span.start <= offset && offset <= span.end
+    }
+
+    fn location(&self, span: &Span) -> Option<Location> {
+        (span.file_id == Some(self.file_id)).then(|| Location {
+            uri: self.uri.clone(),
+            range: self.range(span),
+        })
+    }
+
+    /// Range of a span, spans outside of the document are placed at its start
+    fn range_in_file(&self, span: &Span) -> Range {
+        if span.file_id == Some(self.file_id) {
+            self.range(span)
+        } else {
+            Range::default()
+        }
+    }
+
+    fn range(&self, span: &Span) -> Range {
+        Range {
+            start: self.position(span.start),
+            end: self.position(span.end),
+        }
+    }
+
+    /// LSP position of a byte offset, its character counts UTF-16 code units
+    fn position(&self, offset: usize) -> Position {
+        let (line, character) = self
+            .lines
+            .position(self.source(), offset, ColumnUnit::Utf16);
+        Position {
+            line: line as u32,
+            character: character as u32,
+        }
+    }
+
+    /// Byte offset of an LSP position, clamped to the document
+    fn offset(&self, position: Position) -> usize {
+        let source = self.source();
+        let line = position.line as usize;
+        if line >= self.lines.line_count() {
+            return source.len();
+        }
+        let start = self.lines.line_start(line);
+        let text = self.lines.line_text(source, line);
+        let mut units = 0;
+        for (i, c) in text.char_indices() {
+            if units >= position.character as usize {
+                return start + i;
+            }
+            units += c.len_utf16();
+        }
+        start + text.len()
+    }
+}
+
+/// Check whether the text before the cursor on its line is where an instruction name goes:
+/// after any labels, with nothing but the partially typed name.
+fn is_statement_start(before: &str) -> bool {
+    let is_word_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut rest = before.trim_start();
+    while let Some((label, after)) = rest.split_once(':')
+        && !label.is_empty()
+        && label.chars().all(is_word_char)
+    {
+        rest = after.trim_start();
+    }
+    rest.chars().all(is_word_char)
+}
+
+fn completion(label: &str, kind: CompletionItemKind, detail: String) -> CompletionItem {
+    CompletionItem {
+        label: label.to_owned(),
+        kind: Some(kind),
+        detail: Some(detail),
+        ..Default::default()
+    }
+}
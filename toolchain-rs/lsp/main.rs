@@ -0,0 +1,8 @@
+//! Language server for the assembler, speaking LSP over stdin and stdout.
+
+mod analysis;
+mod server;
+
+fn main() -> anyhow::Result<()> {
+    server::run()
+}
@@ -0,0 +1,183 @@
+//! Message loop: keeps the open documents analysed and answers requests about them.
+use std::collections::HashMap;
+
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::{
+    CompletionOptions, CompletionParams, CompletionResponse, DidChangeTextDocumentParams,
+    DidCloseTextDocumentParams, DidOpenTextDocumentParams, GotoDefinitionParams,
+    GotoDefinitionResponse, Hover, HoverContents, HoverParams, HoverProviderCapability,
+    MarkupContent, MarkupKind, OneOf, PublishDiagnosticsParams, ReferenceParams,
+    ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncKind, Uri,
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _,
+        PublishDiagnostics,
+    },
+    request::{Completion, GotoDefinition, HoverRequest, References, Request as _},
+};
+use serde::de::DeserializeOwned;
+
+use crate::analysis::Analysis;
+
+pub fn run() -> anyhow::Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        ..Default::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+
+    let mut server = Server::default();
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    break;
+                }
+                let response = server.handle_request(request);
+                connection.sender.send(Message::Response(response))?;
+            }
+            Message::Notification(notification) => {
+                if let Some(uri) = server.handle_notification(notification)? {
+                    connection
+                        .sender
+                        .send(Message::Notification(server.publish_diagnostics(uri)))?;
+                }
+            }
+            Message::Response(_) => {}
+        }
+    }
+
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+#[derive(Default)]
+struct Server {
+    documents: HashMap<Uri, Analysis>,
+}
+
+impl Server {
+    fn handle_request(&self, request: Request) -> Response {
+        let id = request.id.clone();
+        let result = match request.method.as_str() {
+            GotoDefinition::METHOD => {
+                params(request).and_then(|params| serde_json::to_value(self.definition(params)))
+            }
+            References::METHOD => {
+                params(request).and_then(|params| serde_json::to_value(self.references(params)))
+            }
+            HoverRequest::METHOD => {
+                params(request).and_then(|params| serde_json::to_value(self.hover(params)))
+            }
+            Completion::METHOD => {
+                params(request).and_then(|params| serde_json::to_value(self.completion(params)))
+            }
+            method => {
+                return Response::new_err(
+                    id,
+                    ErrorCode::MethodNotFound as i32,
+                    format!("unsupported request `{}`", method),
+                );
+            }
+        };
+        match result {
+            Ok(result) => Response::new_ok(id, result),
+            Err(error) => Response::new_err(id, ErrorCode::InvalidParams as i32, error.to_string()),
+        }
+    }
+
+    /// Update the documents, returns the document whose diagnostics changed
+    fn handle_notification(&mut self, notification: Notification) -> anyhow::Result<Option<Uri>> {
+        match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: DidOpenTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                let uri = params.text_document.uri;
+                let analysis = Analysis::new(uri.clone(), params.text_document.text);
+                self.documents.insert(uri.clone(), analysis);
+                Ok(Some(uri))
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: DidChangeTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                let uri = params.text_document.uri;
+                // With full synchronization the last change holds the whole text
+                let Some(change) = params.content_changes.into_iter().last() else {
+                    return Ok(None);
+                };
+                let analysis = Analysis::new(uri.clone(), change.text);
+                self.documents.insert(uri.clone(), analysis);
+                Ok(Some(uri))
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                let uri = params.text_document.uri;
+                self.documents.remove(&uri);
+                Ok(Some(uri))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Diagnostics of a document, an empty list for closed ones clears them
+    fn publish_diagnostics(&self, uri: Uri) -> Notification {
+        let diagnostics = self
+            .documents
+            .get(&uri)
+            .map(|analysis| analysis.diagnostics())
+            .unwrap_or_default();
+        Notification::new(
+            PublishDiagnostics::METHOD.to_owned(),
+            PublishDiagnosticsParams {
+                uri,
+                diagnostics,
+                version: None,
+            },
+        )
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let position = params.text_document_position_params;
+        let analysis = self.documents.get(&position.text_document.uri)?;
+        analysis
+            .definition(position.position)
+            .map(GotoDefinitionResponse::Scalar)
+    }
+
+    fn references(&self, params: ReferenceParams) -> Option<Vec<lsp_types::Location>> {
+        let position = params.text_document_position;
+        let analysis = self.documents.get(&position.text_document.uri)?;
+        Some(analysis.references(position.position, params.context.include_declaration))
+    }
+
+    fn hover(&self, params: HoverParams) -> Option<Hover> {
+        let position = params.text_document_position_params;
+        let analysis = self.documents.get(&position.text_document.uri)?;
+        let (range, text) = analysis.hover(position.position)?;
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: text,
+            }),
+            range: Some(range),
+        })
+    }
+
+    fn completion(&self, params: CompletionParams) -> Option<CompletionResponse> {
+        let position = params.text_document_position;
+        let analysis = self.documents.get(&position.text_document.uri)?;
+        Some(CompletionResponse::Array(
+            analysis.completions(position.position),
+        ))
+    }
+}
+
+fn params<P: DeserializeOwned>(request: Request) -> Result<P, serde_json::Error> {
+    serde_json::from_value(request.params)
+}
@@ -0,0 +1,289 @@
+//! Runs the language server binary and talks to it over stdin and stdout, like an editor.
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+};
+
+use serde_json::{Value, json};
+
+const URI: &str = "file:///test.asm";
+
+const SOURCE: &str = "\
+outer: {
+    inner:
+        add r1, r2, r3
+}
+
+macro twice a {
+    add a, a, a
+}
+
+start:
+    ldi r1, outer.inner
+    twice! r2
+    twice! r3
+    add r1, r2, missing
+";
+
+struct Server {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    next_id: i64,
+    /// Notifications received while waiting for responses
+    notifications: Vec<Value>,
+}
+
+impl Server {
+    /// Start the server and go through the initialization handshake
+    fn start() -> Server {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_lsp"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut server = Server {
+            child,
+            stdin,
+            stdout,
+            next_id: 0,
+            notifications: Vec::new(),
+        };
+
+        let result = server.request("initialize", json!({ "capabilities": {} }));
+        assert_eq!(result["capabilities"]["definitionProvider"], true);
+        assert_eq!(result["capabilities"]["hoverProvider"], true);
+        server.notify("initialized", json!({}));
+        server
+    }
+
+    fn send(&mut self, message: Value) {
+        let body = message.to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.stdin.flush().unwrap();
+    }
+
+    fn receive(&mut self) -> Value {
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            self.stdout.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = Some(value.parse().unwrap());
+            }
+        }
+        let mut body = vec![0; length.expect("messages have a length")];
+        self.stdout.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    fn request(&mut self, method: &str, params: Value) -> Value {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }));
+        loop {
+            let mut message = self.receive();
+            if message["id"] == id {
+                assert_eq!(message["error"], Value::Null);
+                return message["result"].take();
+            }
+            self.notifications.push(message);
+        }
+    }
+
+    fn notification(&mut self, method: &str) -> Value {
+        if let Some(i) = self
+            .notifications
+            .iter()
+            .position(|message| message["method"] == method)
+        {
+            return self.notifications.remove(i)["params"].take();
+        }
+        loop {
+            let mut message = self.receive();
+            if message["method"] == method {
+                return message["params"].take();
+            }
+            self.notifications.push(message);
+        }
+    }
+
+    fn open(&mut self, text: &str) {
+        self.notify(
+            "textDocument/didOpen",
+            json!({ "textDocument": {
+                "uri": URI, "languageId": "asm", "version": 1, "text": text
+            }}),
+        );
+    }
+
+    /// Request with a position in the test document
+    fn at(&mut self, method: &str, line: u32, character: u32) -> Value {
+        self.request(method, position(line, character))
+    }
+
+    fn references(&mut self, line: u32, character: u32) -> Value {
+        let mut params = position(line, character);
+        params["context"] = json!({ "includeDeclaration": true });
+        self.request("textDocument/references", params)
+    }
+
+    fn shutdown(mut self) {
+        self.request("shutdown", Value::Null);
+        self.notify("exit", Value::Null);
+        drop(self.stdin);
+        assert!(self.child.wait().unwrap().success());
+    }
+}
+
+fn position(line: u32, character: u32) -> Value {
+    json!({
+        "textDocument": { "uri": URI },
+        "position": { "line": line, "character": character },
+    })
+}
+
+fn range(line: u32, start: u32, end: u32) -> Value {
+    json!({
+        "start": { "line": line, "character": start },
+        "end": { "line": line, "character": end },
+    })
+}
+
+fn location(line: u32, start: u32, end: u32) -> Value {
+    json!({ "uri": URI, "range": range(line, start, end) })
+}
+
+fn labels(completions: &Value) -> Vec<&str> {
+    completions
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["label"].as_str().unwrap())
+        .collect()
+}
+
+#[test]
+fn publishes_diagnostics() {
+    let mut server = Server::start();
+    server.open(SOURCE);
+
+    let params = server.notification("textDocument/publishDiagnostics");
+    assert_eq!(params["uri"], URI);
+    let diagnostics = params["diagnostics"].as_array().unwrap();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0]["code"], "undefined-name");
+    assert_eq!(diagnostics[0]["severity"], 1);
+    assert_eq!(diagnostics[0]["range"], range(13, 16, 23));
+
+    // Fixing the error clears the diagnostics
+    server.notify(
+        "textDocument/didChange",
+        json!({
+            "textDocument": { "uri": URI, "version": 2 },
+            "contentChanges": [{ "text": SOURCE.replace("missing", "r3") }],
+        }),
+    );
+    let params = server.notification("textDocument/publishDiagnostics");
+    assert_eq!(params["diagnostics"], json!([]));
+    server.shutdown();
+}
+
+#[test]
+fn scoped_names() {
+    let mut server = Server::start();
+    server.open(SOURCE);
+
+    // The reference is the `inner` part of `outer.inner`
+    assert_eq!(
+        server.at("textDocument/definition", 10, 20),
+        location(1, 4, 9)
+    );
+    assert_eq!(
+        server.references(1, 5),
+        json!([location(1, 4, 9), location(10, 18, 23)])
+    );
+    server.shutdown();
+}
+
+#[test]
+fn macros() {
+    let mut server = Server::start();
+    server.open(SOURCE);
+
+    assert_eq!(
+        server.at("textDocument/definition", 11, 5),
+        location(5, 6, 11)
+    );
+    assert_eq!(
+        server.references(5, 7),
+        json!([location(5, 6, 11), location(11, 4, 9), location(12, 4, 9)])
+    );
+    server.shutdown();
+}
+
+#[test]
+fn hover() {
+    let mut server = Server::start();
+    server.open(SOURCE);
+
+    let hover = server.at("textDocument/hover", 2, 9);
+    assert_eq!(
+        hover["range"]["start"],
+        json!({ "line": 2, "character": 8 })
+    );
+    assert_eq!(
+        hover["contents"]["value"],
+        "```\n0000: 1323  Add { rd: Reg(1), ra: Reg(2), rb: Reg(3) }\n```\n1 word, 1 cycle"
+    );
+
+    // A macro call shows the instructions of its expansion
+    let hover = server.at("textDocument/hover", 11, 5);
+    assert_eq!(
+        hover["contents"]["value"],
+        "```\n0003: 2223  Add { rd: Reg(2), ra: Reg(2), rb: Reg(2) }\n```\n1 word, 1 cycle"
+    );
+
+    let hover = server.at("textDocument/hover", 10, 5);
+    assert_eq!(
+        hover["contents"]["value"],
+        "```\n0001: 1000  And { rd: Reg(1), ra: Reg(0), rb: Reg(0) }\n\
+         0002: 100a  Addi { rd: Reg(1), v: 0 }\n```\n2 words, 2 cycles"
+    );
+
+    let hover = server.at("textDocument/hover", 1, 5);
+    assert_eq!(
+        hover["contents"]["value"],
+        "label `inner`: 0x0000 in program"
+    );
+    server.shutdown();
+}
+
+#[test]
+fn completion() {
+    let mut server = Server::start();
+    server.open("start:\n    ad\n    ldcr r1, \n");
+
+    let completions = server.at("textDocument/completion", 1, 6);
+    let mnemonics = labels(&completions);
+    assert!(mnemonics.contains(&"add"));
+    assert!(mnemonics.contains(&"ldi"));
+    assert!(!mnemonics.contains(&"r1"));
+
+    let completions = server.at("textDocument/completion", 2, 13);
+    let operands = labels(&completions);
+    assert!(operands.contains(&"r1"));
+    assert!(operands.contains(&"IntBase"));
+    assert!(!operands.contains(&"add"));
+    server.shutdown();
+}
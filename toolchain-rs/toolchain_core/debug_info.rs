@@ -0,0 +1,167 @@
+//! Symbol and source line information produced by the assembler, stored as JSON next to the image.
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::image::Segment;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DebugInfo {
+    /// Source files, referenced by index from `SourceLocation`
+    pub files: Vec<PathBuf>,
+    pub labels: Vec<LabelInfo>,
+    pub constants: Vec<ConstantInfo>,
+    /// Line table, sorted by segment and address
+    pub lines: Vec<LineEntry>,
+}
+
+/// Position in a source file.
+/// Line and column are one based, `start` and `end` are byte offsets.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceLocation {
+    pub file: usize,
+    pub line: u32,
+    pub column: u32,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LabelInfo {
+    /// Fully qualified name
+    pub name: String,
+    pub segment: Segment,
+    pub address: u16,
+    pub location: Option<SourceLocation>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConstantInfo {
+    /// Fully qualified name
+    pub name: String,
+    pub value: i64,
+    pub location: Option<SourceLocation>,
+}
+
+/// Range of words generated from a single source statement
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LineEntry {
+    pub segment: Segment,
+    pub address: u16,
+    /// Size in words
+    pub size: u16,
+    pub location: SourceLocation,
+    /// Macro calls that produced this entry, outermost first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub expansion: Vec<SourceLocation>,
+}
+
+impl DebugInfo {
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<DebugInfo> {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    pub fn file_path(&self, location: &SourceLocation) -> Option<&Path> {
+        self.files.get(location.file).map(PathBuf::as_path)
+    }
+
+    /// Find the line table entry covering the given address.
+    pub fn line_at(&self, segment: Segment, address: u16) -> Option<&LineEntry> {
+        let index = self
+            .lines
+            .partition_point(|entry| (entry.segment, entry.address) <= (segment, address));
+        let entry = self.lines[..index]
+            .iter()
+            .rev()
+            .find(|entry| entry.size > 0)?;
+        let end = u32::from(entry.address) + u32::from(entry.size);
+        (entry.segment == segment && u32::from(address) < end).then_some(entry)
+    }
+
+    /// Iterate over labels placed exactly at the given address.
+    pub fn labels_at(&self, segment: Segment, address: u16) -> impl Iterator<Item = &LabelInfo> {
+        self.labels
+            .iter()
+            .filter(move |label| label.segment == segment && label.address == address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn location(line: u32) -> SourceLocation {
+        SourceLocation {
+            file: 0,
+            line,
+            column: 1,
+            start: 0,
+            end: 1,
+        }
+    }
+
+    fn line_entry(segment: Segment, address: u16, size: u16, line: u32) -> LineEntry {
+        LineEntry {
+            segment,
+            address,
+            size,
+            location: location(line),
+            expansion: Vec::new(),
+        }
+    }
+
+    fn example() -> DebugInfo {
+        DebugInfo {
+            files: vec!["main.asm".into()],
+            labels: vec![LabelInfo {
+                name: "<anonymous 0>.start".to_string(),
+                segment: Segment::Program,
+                address: 0,
+                location: Some(location(1)),
+            }],
+            constants: vec![ConstantInfo {
+                name: "<anonymous 0>.SIZE".to_string(),
+                value: -5,
+                location: None,
+            }],
+            lines: vec![
+                line_entry(Segment::Data, 0, 4, 10),
+                line_entry(Segment::Program, 0, 0, 1),
+                line_entry(Segment::Program, 0, 3, 2),
+                line_entry(Segment::Program, 3, 1, 3),
+                LineEntry {
+                    expansion: vec![location(7)],
+                    ..line_entry(Segment::Program, 4, 2, 20)
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let info = example();
+        let json = serde_json::to_string(&info).unwrap();
+        assert_eq!(serde_json::from_str::<DebugInfo>(&json).unwrap(), info);
+    }
+
+    #[test_case(Segment::Program, 0 => Some(2); "start_of_entry")]
+    #[test_case(Segment::Program, 2 => Some(2); "inside_entry")]
+    #[test_case(Segment::Program, 3 => Some(3); "next_entry")]
+    #[test_case(Segment::Program, 5 => Some(20); "expanded")]
+    #[test_case(Segment::Program, 6 => None; "past_end")]
+    #[test_case(Segment::Data, 3 => Some(10); "data")]
+    #[test_case(Segment::Data, 4 => None; "data_past_end")]
+    fn line_at(segment: Segment, address: u16) -> Option<u32> {
+        example()
+            .line_at(segment, address)
+            .map(|entry| entry.location.line)
+    }
+}
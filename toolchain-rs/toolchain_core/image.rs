@@ -1,8 +1,12 @@
 //! Utilities for loading Pickle Risc image files
 use itertools::{Itertools, repeat_n};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use std::path::{Path, PathBuf};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
 
 pub fn load_ihex<P: AsRef<Path>>(path: P) -> anyhow::Result<Box<[u16]>> {
     let file_str = std::fs::read_to_string(&path)?;
@@ -11,6 +15,146 @@ pub fn load_ihex<P: AsRef<Path>>(path: P) -> anyhow::Result<Box<[u16]>> {
     convert_u8_segments(&u8segments, Some(path.as_ref()))
 }
 
+/// Virtual memory segment that a piece of code or data belongs to
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Segment {
+    Data,
+    Program,
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Segment::Data => write!(f, "data"),
+            Segment::Program => write!(f, "program"),
+        }
+    }
+}
+
+/// Contents of the boot ROM as produced by the assembler, a sparse collection of word blocks.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Image {
+    blocks: Vec<ImageBlock>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ImageBlock {
+    offset: u32,
+    data: Vec<u16>,
+}
+
+impl ImageBlock {
+    fn end(&self) -> u32 {
+        self.offset + u32::try_from(self.data.len()).unwrap()
+    }
+}
+
+impl Image {
+    /// Place words at given word offset.
+    /// Fails if the words would overlap data that is already present.
+    pub fn add(&mut self, offset: u32, data: &[u16]) -> Result<(), ImageOverlapError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = offset + u32::try_from(data.len()).unwrap();
+        let index = self.blocks.partition_point(|block| block.end() <= offset);
+
+        if let Some(block) = self.blocks.get(index)
+            && block.offset < end
+        {
+            return Err(ImageOverlapError {
+                offset: offset.max(block.offset),
+            });
+        }
+
+        let index = if index > 0 && self.blocks[index - 1].end() == offset {
+            self.blocks[index - 1].data.extend_from_slice(data);
+            index - 1
+        } else {
+            self.blocks.insert(
+                index,
+                ImageBlock {
+                    offset,
+                    data: data.to_vec(),
+                },
+            );
+            index
+        };
+
+        if index + 1 < self.blocks.len()
+            && self.blocks[index].end() == self.blocks[index + 1].offset
+        {
+            let next = self.blocks.remove(index + 1);
+            self.blocks[index].data.extend(next.data);
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Iterate over (word offset, words) of contiguous blocks of data in the image.
+    pub fn iter_blocks(&self) -> impl Iterator<Item = (u32, &[u16])> + '_ {
+        self.blocks
+            .iter()
+            .map(|block| (block.offset, block.data.as_slice()))
+    }
+
+    /// Serialize the image as Intel hex, with big endian words at byte offsets
+    /// (the format that `load_ihex` reads).
+    pub fn to_ihex(&self) -> anyhow::Result<String> {
+        const RECORD_BYTES: usize = 16;
+
+        let mut records = Vec::new();
+        let mut address_base: u32 = 0;
+
+        for block in self.blocks.iter() {
+            let bytes: Vec<u8> = block.data.iter().flat_map(|w| w.to_be_bytes()).collect();
+            let mut address = block.offset * 2;
+            let mut remaining = bytes.as_slice();
+
+            while !remaining.is_empty() {
+                if address & 0xffff0000 != address_base {
+                    address_base = address & 0xffff0000;
+                    records.push(ihex::Record::ExtendedLinearAddress(
+                        (address_base >> 16) as u16,
+                    ));
+                }
+
+                // Records must not cross the 64kB boundary of the extended address
+                let to_boundary = 0x10000 - (address & 0xffff) as usize;
+                let (chunk, rest) =
+                    remaining.split_at(remaining.len().min(RECORD_BYTES).min(to_boundary));
+
+                records.push(ihex::Record::Data {
+                    offset: (address & 0xffff) as u16,
+                    value: chunk.to_vec(),
+                });
+
+                address += u32::try_from(chunk.len()).unwrap();
+                remaining = rest;
+            }
+        }
+        records.push(ihex::Record::EndOfFile);
+
+        Ok(ihex::create_object_file_representation(&records)?)
+    }
+
+    pub fn save_ihex<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        std::fs::write(path, self.to_ihex()?)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Overlapping data in image at {offset:#08x}")]
+pub struct ImageOverlapError {
+    pub offset: u32,
+}
+
 fn convert_u8_segments(
     u8segments: &[U8Segment],
     file: Option<&Path>,
@@ -172,6 +316,56 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_image_add_merges_blocks() {
+        let mut image = Image::default();
+        image.add(4, &[3, 4]).unwrap();
+        image.add(0, &[1]).unwrap();
+        image.add(1, &[2]).unwrap();
+        image.add(2, &[5, 6]).unwrap();
+        assert_eq!(
+            image.iter_blocks().collect::<Vec<_>>(),
+            vec![(0, [1u16, 2, 5, 6, 3, 4].as_slice())]
+        );
+    }
+
+    #[test]
+    fn test_image_add_overlap() {
+        let mut image = Image::default();
+        image.add(4, &[1, 2, 3]).unwrap();
+        assert_eq!(
+            image.add(2, &[1, 2, 3]),
+            Err(ImageOverlapError { offset: 4 })
+        );
+        assert_eq!(image.add(6, &[1]), Err(ImageOverlapError { offset: 6 }));
+        image.add(7, &[1]).unwrap();
+    }
+
+    #[proptest]
+    fn test_image_ihex_roundtrip(
+        #[strategy(proptest::collection::vec(proptest::prelude::any::<u16>(), 1..100))] data1: Vec<
+            u16,
+        >,
+        #[strategy(0u32..0x20000)] gap: u32,
+        #[strategy(proptest::collection::vec(proptest::prelude::any::<u16>(), 1..100))] data2: Vec<
+            u16,
+        >,
+    ) {
+        let offset2 = u32::try_from(data1.len()).unwrap() + gap;
+        let mut image = Image::default();
+        image.add(0, &data1).unwrap();
+        image.add(offset2, &data2).unwrap();
+
+        let ihex = image.to_ihex().unwrap();
+        let segments = load_ihex_segments(&ihex, None).unwrap();
+        let loaded = convert_u8_segments(&segments, None).unwrap();
+
+        let mut expected = data1.clone();
+        expected.resize(usize::try_from(offset2).unwrap(), 0);
+        expected.extend_from_slice(&data2);
+        assert_eq!(loaded.as_ref(), expected.as_slice());
+    }
+
     #[test]
     fn test_load_ihex() {
         let ihex = ":040010001122334442";
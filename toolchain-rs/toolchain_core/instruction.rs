@@ -5,13 +5,13 @@ use proptest::{
     strategy::{BoxedStrategy, Strategy},
 };
 use std::str::FromStr;
-use strum::EnumString;
+use strum::{EnumIter, EnumString, IntoStaticStr};
 #[cfg(test)]
 use test_strategy::Arbitrary;
 use thiserror::Error;
 use ux::*; // Non-standard integer types
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Reg(u16);
 
 impl std::fmt::Display for Reg {
@@ -45,11 +45,31 @@ impl From<u4> for Reg {
     }
 }
 
+/// Register names of the calling convention, indexed by register number.
+/// `ra` holds the return address of `jal`, `a*` are arguments and return values,
+/// `t*` are clobbered by calls and `s*` are preserved across calls.
+pub const ABI_NAMES: [&str; 16] = [
+    "zero", "ra", "sp", "a0", "a1", "a2", "a3", "t0", "t1", "t2", "t3", "s0", "s1", "s2", "s3",
+    "s4",
+];
+
 impl Reg {
     /// Create a new register index
     pub fn new(i: u16) -> Result<Reg, ()> {
         i.try_into()
     }
+
+    /// Register with the given calling convention name (`sp`, `a0`, ...)
+    pub fn from_abi_name(name: &str) -> Option<Reg> {
+        ABI_NAMES
+            .iter()
+            .position(|abi_name| *abi_name == name)
+            .map(|i| Reg(i as u16))
+    }
+
+    pub fn abi_name(&self) -> &'static str {
+        ABI_NAMES[usize::from(self.0)]
+    }
 }
 
 impl FromStr for Reg {
@@ -73,7 +93,7 @@ impl Arbitrary for Reg {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, TryFromPrimitive, EnumString)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, TryFromPrimitive, EnumString, EnumIter)]
 #[cfg_attr(test, derive(Arbitrary))]
 #[repr(u16)]
 pub enum ControlRegister {
@@ -271,6 +291,219 @@ impl Instruction {
             _ => unreachable!(),
         })
     }
+
+    pub fn mnemonic(&self) -> Mnemonic {
+        match self {
+            Instruction::And { .. } => Mnemonic::And,
+            Instruction::Or { .. } => Mnemonic::Or,
+            Instruction::Xor { .. } => Mnemonic::Xor,
+            Instruction::Add { .. } => Mnemonic::Add,
+            Instruction::Sub { .. } => Mnemonic::Sub,
+            Instruction::Pack { .. } => Mnemonic::Pack,
+            Instruction::Bcmp { .. } => Mnemonic::Bcmp,
+            Instruction::Cadd { .. } => Mnemonic::Cadd,
+            Instruction::Ldui { .. } => Mnemonic::Ldui,
+            Instruction::Ldpc { .. } => Mnemonic::Ldpc,
+            Instruction::Addi { .. } => Mnemonic::Addi,
+            Instruction::Ld { .. } => Mnemonic::Ld,
+            Instruction::St { .. } => Mnemonic::St,
+            Instruction::Bc { .. } => Mnemonic::Bc,
+            Instruction::Bnc { .. } => Mnemonic::Bnc,
+            Instruction::Bz { .. } => Mnemonic::Bz,
+            Instruction::Bnz { .. } => Mnemonic::Bnz,
+            Instruction::Jal { .. } => Mnemonic::Jal,
+            Instruction::Addc { .. } => Mnemonic::Addc,
+            Instruction::Subc { .. } => Mnemonic::Subc,
+            Instruction::Shr { .. } => Mnemonic::Shr,
+            Instruction::Shrc { .. } => Mnemonic::Shrc,
+            Instruction::Shra { .. } => Mnemonic::Shra,
+            Instruction::Shr8 { .. } => Mnemonic::Shr8,
+            Instruction::Ldp { .. } => Mnemonic::Ldp,
+            Instruction::Cst { .. } => Mnemonic::Cst,
+            Instruction::Andi { .. } => Mnemonic::Andi,
+            Instruction::Ori { .. } => Mnemonic::Ori,
+            Instruction::Xori { .. } => Mnemonic::Xori,
+            Instruction::Ldcr { .. } => Mnemonic::Ldcr,
+            Instruction::Stcr { .. } => Mnemonic::Stcr,
+            Instruction::Syscall { .. } => Mnemonic::Syscall,
+            Instruction::Reti => Mnemonic::Reti,
+            Instruction::Break => Mnemonic::Break,
+        }
+    }
+
+    pub fn cycles(&self) -> u32 {
+        self.mnemonic().cycles()
+    }
+
+    /// Registers and flags whose values the instruction uses. `r0` is never listed.
+    pub fn reads(&self) -> Vec<Location> {
+        let (registers, carry) = match self {
+            Instruction::And { ra, rb, .. }
+            | Instruction::Or { ra, rb, .. }
+            | Instruction::Xor { ra, rb, .. }
+            | Instruction::Add { ra, rb, .. }
+            | Instruction::Sub { ra, rb, .. }
+            | Instruction::Pack { ra, rb, .. }
+            | Instruction::Bcmp { ra, rb, .. } => (vec![*ra, *rb], false),
+            // Keeps the original value of rd if carry is clear
+            Instruction::Cadd { rd, ra, rb } => (vec![*rd, *ra, *rb], true),
+            // Replaces only the upper byte
+            Instruction::Ldui { rd, .. } => (vec![*rd], false),
+            Instruction::Ldpc { .. } | Instruction::Ldcr { .. } => (vec![], false),
+            Instruction::Addi { rd, .. }
+            | Instruction::Andi { rd, .. }
+            | Instruction::Ori { rd, .. }
+            | Instruction::Xori { rd, .. } => (vec![*rd], false),
+            Instruction::Ld { addr, .. }
+            | Instruction::Jal { addr, .. }
+            | Instruction::Ldp { addr, .. } => (vec![*addr], false),
+            Instruction::St { val, addr, .. } => (vec![*val, *addr], false),
+            Instruction::Cst { rd, addr } => (vec![*rd, *addr], false),
+            Instruction::Bc { addr } | Instruction::Bnc { addr } => (vec![*addr], true),
+            Instruction::Bz { cond, addr } | Instruction::Bnz { cond, addr } => {
+                (vec![*cond, *addr], false)
+            }
+            Instruction::Addc { rd, rb } | Instruction::Subc { rd, rb } => (vec![*rd, *rb], true),
+            Instruction::Shr { rb, .. }
+            | Instruction::Shra { rb, .. }
+            | Instruction::Shr8 { rb, .. } => (vec![*rb], false),
+            Instruction::Shrc { rb, .. } => (vec![*rb], true),
+            Instruction::Stcr { val, .. } => (vec![*val], false),
+            Instruction::Syscall { .. } | Instruction::Reti | Instruction::Break => (vec![], false),
+        };
+        locations(registers, carry)
+    }
+
+    /// Registers and flags that the instruction modifies. `r0` is never listed.
+    pub fn writes(&self) -> Vec<Location> {
+        let (registers, carry) = match self {
+            Instruction::Add { rd, .. }
+            | Instruction::Sub { rd, .. }
+            | Instruction::Addi { rd, .. }
+            | Instruction::Addc { rd, .. }
+            | Instruction::Subc { rd, .. }
+            | Instruction::Shr { rd, .. }
+            | Instruction::Shrc { rd, .. }
+            | Instruction::Shra { rd, .. } => (vec![*rd], true),
+            Instruction::And { rd, .. }
+            | Instruction::Or { rd, .. }
+            | Instruction::Xor { rd, .. }
+            | Instruction::Pack { rd, .. }
+            | Instruction::Bcmp { rd, .. }
+            | Instruction::Cadd { rd, .. }
+            | Instruction::Ldui { rd, .. }
+            | Instruction::Ldpc { rd, .. }
+            | Instruction::Ld { rd, .. }
+            | Instruction::Jal { rd, .. }
+            | Instruction::Shr8 { rd, .. }
+            | Instruction::Ldp { rd, .. }
+            | Instruction::Andi { rd, .. }
+            | Instruction::Ori { rd, .. }
+            | Instruction::Xori { rd, .. }
+            | Instruction::Ldcr { rd, .. } => (vec![*rd], false),
+            Instruction::St { .. }
+            | Instruction::Cst { .. }
+            | Instruction::Bc { .. }
+            | Instruction::Bnc { .. }
+            | Instruction::Bz { .. }
+            | Instruction::Bnz { .. }
+            | Instruction::Stcr { .. }
+            | Instruction::Syscall { .. }
+            | Instruction::Reti
+            | Instruction::Break => (vec![], false),
+        };
+        locations(registers, carry)
+    }
+}
+
+/// Register or status flag accessed by an instruction
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Location {
+    Register(Reg),
+    Carry,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Location::Register(reg) => write!(f, "{}", reg),
+            Location::Carry => write!(f, "C"),
+        }
+    }
+}
+
+fn locations(registers: Vec<Reg>, carry: bool) -> Vec<Location> {
+    registers
+        .into_iter()
+        .filter(|reg| reg.0 != 0)
+        .map(Location::Register)
+        .chain(carry.then_some(Location::Carry))
+        .collect()
+}
+
+/// Instruction names as used in the assembly source
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, EnumString, IntoStaticStr, EnumIter)]
+#[strum(serialize_all = "lowercase")]
+pub enum Mnemonic {
+    And,
+    Or,
+    Xor,
+    Add,
+    Sub,
+    Pack,
+    Bcmp,
+    Cadd,
+    Ldui,
+    Ldpc,
+    Addi,
+    Ld,
+    St,
+    Bc,
+    Bnc,
+    Bz,
+    Bnz,
+    Jal,
+    Addc,
+    Subc,
+    Shr,
+    Shrc,
+    Shra,
+    Shr8,
+    Ldp,
+    Cst,
+    Andi,
+    Ori,
+    Xori,
+    Ldcr,
+    Stcr,
+    Syscall,
+    Reti,
+    Break,
+}
+
+impl Mnemonic {
+    /// Number of clock cycles the instruction takes to execute.
+    /// Instructions accessing memory take two cycles, everything else takes one.
+    pub fn cycles(&self) -> u32 {
+        match self {
+            Mnemonic::Ld | Mnemonic::St | Mnemonic::Ldp | Mnemonic::Cst => 2,
+            _ => 1,
+        }
+    }
+
+    /// Branches and jumps execute the following instruction before the jump takes effect
+    pub fn has_delay_slot(&self) -> bool {
+        matches!(
+            self,
+            Mnemonic::Bc | Mnemonic::Bnc | Mnemonic::Bz | Mnemonic::Bnz | Mnemonic::Jal
+        )
+    }
+}
+
+impl std::fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        f.write_str(self.into())
+    }
 }
 
 fn encode_rrr(rd: &Reg, rb: &Reg, ra: &Reg, opcode: u16) -> u16 {
@@ -348,6 +581,50 @@ mod tests {
         assert_eq!(decoded, instr);
     }
 
+    #[test]
+    fn addc_reads_and_writes() {
+        let instruction = Instruction::Addc {
+            rd: Reg(1),
+            rb: Reg(2),
+        };
+        assert_eq!(
+            instruction.reads(),
+            vec![
+                Location::Register(Reg(1)),
+                Location::Register(Reg(2)),
+                Location::Carry
+            ]
+        );
+        assert_eq!(
+            instruction.writes(),
+            vec![Location::Register(Reg(1)), Location::Carry]
+        );
+    }
+
+    #[test]
+    fn zero_register_not_accessed() {
+        let nop = Instruction::Add {
+            rd: Reg(0),
+            ra: Reg(0),
+            rb: Reg(0),
+        };
+        assert_eq!(nop.reads(), vec![]);
+        assert_eq!(nop.writes(), vec![Location::Carry]);
+    }
+
+    #[proptest]
+    fn reg_abi_name_roundtrip(reg: Reg) {
+        assert_eq!(Reg::from_abi_name(reg.abi_name()), Some(reg));
+    }
+
+    #[test_case("zero" => Some(Reg(0)); "zero")]
+    #[test_case("sp" => Some(Reg(2)); "sp")]
+    #[test_case("s4" => Some(Reg(15)); "last")]
+    #[test_case("r1" => None; "numbered")]
+    fn reg_from_abi_name(name: &str) -> Option<Reg> {
+        Reg::from_abi_name(name)
+    }
+
     #[proptest]
     fn control_register_str_roundtrip(cr: ControlRegister) {
         let string = format!("{cr}");
@@ -393,6 +670,31 @@ mod tests {
         Reg::from_str(s).unwrap_err();
     }
 
+    #[test_case(Instruction::Ld { rd: Reg(1), addr: Reg(2), offset: i4::new(0) }, 2; "ld")]
+    #[test_case(Instruction::Ldp { rd: Reg(1), addr: Reg(2) }, 2; "ldp")]
+    #[test_case(Instruction::Add { rd: Reg(1), ra: Reg(2), rb: Reg(3) }, 1; "add")]
+    #[test_case(Instruction::Break, 1; "break_")]
+    fn instruction_cycles(instr: Instruction, expected: u32) {
+        assert_eq!(instr.cycles(), expected);
+    }
+
+    #[test_case(Mnemonic::Jal => true; "jal")]
+    #[test_case(Mnemonic::Bnz => true; "bnz")]
+    #[test_case(Mnemonic::Ldpc => false; "ldpc")]
+    #[test_case(Mnemonic::Reti => false; "reti")]
+    fn mnemonic_has_delay_slot(mnemonic: Mnemonic) -> bool {
+        mnemonic.has_delay_slot()
+    }
+
+    #[test]
+    fn mnemonic_str_roundtrip() {
+        use strum::IntoEnumIterator;
+        for mnemonic in Mnemonic::iter() {
+            let string = format!("{mnemonic}");
+            assert_eq!(Mnemonic::from_str(&string).unwrap(), mnemonic);
+        }
+    }
+
     #[proptest]
     fn reg_u16_roundtrip(reg: Reg) {
         let num: u16 = reg.into();
@@ -1,3 +1,6 @@
+pub mod debug_info;
 pub mod image;
 pub mod instruction;
+pub mod memory_layout;
+pub mod object;
 pub mod util;
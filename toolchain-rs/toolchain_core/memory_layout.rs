@@ -0,0 +1,335 @@
+//! Description of memory regions that the program and data segments are placed into,
+//! shared by the assembler and the linker.
+//!
+//! Layout files are TOML with a list of regions, for example:
+//! ```toml
+//! [[region]]
+//! name = "boot"
+//! segment = "program"
+//! origin = 0x0000
+//! length = 0x0400
+//! within_page = true
+//!
+//! [[region]]
+//! name = "init_data"
+//! segment = "data"
+//! origin = 0x0000
+//! length = 0x1000
+//! load = 0x8000
+//! ```
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::image::Segment;
+
+/// Size of a MMU page in words
+pub const PAGE_SIZE: u32 = 1 << 10;
+
+/// Size of the virtual address space of a single segment in words
+pub const SEGMENT_SIZE: u32 = 1 << 16;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryLayout {
+    /// Regions in placement order
+    #[serde(rename = "region")]
+    pub regions: Vec<Region>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Region {
+    pub name: String,
+    pub segment: Segment,
+    /// First virtual address of the region
+    pub origin: u16,
+    /// Size in words
+    pub length: u32,
+    /// Word offset in the output image where the contents of the region are stored.
+    /// Program regions are stored at their origin by default, data regions are only
+    /// stored in the image if this is set (for initialized data copied to RAM at startup).
+    #[serde(default)]
+    pub load: Option<u32>,
+    /// Nothing placed in the region may cross a MMU page boundary
+    #[serde(default)]
+    pub within_page: bool,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MemoryLayoutError {
+    #[error("region `{name}` extends past the end of the segment")]
+    OutOfAddressSpace { name: String },
+    #[error("regions `{first}` and `{second}` overlap")]
+    Overlap { first: String, second: String },
+    #[error("regions `{first}` and `{second}` are loaded to overlapping addresses")]
+    LoadOverlap { first: String, second: String },
+}
+
+/// Reason why a block of words doesn't fit into a region
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlacementError {
+    /// The block reaches outside of the region
+    OutOfRegion,
+    /// The block crosses a page boundary in a region that doesn't allow it
+    PageBoundary,
+}
+
+impl Default for MemoryLayout {
+    /// Whole address space of both segments, with only the program segment stored in the image
+    fn default() -> Self {
+        MemoryLayout {
+            regions: vec![
+                Region {
+                    name: "program".to_string(),
+                    segment: Segment::Program,
+                    origin: 0,
+                    length: SEGMENT_SIZE,
+                    load: None,
+                    within_page: false,
+                },
+                Region {
+                    name: "data".to_string(),
+                    segment: Segment::Data,
+                    origin: 0,
+                    length: SEGMENT_SIZE,
+                    load: None,
+                    within_page: false,
+                },
+            ],
+        }
+    }
+}
+
+impl MemoryLayout {
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<MemoryLayout> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(s: &str) -> anyhow::Result<MemoryLayout> {
+        let layout: MemoryLayout = toml::from_str(s)?;
+        layout.validate()?;
+        Ok(layout)
+    }
+
+    fn validate(&self) -> Result<(), MemoryLayoutError> {
+        for (i, region) in self.regions.iter().enumerate() {
+            if region.end() > SEGMENT_SIZE {
+                return Err(MemoryLayoutError::OutOfAddressSpace {
+                    name: region.name.clone(),
+                });
+            }
+
+            for other in self.regions[..i].iter() {
+                if other.segment == region.segment
+                    && ranges_overlap(
+                        other.origin.into(),
+                        other.end(),
+                        region.origin.into(),
+                        region.end(),
+                    )
+                {
+                    return Err(MemoryLayoutError::Overlap {
+                        first: other.name.clone(),
+                        second: region.name.clone(),
+                    });
+                }
+                if let (Some(a), Some(b)) = (other.load_offset(), region.load_offset())
+                    && ranges_overlap(a, a + other.length, b, b + region.length)
+                {
+                    return Err(MemoryLayoutError::LoadOverlap {
+                        first: other.name.clone(),
+                        second: region.name.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Regions of a segment, in placement order
+    pub fn regions(&self, segment: Segment) -> impl Iterator<Item = &Region> {
+        self.regions
+            .iter()
+            .filter(move |region| region.segment == segment)
+    }
+
+    /// Address where placement in the segment starts
+    pub fn start(&self, segment: Segment) -> u16 {
+        self.regions(segment)
+            .next()
+            .map(|region| region.origin)
+            .unwrap_or(0)
+    }
+
+    pub fn region_at(&self, segment: Segment, address: u16) -> Option<&Region> {
+        self.regions(segment)
+            .find(|region| (u32::from(region.origin)..region.end()).contains(&address.into()))
+    }
+
+    /// Offset in the output image where a word at the given address is stored,
+    /// `None` if it is not a part of the image.
+    pub fn load_address(&self, segment: Segment, address: u16) -> Option<u32> {
+        let region = self.region_at(segment, address)?;
+        Some(region.load_offset()? + u32::from(address - region.origin))
+    }
+}
+
+impl Region {
+    /// One past the last address of the region
+    pub fn end(&self) -> u32 {
+        u32::from(self.origin) + self.length
+    }
+
+    /// Check that `length` words starting at `address` are inside the region
+    pub fn contains(&self, address: u32, length: u32) -> bool {
+        address >= self.origin.into() && address + length <= self.end()
+    }
+
+    /// Check that `length` words starting at `address` can be placed into the region
+    pub fn check_placement(&self, address: u32, length: u32) -> Result<(), PlacementError> {
+        if !self.contains(address, length) {
+            Err(PlacementError::OutOfRegion)
+        } else if self.within_page && crosses_page(address, length) {
+            Err(PlacementError::PageBoundary)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// First address starting from `address` where `length` words can be placed into the region,
+    /// blocks that would cross a page boundary are moved to the next page if the region requires it
+    pub fn first_placement(&self, address: u32, length: u32) -> Result<u32, PlacementError> {
+        let address = if self.within_page && length <= PAGE_SIZE && crosses_page(address, length) {
+            address.next_multiple_of(PAGE_SIZE)
+        } else {
+            address
+        };
+        self.check_placement(address, length).map(|()| address)
+    }
+
+    fn load_offset(&self) -> Option<u32> {
+        match (self.segment, self.load) {
+            (_, Some(load)) => Some(load),
+            (Segment::Program, None) => Some(self.origin.into()),
+            (Segment::Data, None) => None,
+        }
+    }
+}
+
+/// Check whether `length` words starting at `address` span more than one MMU page
+pub fn crosses_page(address: u32, length: u32) -> bool {
+    length > 0 && address / PAGE_SIZE != (address + length - 1) / PAGE_SIZE
+}
+
+fn ranges_overlap(a_start: u32, a_end: u32, b_start: u32, b_end: u32) -> bool {
+    a_start < b_end && b_start < a_end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    const EXAMPLE: &str = r#"
+        [[region]]
+        name = "boot"
+        segment = "program"
+        origin = 0x0000
+        length = 0x0400
+        within_page = true
+
+        [[region]]
+        name = "code"
+        segment = "program"
+        origin = 0x0400
+        length = 0x1000
+
+        [[region]]
+        name = "init_data"
+        segment = "data"
+        origin = 0x0100
+        length = 0x0100
+        load = 0x8000
+    "#;
+
+    #[test]
+    fn parse_example() {
+        let layout = MemoryLayout::parse(EXAMPLE).unwrap();
+        assert_eq!(layout.regions.len(), 3);
+        assert!(layout.regions[0].within_page);
+        assert_eq!(layout.start(Segment::Program), 0);
+        assert_eq!(layout.start(Segment::Data), 0x100);
+    }
+
+    #[test_case(Segment::Program, 0x0010 => Some(0x0010); "program_default_load")]
+    #[test_case(Segment::Program, 0x1400 => None; "program_outside_regions")]
+    #[test_case(Segment::Data, 0x0110 => Some(0x8010); "data_with_load")]
+    #[test_case(Segment::Data, 0x0000 => None; "data_outside_regions")]
+    fn load_address(segment: Segment, address: u16) -> Option<u32> {
+        MemoryLayout::parse(EXAMPLE)
+            .unwrap()
+            .load_address(segment, address)
+    }
+
+    #[test]
+    fn default_layout_not_loading_data() {
+        let layout = MemoryLayout::default();
+        assert_eq!(layout.load_address(Segment::Program, 0x1234), Some(0x1234));
+        assert_eq!(layout.load_address(Segment::Data, 0x1234), None);
+    }
+
+    #[test]
+    fn overlapping_regions() {
+        let layout = MemoryLayout {
+            regions: vec![
+                Region {
+                    name: "a".to_string(),
+                    segment: Segment::Program,
+                    origin: 0,
+                    length: 0x200,
+                    load: None,
+                    within_page: false,
+                },
+                Region {
+                    name: "b".to_string(),
+                    segment: Segment::Program,
+                    origin: 0x100,
+                    length: 0x200,
+                    load: Some(0x1000),
+                    within_page: false,
+                },
+            ],
+        };
+        assert_eq!(
+            layout.validate(),
+            Err(MemoryLayoutError::Overlap {
+                first: "a".to_string(),
+                second: "b".to_string()
+            })
+        );
+    }
+
+    #[test_case(0x0010, 0x10 => Ok(0x0010); "fits")]
+    #[test_case(0x03f8, 0x10 => Ok(0x0400); "moved_to_next_page")]
+    #[test_case(0x0000, 0x0401 => Err(PlacementError::PageBoundary); "larger_than_page")]
+    #[test_case(0x07f8, 0x10 => Err(PlacementError::OutOfRegion); "past_region_end")]
+    fn placement(address: u32, length: u32) -> Result<u32, PlacementError> {
+        let region = Region {
+            name: "paged".to_string(),
+            segment: Segment::Program,
+            origin: 0,
+            length: 0x0800,
+            load: None,
+            within_page: true,
+        };
+        region.first_placement(address, length)
+    }
+
+    #[test_case(0, 1024 => false; "whole_page")]
+    #[test_case(1023, 2 => true; "crossing")]
+    #[test_case(1024, 0 => false; "empty")]
+    fn page_crossing(address: u32, length: u32) -> bool {
+        crosses_page(address, length)
+    }
+}
@@ -0,0 +1,269 @@
+//! Relocatable object files, produced by the assembler and combined by the linker.
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{image::Segment, instruction::Instruction};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectFile {
+    pub sections: Vec<Section>,
+    pub exports: Vec<Export>,
+    /// Names of symbols that must be provided by other object files,
+    /// referenced by index from `RelocationTarget::Import`
+    pub imports: Vec<String>,
+}
+
+/// Block of words that will be placed at a single continuous range of addresses
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Section {
+    pub segment: Segment,
+    /// The section must start at a multiple of this many words
+    #[serde(default = "default_alignment")]
+    pub alignment: u32,
+    pub data: Vec<u16>,
+    pub relocations: Vec<Relocation>,
+}
+
+/// Symbol defined in this object file and visible to the others
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Export {
+    pub name: String,
+    pub section: usize,
+    /// Offset in words from the start of the section
+    pub offset: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Relocation {
+    /// Offset in words of the first patched word from the start of the section
+    pub offset: u16,
+    pub kind: RelocationKind,
+    pub target: RelocationTarget,
+    /// Value added to the target address
+    pub addend: i64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelocationTarget {
+    /// Start of a section in the same object file
+    Section(usize),
+    /// Imported symbol
+    Import(usize),
+}
+
+/// Immediate field that a relocation patches
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelocationKind {
+    /// i8 offset of `ldpc`, relative to the address of the instruction
+    Ldpc,
+    /// u8 immediate of `ldui`, receives the upper byte of the value
+    Ldui,
+    /// Three word `ldi` sequence (`and`, `addi`, `ldui`), receives the full 16 bit value
+    Ldi,
+    /// Data word (`.dw`)
+    Word,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RelocationError {
+    #[error("relocated value {value} is out of range {min}..={max}")]
+    OutOfRange { value: i64, min: i64, max: i64 },
+    #[error("relocated word {word:#06x} is not the expected instruction")]
+    InstructionMismatch { word: u16 },
+    #[error("relocation extends past the end of the section")]
+    OutOfBounds,
+}
+
+fn default_alignment() -> u32 {
+    1
+}
+
+impl ObjectFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<ObjectFile> {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+}
+
+impl RelocationKind {
+    /// Number of words patched by the relocation
+    pub fn size(&self) -> usize {
+        match self {
+            RelocationKind::Ldi => 3,
+            _ => 1,
+        }
+    }
+
+    /// Patch the field in `words` (starting at the relocation offset) with `value`.
+    /// `place` is the final address of the first patched word.
+    pub fn apply(&self, words: &mut [u16], value: i64, place: u16) -> Result<(), RelocationError> {
+        let words = words
+            .get_mut(..self.size())
+            .ok_or(RelocationError::OutOfBounds)?;
+
+        match self {
+            RelocationKind::Ldpc => {
+                let offset = check_range(value - i64::from(place), i8::MIN.into(), i8::MAX.into())?;
+                patch(&mut words[0], |instruction| match instruction {
+                    Instruction::Ldpc { rd, .. } => Some(Instruction::Ldpc {
+                        rd,
+                        offset: offset as i8,
+                    }),
+                    _ => None,
+                })
+            }
+            RelocationKind::Ldui => {
+                let value = check_range(value, 0, u16::MAX.into())?;
+                patch(&mut words[0], |instruction| match instruction {
+                    Instruction::Ldui { rd, .. } => Some(Instruction::Ldui {
+                        rd,
+                        v: (value >> 8) as u8,
+                    }),
+                    _ => None,
+                })
+            }
+            RelocationKind::Ldi => {
+                let value = check_range(value, i16::MIN.into(), u16::MAX.into())?;
+                patch(&mut words[1], |instruction| match instruction {
+                    Instruction::Addi { rd, .. } => Some(Instruction::Addi {
+                        rd,
+                        v: value as u8 as i8,
+                    }),
+                    _ => None,
+                })?;
+                patch(&mut words[2], |instruction| match instruction {
+                    Instruction::Ldui { rd, .. } => Some(Instruction::Ldui {
+                        rd,
+                        v: (value >> 8) as u8,
+                    }),
+                    _ => None,
+                })
+            }
+            RelocationKind::Word => {
+                words[0] = check_range(value, i16::MIN.into(), u16::MAX.into())? as u16;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn check_range(value: i64, min: i64, max: i64) -> Result<i64, RelocationError> {
+    if (min..=max).contains(&value) {
+        Ok(value)
+    } else {
+        Err(RelocationError::OutOfRange { value, min, max })
+    }
+}
+
+/// Decode an instruction, modify it and encode it back
+fn patch(
+    word: &mut u16,
+    f: impl FnOnce(Instruction) -> Option<Instruction>,
+) -> Result<(), RelocationError> {
+    let patched = Instruction::decode(*word)
+        .and_then(f)
+        .ok_or(RelocationError::InstructionMismatch { word: *word })?;
+    *word = patched.encode();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Reg;
+    use test_case::test_case;
+
+    fn r(i: u16) -> Reg {
+        Reg::new(i).unwrap()
+    }
+
+    fn ldi_sequence() -> Vec<u16> {
+        vec![
+            Instruction::And {
+                rd: r(3),
+                ra: r(0),
+                rb: r(0),
+            }
+            .encode(),
+            Instruction::Addi { rd: r(3), v: 0 }.encode(),
+            Instruction::Ldui { rd: r(3), v: 0 }.encode(),
+        ]
+    }
+
+    #[test_case(0x1234, 0x34, 0x12; "positive")]
+    #[test_case(0xff80, -0x80, 0xff; "sign_bit_set")]
+    #[test_case(-2, -2, 0xff; "negative")]
+    fn apply_ldi(value: i64, expected_lo: i8, expected_hi: u8) {
+        let mut words = ldi_sequence();
+        RelocationKind::Ldi.apply(&mut words, value, 0).unwrap();
+        assert_eq!(
+            Instruction::decode(words[1]),
+            Some(Instruction::Addi {
+                rd: r(3),
+                v: expected_lo
+            })
+        );
+        assert_eq!(
+            Instruction::decode(words[2]),
+            Some(Instruction::Ldui {
+                rd: r(3),
+                v: expected_hi
+            })
+        );
+    }
+
+    #[test_case(0x110, 0x100 => Ok(0x10); "forward")]
+    #[test_case(0x100, 0x180 => Ok(-0x80); "backward_limit")]
+    #[test_case(0x200, 0x100 => Err(RelocationError::OutOfRange { value: 0x100, min: -128, max: 127 }); "out_of_range")]
+    fn apply_ldpc(value: i64, place: u16) -> Result<i8, RelocationError> {
+        let mut words = vec![
+            Instruction::Ldpc {
+                rd: r(1),
+                offset: 0,
+            }
+            .encode(),
+        ];
+        RelocationKind::Ldpc.apply(&mut words, value, place)?;
+        match Instruction::decode(words[0]) {
+            Some(Instruction::Ldpc { offset, .. }) => Ok(offset),
+            other => panic!("Unexpected instruction {other:?}"),
+        }
+    }
+
+    #[test]
+    fn apply_wrong_instruction() {
+        let mut words = vec![Instruction::Break.encode()];
+        assert_eq!(
+            RelocationKind::Ldui.apply(&mut words, 0x1234, 0),
+            Err(RelocationError::InstructionMismatch { word: words[0] })
+        );
+    }
+
+    #[test]
+    fn apply_out_of_bounds() {
+        let mut words = ldi_sequence();
+        assert_eq!(
+            RelocationKind::Ldi.apply(&mut words[1..], 0, 0),
+            Err(RelocationError::OutOfBounds)
+        );
+    }
+
+    #[test_case(0xbeef => Ok(0xbeef); "unsigned")]
+    #[test_case(-1 => Ok(0xffff); "negative")]
+    #[test_case(0x10000 => Err(RelocationError::OutOfRange { value: 0x10000, min: -32768, max: 65535 }); "too_large")]
+    fn apply_word(value: i64) -> Result<u16, RelocationError> {
+        let mut words = vec![0];
+        RelocationKind::Word.apply(&mut words, value, 0)?;
+        Ok(words[0])
+    }
+}